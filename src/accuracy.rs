@@ -0,0 +1,379 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    audio::{EVENT_TIMING, SAMPLE_RATE},
+    bus::BusError,
+    player::{Player, CAPTURE_BUFFER_ADDRESSES},
+    spu::{AudioBitDepth, Nds, Spu},
+    trace::SpuTrace,
+};
+
+/// A channel from the sample it was keyed on until it was keyed off or reused
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracedNote {
+    pub channel: u8,
+    pub start: u64,
+    pub end: u64,
+    pub src_address: usize,
+    pub timer_reload: u16,
+    pub pan: u8,
+    /// (samples since `start`, `SpuChannel::effective_volume`) every time the volume changed
+    pub envelope: Vec<(u64, u32)>,
+}
+
+impl TracedNote {
+    /// The volume `offset` samples into the note
+    pub fn volume_at(&self, offset: u64) -> u32 {
+        self.envelope
+            .iter()
+            .take_while(|(time, _)| *time <= offset)
+            .last()
+            .map(|&(_, volume)| volume)
+            .unwrap_or(0)
+    }
+
+    fn length(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// Runs `trace` through the SPU's register decoding and splits it into notes.
+/// Capture channels are left out since they only replay the mix.
+pub fn trace_notes(trace: &SpuTrace) -> Vec<TracedNote> {
+    let mut spu = Spu::new(Arc::new(Mutex::new(Nds::empty())), AudioBitDepth::_16bit);
+    if let Some(state) = &trace.initial_state {
+        // Undecodable state just means starting from a reset SPU
+        let _ = spu.load_state(&mut 0, state);
+    }
+    for channel in &mut spu.channels {
+        channel.key_on = false;
+    }
+
+    let mut notes = Vec::new();
+    let mut playing: [Option<TracedNote>; 16] = Default::default();
+    let mut events = trace.events.iter().peekable();
+
+    while let Some(event) = events.next() {
+        let sample = event.sample;
+        spu.apply(event.call);
+        // Registers written on the same sample all take effect together
+        while let Some(event) = events.next_if(|event| event.sample == sample) {
+            spu.apply(event.call);
+        }
+
+        for (index, channel) in spu.channels.iter_mut().enumerate() {
+            let keyed_on = channel.key_on;
+            channel.key_on = false;
+
+            if keyed_on || !channel.is_playing() {
+                if let Some(mut note) = playing[index].take() {
+                    note.end = sample;
+                    notes.push(note);
+                }
+            }
+
+            if keyed_on {
+                if CAPTURE_BUFFER_ADDRESSES.contains(&channel.src_address()) {
+                    continue;
+                }
+                playing[index] = Some(TracedNote {
+                    channel: index as u8,
+                    start: sample,
+                    end: sample,
+                    src_address: channel.src_address(),
+                    timer_reload: channel.timer_reload(),
+                    pan: channel.pan(),
+                    envelope: vec![(0, channel.effective_volume())],
+                });
+            } else if let Some(note) = &mut playing[index] {
+                let volume = channel.effective_volume();
+                if note.envelope.last().map(|&(_, last)| last) != Some(volume) {
+                    note.envelope.push((sample - note.start, volume));
+                }
+            }
+        }
+    }
+
+    for mut note in playing.iter_mut().filter_map(Option::take) {
+        note.end = trace.length.max(note.start);
+        notes.push(note);
+    }
+    notes.sort_by_key(|note| (note.start, note.channel));
+    notes
+}
+
+/// A note from the reference trace and the engine note it lined up with
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteComparison {
+    pub reference: TracedNote,
+    pub engine: Option<TracedNote>,
+}
+
+impl NoteComparison {
+    /// Samples the engine's note starts after the reference's, once the traces are aligned
+    pub fn timing_offset(&self, alignment: i64) -> Option<i64> {
+        let engine = self.engine.as_ref()?;
+        Some(engine.start as i64 - (self.reference.start as i64 + alignment))
+    }
+
+    pub fn timer_reload_difference(&self) -> Option<i32> {
+        let engine = self.engine.as_ref()?;
+        Some(engine.timer_reload as i32 - self.reference.timer_reload as i32)
+    }
+
+    /// How far off the engine's pitch is
+    pub fn cents_difference(&self) -> Option<f32> {
+        let engine = self.engine.as_ref()?;
+        let period = |timer_reload: u16| (0x10000 - timer_reload as u32).max(1) as f32;
+        Some(1200.0 * (period(self.reference.timer_reload) / period(engine.timer_reload)).log2())
+    }
+
+    pub fn pan_difference(&self) -> Option<i32> {
+        let engine = self.engine.as_ref()?;
+        Some(engine.pan as i32 - self.reference.pan as i32)
+    }
+
+    /// The largest volume difference over the part of the note both traces play
+    pub fn envelope_difference(&self) -> Option<u32> {
+        let engine = self.engine.as_ref()?;
+        let length = self.reference.length().min(engine.length());
+        let difference = self
+            .reference
+            .envelope
+            .iter()
+            .chain(&engine.envelope)
+            .map(|&(time, _)| time)
+            .filter(|&time| time <= length)
+            .map(|time| {
+                (self.reference.volume_at(time) as i32 - engine.volume_at(time) as i32)
+                    .unsigned_abs()
+            })
+            .max()
+            .unwrap_or(0);
+        Some(difference)
+    }
+}
+
+/// How far apart notes can be before they count as different
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub timing: u64,
+    pub cents: f32,
+    pub pan: u8,
+    pub volume: u32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            timing: EVENT_TIMING as u64,
+            cents: 5.0,
+            pan: 2,
+            volume: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccuracyReport {
+    /// Added to reference sample times to line them up with the engine's
+    pub alignment: i64,
+    /// In reference order
+    pub comparisons: Vec<NoteComparison>,
+    /// Engine notes nothing in the reference lined up with
+    pub extra_notes: Vec<TracedNote>,
+    pub tolerance: Tolerance,
+}
+
+impl AccuracyReport {
+    pub fn is_mismatch(&self, comparison: &NoteComparison) -> bool {
+        let tolerance = &self.tolerance;
+        comparison.engine.is_none()
+            || comparison
+                .timing_offset(self.alignment)
+                .unwrap()
+                .unsigned_abs()
+                > tolerance.timing
+            || comparison.cents_difference().unwrap().abs() > tolerance.cents
+            || comparison.pan_difference().unwrap().unsigned_abs() > tolerance.pan as u32
+            || comparison.envelope_difference().unwrap() > tolerance.volume
+    }
+
+    pub fn mismatches(&self) -> impl Iterator<Item = &NoteComparison> {
+        self.comparisons
+            .iter()
+            .filter(move |comparison| self.is_mismatch(comparison))
+    }
+
+    pub fn is_match(&self) -> bool {
+        self.mismatches().next().is_none() && self.extra_notes.is_empty()
+    }
+}
+
+impl fmt::Display for AccuracyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} reference notes, {} mismatched, {} extra in the engine (aligned by {} samples)",
+            self.comparisons.len(),
+            self.mismatches().count(),
+            self.extra_notes.len(),
+            self.alignment
+        )?;
+        for comparison in self.mismatches() {
+            let reference = &comparison.reference;
+            let seconds = reference.start as f32 / SAMPLE_RATE as f32;
+            write!(
+                f,
+                "{:9.3}s ch {:2} src {:#010X}: ",
+                seconds, reference.channel, reference.src_address
+            )?;
+            match &comparison.engine {
+                Some(engine) => writeln!(
+                    f,
+                    "engine ch {:2} timing {:+} samples, timer reload {:+} ({:+.1} cents), pan {:+}, volume {}",
+                    engine.channel,
+                    comparison.timing_offset(self.alignment).unwrap(),
+                    comparison.timer_reload_difference().unwrap(),
+                    comparison.cents_difference().unwrap(),
+                    comparison.pan_difference().unwrap(),
+                    comparison.envelope_difference().unwrap(),
+                )?,
+                None => writeln!(f, "missing from the engine")?,
+            }
+        }
+        for note in &self.extra_notes {
+            writeln!(
+                f,
+                "{:9.3}s ch {:2} src {:#010X}: only in the engine",
+                note.start as f32 / SAMPLE_RATE as f32,
+                note.channel,
+                note.src_address
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Lines notes in `reference` up with the ones playing the same sample in `engine`.
+/// The traces are aligned on their first notes, after which each reference note takes the
+/// nearest unclaimed engine note within `window` samples.
+pub fn compare_traces(
+    reference: &SpuTrace,
+    engine: &SpuTrace,
+    window: u64,
+    tolerance: Tolerance,
+) -> AccuracyReport {
+    let reference_notes = trace_notes(reference);
+    let mut engine_notes: Vec<Option<TracedNote>> =
+        trace_notes(engine).into_iter().map(Some).collect();
+
+    let alignment = match (reference_notes.first(), engine_notes.first()) {
+        (Some(first_reference), Some(Some(first_engine))) => {
+            first_engine.start as i64 - first_reference.start as i64
+        }
+        _ => 0,
+    };
+
+    let comparisons = reference_notes
+        .into_iter()
+        .map(|reference| {
+            let expected_start = reference.start as i64 + alignment;
+            let nearest = engine_notes
+                .iter()
+                .enumerate()
+                .filter_map(|(index, note)| Some((index, note.as_ref()?)))
+                .filter(|(_, note)| note.src_address == reference.src_address)
+                .map(|(index, note)| (index, (note.start as i64 - expected_start).unsigned_abs()))
+                .filter(|&(_, distance)| distance <= window)
+                .min_by_key(|&(_, distance)| distance);
+
+            NoteComparison {
+                reference,
+                engine: nearest.and_then(|(index, _)| engine_notes[index].take()),
+            }
+        })
+        .collect();
+
+    AccuracyReport {
+        alignment,
+        comparisons,
+        extra_notes: engine_notes.into_iter().flatten().collect(),
+        tolerance,
+    }
+}
+
+/// The engine's own trace of the first `length` samples of a song
+pub fn engine_trace(mio_data: &[u8], ram: &[u8], length: usize) -> Result<SpuTrace, BusError> {
+    let mut player = Player::new(mio_data, ram, 1.0)?;
+    player.start_trace();
+    let mut data = vec![0.0; length * 2];
+    player.render_native(&mut data);
+    Ok(player.stop_trace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures,
+        spu::{SpuCall, TraceEvent},
+    };
+
+    #[test]
+    fn test_compare_traces() {
+        let ram = fixtures::patterned_ram();
+        let notes: Vec<(usize, u8)> = (0..8).map(|time| (time, 12 + time as u8)).collect();
+        let mio = fixtures::song(0, &notes);
+
+        let engine = engine_trace(&mio, &ram, 40000).unwrap();
+        let notes = trace_notes(&engine);
+        assert!(notes.len() >= 4);
+
+        // The same trace later on, with one note's pitch and pan changed
+        let mut reference = engine.clone();
+        let detuned = notes[1].clone();
+        for event in &mut reference.events {
+            event.sample += 1000;
+        }
+        let key_on = reference
+            .events
+            .iter()
+            .position(|event| {
+                event.sample == detuned.start + 1000
+                    && matches!(event.call, SpuCall::ChannelPlayNote { channel, .. } if channel == detuned.channel)
+            })
+            .unwrap();
+        let channel = detuned.channel;
+        let sample = detuned.start + 1000;
+        reference.events.splice(
+            key_on..key_on,
+            vec![
+                TraceEvent {
+                    sample,
+                    call: SpuCall::SetChannelTimerReload {
+                        channel,
+                        timer_reload: detuned.timer_reload as u32 - 100,
+                    },
+                },
+                TraceEvent {
+                    sample,
+                    call: SpuCall::SetChannelPan { channel, pan: 0 },
+                },
+            ],
+        );
+
+        assert!(compare_traces(&engine, &engine, 64, Tolerance::default()).is_match());
+
+        let report = compare_traces(&reference, &engine, 64, Tolerance::default());
+        assert_eq!(report.alignment, -1000);
+        let mismatches: Vec<_> = report.mismatches().collect();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].timer_reload_difference(), Some(100));
+        assert_eq!(mismatches[0].pan_difference(), Some(detuned.pan as i32));
+        assert_eq!(mismatches[0].timing_offset(report.alignment), Some(0));
+    }
+}
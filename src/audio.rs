@@ -5,12 +5,14 @@ use std::{
 };
 
 use crate::{
+    log::{self, Category, Event, Level},
+    mixer::Mixer,
     record::{Record, Repeats},
-    spu::Spu,
+    spu::{SampleFormat, Spu},
 };
-use nanoserde::{DeBin, SerBin};
+use nanoserde::{DeBin, DeBinErr, SerBin};
 
-#[derive(DeBin, SerBin, Clone, Copy, Debug)]
+#[derive(DeBin, SerBin, Clone, Copy, Debug, PartialEq)]
 pub enum Write {
     u8(u8),
     u16(u16),
@@ -21,6 +23,12 @@ pub const SAMPLE_RATE: usize = 32824;
 pub const NOTE_RATE: usize = SAMPLE_RATE / 8;
 pub const EVENT_TIMING: usize = 171;
 pub const TRACK_LENGTH: usize = 32;
+/// The MIDI note a mio note of 0 is treated as when exporting
+pub const BASE_MIDI_NOTE: u8 = 60;
+/// Tracks a channel can belong to, the song's melodic tracks and drum lanes come first
+pub const TRACK_SLOTS: usize = 16;
+/// Tracks from here on are played live instead of by the sequencer, which leaves them be
+pub const FIRST_LIVE_TRACK: u8 = 8;
 
 pub fn interp_val(note: u32, (lowest, highest): (u32, u32)) -> u32 {
     let note = note as f32 / 24.0;
@@ -39,7 +47,7 @@ pub fn interp_val_until(note: u32, (lowest, highest): (u32, u32), until: u32) ->
     (lowest as f32 + note * diff) as u32
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub enum AttackEnvelope {
     Linear {
         volume: u32,
@@ -70,13 +78,13 @@ impl AttackEnvelope {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, DeBin, SerBin)]
 pub struct SustainEnvelope {
     pub volume: u32,
     pub duration: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, DeBin, SerBin)]
 pub enum DecayEnvelope {
     Linear { duration: u32 },
     Exponential { constant: f32, duration: u32 },
@@ -91,7 +99,7 @@ impl DecayEnvelope {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, DeBin, SerBin)]
 pub struct Envelope {
     pub attack: Option<AttackEnvelope>,
     pub initial_volume: u32,
@@ -100,7 +108,7 @@ pub struct Envelope {
     pub release: Option<ReleaseInstructions>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct Sample {
     pub volume: (u32, u32),
     pub base_timer_reload: u16,
@@ -110,7 +118,7 @@ pub struct Sample {
     pub is_repeating: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct ProgrammableSample {
     pub volume: (u32, u32),
     pub base_timer_reload: u16,
@@ -120,7 +128,7 @@ pub struct ProgrammableSample {
 // 1 asian drum and 3 8-bit drum timer reload adjusts over time, maybe more
 // 1 8-bit drum repeats with reduced volume, maybe more
 // 2 noise drum
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct DrumSample {
     pub volume: u32,
     pub timer_reload: u16,
@@ -142,13 +150,13 @@ pub struct TimedDrumSample {
     pub sample: DrumSample,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, DeBin, SerBin)]
 pub struct TimedPitchAdjustment {
     pub time: u32,
     pub timer_reload: u16,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct TimedVolumeAdjustment {
     pub time: u32,
     pub volume: u32,
@@ -220,6 +228,26 @@ pub enum DrumInstructions {
     //ExactPitchAdjust {sample: DrumSample, adjustments: Vec<TimedPitchAdjustments> },
 }
 
+impl DrumInstructions {
+    /// Every sample this drum plays from RAM, noise drums have none
+    pub fn samples(&self) -> Vec<&DrumSample> {
+        match self {
+            DrumInstructions::Dsr { sample, .. }
+            | DrumInstructions::Sr { sample, .. }
+            | DrumInstructions::Decay { sample, .. }
+            | DrumInstructions::Simple { sample }
+            | DrumInstructions::ExactVolumeAdjust { sample, .. }
+            | DrumInstructions::DsrPitchAdjust { sample, .. }
+            | DrumInstructions::PitchThenRelease { sample, .. }
+            | DrumInstructions::RepeatOnce { sample, .. } => vec![sample],
+            DrumInstructions::Multiple { samples } => {
+                samples.iter().map(|timed| &timed.sample).collect()
+            }
+            DrumInstructions::Noise { .. } => Vec::new(),
+        }
+    }
+}
+
 pub struct RhythmSection {
     pub name: String,
     pub instructions: [DrumInstructions; DRUM_COUNT],
@@ -231,7 +259,7 @@ impl RhythmSection {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, DeBin, SerBin)]
 pub enum ReleaseInstructions {
     Geometric { ratio: f32 },
     GeometricStopBlowing { ratio: f32 },
@@ -288,10 +316,12 @@ pub struct SustainInstructions {
     pub duration: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub enum InstrumentSample {
     PCM16(Sample),
     PSG(ProgrammableSample),
+    /// A sample loaded from outside the game, played as its own format
+    Custom(Sample, SampleFormat),
 }
 
 impl InstrumentSample {
@@ -299,6 +329,7 @@ impl InstrumentSample {
         match self {
             Self::PCM16(sample) => sample.volume,
             Self::PSG(sample) => sample.volume,
+            Self::Custom(sample, _) => sample.volume,
         }
     }
 
@@ -306,23 +337,25 @@ impl InstrumentSample {
         match self {
             Self::PCM16(sample) => sample.base_timer_reload,
             Self::PSG(sample) => sample.base_timer_reload,
+            Self::Custom(sample, _) => sample.base_timer_reload,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct InstrumentTimedVolumeAdjustment {
     pub time: u32,
     pub volume: (u32, u32),
 }
 
+#[derive(Clone)]
 pub struct Instrument {
     pub name: String,
     pub instructions: InstrumentInstructions,
     pub pitch_adjustments: Vec<TimedRelativePitchAdjustment>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub enum InstrumentAttack {
     Exact {
         adjustments: Vec<InstrumentTimedVolumeAdjustment>,
@@ -332,7 +365,7 @@ pub enum InstrumentAttack {
     },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub enum InstrumentDecay {
     Exponential {
         duration: u32,
@@ -357,13 +390,13 @@ pub enum InstrumentDecay {
     },
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, DeBin, SerBin)]
 pub struct InstrumentSustain {
     pub volume: (u32, u32),
     pub duration: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub enum InstrumentRelease {
     Basic,
     ExponentialUntil { duration: u32, until: u32 },
@@ -374,7 +407,7 @@ pub enum InstrumentRelease {
     KeepBlowing,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct Adsr {
     pub sample: InstrumentSample,
     pub attack: Option<InstrumentAttack>,
@@ -383,12 +416,14 @@ pub struct Adsr {
     pub release: Option<InstrumentRelease>,
 }
 
+#[derive(Clone)]
 pub struct RangedAdsr {
     pub low: u8,
     pub high: u8,
     pub adsr: Adsr,
 }
 
+#[derive(Clone)]
 pub struct StupidCricket {
     pub low: u8,
     pub high: u8,
@@ -397,6 +432,7 @@ pub struct StupidCricket {
     pub pitch_adjustments: Vec<TimedRelativePitchAdjustment>,
 }
 
+#[derive(Clone)]
 pub enum InstrumentInstructions {
     Adsr(Adsr),
     Dual([Adsr; 2]),
@@ -406,7 +442,37 @@ pub enum InstrumentInstructions {
     Cricket(Vec<StupidCricket>),
 }
 
-#[derive(Debug, Clone)]
+impl InstrumentInstructions {
+    /// Every ADSR the instrument can play, with the (low, high) notes it covers
+    pub fn adsrs(&self) -> Vec<(&Adsr, u8, u8)> {
+        match self {
+            InstrumentInstructions::Adsr(adsr) => vec![(adsr, 0, 24)],
+            InstrumentInstructions::Dual(adsr_pair) => {
+                adsr_pair.iter().map(|adsr| (adsr, 0, 24)).collect()
+            }
+            InstrumentInstructions::Ranged(ranged_adsr) => ranged_adsr
+                .iter()
+                .map(|ranged| (&ranged.adsr, ranged.low, ranged.high))
+                .collect(),
+            InstrumentInstructions::TimedMultiple(timed_adsr) => {
+                timed_adsr.iter().map(|(_, adsr)| (adsr, 0, 24)).collect()
+            }
+            InstrumentInstructions::Random(adsrs) => {
+                adsrs.iter().map(|adsr| (adsr, 0, 24)).collect()
+            }
+            InstrumentInstructions::Cricket(cricket_adsr) => cricket_adsr
+                .iter()
+                .flat_map(|cricket| {
+                    std::iter::once(&cricket.adsr)
+                        .chain(cricket.future_adsr.iter().map(|(_, adsr)| adsr))
+                        .map(move |adsr| (adsr, cricket.low, cricket.high))
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct QueuedNote {
     pub time: u32,
     pub instrument: u32,
@@ -416,7 +482,7 @@ pub struct QueuedNote {
     pub volume_multiplier: f32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub struct QueuedDrum {
     pub time: u32,
     pub section: usize,
@@ -438,7 +504,8 @@ pub enum Channel {
         queued_samples: Vec<(DrumSample, u32)>,
         start_tick: u32,
         range: (u32, u32),
-        true_time: usize,
+        /// Samples since the note's step, before swing. Envelope ticks count from here.
+        age: usize,
     },
     Freeing {
         sound: QueuedSound,
@@ -446,13 +513,92 @@ pub enum Channel {
         volume: u32,
         kill_tick: u32,
         release: Option<ReleaseInstructions>,
-        true_time: usize,
+        age: usize,
     },
     Withheld,
     Blocked,
 }
 
-#[derive(Debug, Clone)]
+// Written by hand since nanoserde has no VecDeque support
+impl SerBin for Channel {
+    fn ser_bin(&self, s: &mut Vec<u8>) {
+        match self {
+            Channel::Open => 0u16.ser_bin(s),
+            Channel::Used {
+                sound,
+                envelope,
+                volume,
+                pitch_adjustments,
+                future_adsr,
+                queued_samples,
+                start_tick,
+                range,
+                age,
+            } => {
+                1u16.ser_bin(s);
+                sound.ser_bin(s);
+                envelope.ser_bin(s);
+                volume.ser_bin(s);
+                pitch_adjustments.ser_bin(s);
+                future_adsr.iter().cloned().collect::<Vec<_>>().ser_bin(s);
+                queued_samples.ser_bin(s);
+                start_tick.ser_bin(s);
+                range.ser_bin(s);
+                age.ser_bin(s);
+            }
+            Channel::Freeing {
+                sound,
+                initial_release_volume,
+                volume,
+                kill_tick,
+                release,
+                age,
+            } => {
+                2u16.ser_bin(s);
+                sound.ser_bin(s);
+                initial_release_volume.ser_bin(s);
+                volume.ser_bin(s);
+                kill_tick.ser_bin(s);
+                release.ser_bin(s);
+                age.ser_bin(s);
+            }
+            Channel::Withheld => 3u16.ser_bin(s),
+            Channel::Blocked => 4u16.ser_bin(s),
+        }
+    }
+}
+
+impl DeBin for Channel {
+    fn de_bin(o: &mut usize, d: &[u8]) -> Result<Self, DeBinErr> {
+        match u16::de_bin(o, d)? {
+            0 => Ok(Channel::Open),
+            1 => Ok(Channel::Used {
+                sound: DeBin::de_bin(o, d)?,
+                envelope: DeBin::de_bin(o, d)?,
+                volume: DeBin::de_bin(o, d)?,
+                pitch_adjustments: DeBin::de_bin(o, d)?,
+                future_adsr: Vec::<(u32, Adsr)>::de_bin(o, d)?.into(),
+                queued_samples: DeBin::de_bin(o, d)?,
+                start_tick: DeBin::de_bin(o, d)?,
+                range: DeBin::de_bin(o, d)?,
+                age: DeBin::de_bin(o, d)?,
+            }),
+            2 => Ok(Channel::Freeing {
+                sound: DeBin::de_bin(o, d)?,
+                initial_release_volume: DeBin::de_bin(o, d)?,
+                volume: DeBin::de_bin(o, d)?,
+                kill_tick: DeBin::de_bin(o, d)?,
+                release: DeBin::de_bin(o, d)?,
+                age: DeBin::de_bin(o, d)?,
+            }),
+            3 => Ok(Channel::Withheld),
+            4 => Ok(Channel::Blocked),
+            _ => Err(DeBinErr::new(*o, 0, d.len())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, DeBin, SerBin)]
 pub enum QueuedSound {
     Note(QueuedNote),
     Drum(QueuedDrum),
@@ -488,7 +634,7 @@ impl QueuedSound {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, DeBin, SerBin)]
 pub struct ChannelManager {
     pub channels: [Channel; 16],
 }
@@ -504,10 +650,14 @@ impl ChannelManager {
         future_adsr: VecDeque<(u32, Adsr)>,
         start_tick: u32,
         range: (u32, u32),
-        true_time: usize,
+        age: usize,
     ) {
         //let channel_id = self.request_channel_pcm(note.track).unwrap();
 
+        log::log(Event::ChannelAllocated {
+            channel: channel_id as u8,
+            track: note.track,
+        });
         self.channels[channel_id] = Channel::Used {
             sound: QueuedSound::Note(note),
             envelope: envelope,
@@ -517,7 +667,7 @@ impl ChannelManager {
             queued_samples: Vec::new(),
             start_tick,
             range,
-            true_time,
+            age,
         }
     }
 
@@ -529,10 +679,14 @@ impl ChannelManager {
         volume: u32,
         pitch_adjustments: Vec<TimedPitchAdjustment>,
         queued_samples: Vec<(DrumSample, u32)>,
-        true_time: usize,
+        age: usize,
     ) {
         //let channel_id = self.request_channel_pcm(drum.pretend_track).unwrap();
 
+        log::log(Event::ChannelAllocated {
+            channel: channel_id as u8,
+            track: drum.pretend_track,
+        });
         self.channels[channel_id] = Channel::Used {
             sound: QueuedSound::Drum(drum),
             envelope: envelope,
@@ -542,7 +696,7 @@ impl ChannelManager {
             queued_samples: queued_samples,
             start_tick: 0,
             range: (0, 0),
-            true_time,
+            age,
         }
     }
 
@@ -610,6 +764,7 @@ impl ChannelManager {
             }
         }
 
+        log::log(Event::NoChannelAvailable { track });
         None
     }
 
@@ -660,6 +815,7 @@ impl ChannelManager {
             }
         }
 
+        log::log(Event::NoChannelAvailable { track });
         None
     }
 
@@ -718,25 +874,29 @@ impl ChannelManager {
         Some(14)
     }
 
-    pub fn release_tracks(&mut self, track: u8, mio_tick: usize, record: &Record) {
-        for channel in &mut self.channels {
+    pub fn release_tracks(&mut self, track: u8) {
+        for (channel_id, channel) in self.channels.iter_mut().enumerate() {
             match channel {
                 Channel::Used {
                     sound,
                     envelope,
                     volume,
-                    true_time,
+                    age,
                     ..
                 } => {
-                    let tick = ((mio_tick - *true_time * record.note_rate) / EVENT_TIMING) as u32;
+                    let tick = (*age / EVENT_TIMING) as u32;
                     if sound.track() == track {
+                        log::log(Event::ChannelReleased {
+                            channel: channel_id as u8,
+                            track,
+                        });
                         *channel = Channel::Freeing {
                             sound: sound.clone(),
                             volume: *volume,
                             initial_release_volume: *volume,
                             kill_tick: tick,
                             release: envelope.as_ref().map(|env| env.release.clone()).flatten(),
-                            true_time: *true_time,
+                            age: *age,
                         }
                     }
                 }
@@ -782,6 +942,8 @@ pub enum PossiblePitchAdjustment {
     Exact(Vec<TimedPitchAdjustment>),
 }
 
+/// Starts `adsr` for `note` on a channel that can play its sample, None when there's no
+/// channel free for it
 pub fn add_adsr_to_channel(
     channel_manager: &mut ChannelManager,
     spu: &mut Spu,
@@ -791,10 +953,11 @@ pub fn add_adsr_to_channel(
     adsr: &Adsr,
     future_adsr: VecDeque<(u32, Adsr)>,
     tick: u32,
-    previous_notes: &mut [Option<u8>; 4],
+    previous_notes: &mut [Option<u8>; TRACK_SLOTS],
     custom_pitch_adjustments: Option<PossiblePitchAdjustment>,
-    repeat_count: usize,
-) -> usize {
+    age: usize,
+    transpose: i32,
+) -> Option<usize> {
     let Adsr {
         sample,
         attack,
@@ -804,13 +967,16 @@ pub fn add_adsr_to_channel(
     } = adsr;
 
     let channel_id = match sample {
-        InstrumentSample::PCM16(_) => channel_manager.request_channel_pcm(note.track),
+        InstrumentSample::PCM16(_) | InstrumentSample::Custom(..) => {
+            channel_manager.request_channel_pcm(note.track)
+        }
         InstrumentSample::PSG(_) => channel_manager.request_channel_psg(note.track),
-    }
-    .unwrap();
+    }?;
 
     let (adsr_low, until_note) = range;
     let note_offset = note.note as u32 - adsr_low;
+    // Only the pitch moves, the note keeps its sample and envelope
+    let pitch_offset = note_offset as i32 + transpose;
     let initial_volume = interp_val_until(note_offset, sample.volume(), until_note);
 
     let attack_volume = match attack {
@@ -823,7 +989,7 @@ pub fn add_adsr_to_channel(
 
     match sample {
         InstrumentSample::PCM16(sample) => {
-            let timer_reload = nth_timer_reload(sample.base_timer_reload, note_offset as i32);
+            let timer_reload = nth_timer_reload(sample.base_timer_reload, pitch_offset);
 
             spu.set_adjusted_channel_pan(channel_id, 64, note.pan_addition);
             spu.set_adjusted_channel_volume(channel_id, initial_volume, note.volume_multiplier);
@@ -833,15 +999,19 @@ pub fn add_adsr_to_channel(
             spu.set_channel_src_address(channel_id, sample.src_address);
             spu.channel_play_note(channel_id, sample.is_repeating);
         }
+        InstrumentSample::Custom(sample, format) => {
+            let timer_reload = nth_timer_reload(sample.base_timer_reload, pitch_offset);
+
+            spu.set_adjusted_channel_pan(channel_id, 64, note.pan_addition);
+            spu.set_adjusted_channel_volume(channel_id, initial_volume, note.volume_multiplier);
+            spu.set_channel_timer_reload(channel_id, timer_reload as u32);
+            spu.set_channel_loop_pos(channel_id, sample.loop_pos);
+            spu.set_channel_length(channel_id, sample.length);
+            spu.set_channel_src_address(channel_id, sample.src_address);
+            spu.channel_play_sample(channel_id, sample.is_repeating, *format);
+        }
         InstrumentSample::PSG(sample) => {
-            let timer_reload = match sample.base_timer_reload {
-                62868 => nth_timer_reload_ting_ting(note_offset as i32),
-                22792 => nth_timer_reload_bong_bong(note_offset as i32),
-                54852 => nth_timer_reload_ding_ding(note_offset as i32),
-                41548 => nth_timer_reload_bing_bing(note_offset as i32),
-                //60196 => nth_timer_reload_bling_bling(note_offset as i32),
-                _ => nth_timer_reload(sample.base_timer_reload, note_offset as i32),
-            };
+            let timer_reload = nth_psg_timer_reload(sample.base_timer_reload, pitch_offset);
 
             spu.set_adjusted_channel_pan(channel_id, 64, note.pan_addition);
             spu.set_adjusted_channel_volume(channel_id, initial_volume, note.volume_multiplier);
@@ -857,7 +1027,7 @@ pub fn add_adsr_to_channel(
                 time: adj.time,
                 timer_reload: nth_micro_timer_reload(
                     sample.base_timer_reload(),
-                    note_offset as f32 + adj.pitch_adjust,
+                    pitch_offset as f32 + adj.pitch_adjust,
                 ),
             })
             .collect(),
@@ -869,7 +1039,7 @@ pub fn add_adsr_to_channel(
                 time: adj.time,
                 timer_reload: nth_micro_timer_reload(
                     sample.base_timer_reload(),
-                    note_offset as f32 + adj.pitch_adjust,
+                    pitch_offset as f32 + adj.pitch_adjust,
                 ),
             })
             .collect(),
@@ -929,7 +1099,7 @@ pub fn add_adsr_to_channel(
                         time: i,
                         timer_reload: nth_micro_timer_reload(
                             sample.base_timer_reload(),
-                            note_offset as f32 + wobble,
+                            pitch_offset as f32 + wobble,
                         ),
                     });
                 }
@@ -937,10 +1107,14 @@ pub fn add_adsr_to_channel(
                     time: *duration + 1,
                     timer_reload: nth_micro_timer_reload(
                         sample.base_timer_reload(),
-                        note_offset as f32,
+                        pitch_offset as f32,
                     ),
                 });
-                println!("PITHC: {:?}", pitch_adjustments);
+                if log::enabled(Category::Envelope, Level::Trace) {
+                    log::log(Event::PitchAdjustments {
+                        adjustments: pitch_adjustments.clone(),
+                    });
+                }
                 let decay_constant =
                     (1.0 / *duration as f32) * (this_sustain as f32 / attack_volume as f32).ln();
                 Some(DecayEnvelope::Exponential {
@@ -961,7 +1135,7 @@ pub fn add_adsr_to_channel(
                         time: i,
                         timer_reload: nth_micro_timer_reload(
                             sample.base_timer_reload(),
-                            note_offset as f32 + rise,
+                            pitch_offset as f32 + rise,
                         ),
                     });
                 }
@@ -1035,7 +1209,7 @@ pub fn add_adsr_to_channel(
                         time,
                         timer_reload: nth_micro_timer_reload(
                             sample.base_timer_reload(),
-                            n as f32 + step / 2.0,
+                            (n as i32 + transpose) as f32 + step / 2.0,
                         ),
                     });
 
@@ -1048,7 +1222,10 @@ pub fn add_adsr_to_channel(
                     time += 1;
                     pitch_adjustments.push(TimedPitchAdjustment {
                         time,
-                        timer_reload: nth_micro_timer_reload(sample.base_timer_reload(), n as f32),
+                        timer_reload: nth_micro_timer_reload(
+                            sample.base_timer_reload(),
+                            (n as i32 + transpose) as f32,
+                        ),
                     });
                 }
             }
@@ -1094,10 +1271,10 @@ pub fn add_adsr_to_channel(
         future_adsr,
         tick,
         range,
-        note.time as usize + repeat_count * TRACK_LENGTH,
+        age,
     );
 
-    channel_id
+    Some(channel_id)
 }
 
 /*pub fn add_drum_to_channel(
@@ -1108,7 +1285,7 @@ pub fn add_adsr_to_channel(
     adsr: &Adsr,
     future_adsr: VecDeque<(u32, Adsr)>,
     tick: u32,
-    previous_notes: &mut [Option<u8>; 4],
+    previous_notes: &mut [Option<u8>; TRACK_SLOTS],
 ) -> usize {
     let Adsr {
         sample,
@@ -1223,7 +1400,11 @@ pub fn add_adsr_to_channel(
                         note_offset as f32,
                     ),
                 });
-                println!("PITHC: {:?}", pitch_adjustments);
+                if log::enabled(Category::Envelope, Level::Trace) {
+                    log::log(Event::PitchAdjustments {
+                        adjustments: pitch_adjustments.clone(),
+                    });
+                }
                 let decay_constant =
                     (1.0 / *duration as f32) * (this_sustain as f32 / attack_volume as f32).ln();
                 Some(DecayEnvelope::Exponential {
@@ -1359,7 +1540,11 @@ pub fn add_adsr_to_channel(
         })
     };
 
-    println!("ADJ {:?}", pitch_adjustments);
+    if log::enabled(Category::Envelope, Level::Trace) {
+        log::log(Event::PitchAdjustments {
+            adjustments: pitch_adjustments.clone(),
+        });
+    }
 
     channel_manager.allocate_pcm(
         note.clone(),
@@ -1377,8 +1562,34 @@ fn nth_timer_reload(original: u16, offset: i32) -> u16 {
     let timer = 512.0;
     let max_reload = 65536.0;
     let m = timer / (max_reload - original as f32);
-    println!("offset: {}, M: {}", offset, m);
-    (max_reload - timer / (2_f32.powf(offset as f32 / 12.0) * m)).round() as u16
+    let timer_reload = (max_reload - timer / (2_f32.powf(offset as f32 / 12.0) * m)).round() as u16;
+    log::log(Event::TimerReload {
+        original,
+        offset,
+        timer_reload,
+    });
+    timer_reload
+}
+
+/// PSG instruments with a known base reload follow pitches measured from the game,
+/// which drift from equal temperament. Past either end of a table, they carry on from
+/// its last entry.
+fn nth_psg_timer_reload(original: u16, offset: i32) -> u16 {
+    const TABLE_LENGTH: i32 = 25;
+    let table: fn(i32) -> u16 = match original {
+        62868 => nth_timer_reload_ting_ting,
+        22792 => nth_timer_reload_bong_bong,
+        54852 => nth_timer_reload_ding_ding,
+        41548 => nth_timer_reload_bing_bing,
+        //60196 => nth_timer_reload_bling_bling,
+        _ => return nth_timer_reload(original, offset),
+    };
+    let index = offset.clamp(0, TABLE_LENGTH - 1);
+    if index == offset {
+        table(offset)
+    } else {
+        nth_timer_reload(table(index), offset - index)
+    }
 }
 
 fn nth_timer_reload_ting_ting(offset: i32) -> u16 {
@@ -1416,6 +1627,11 @@ fn nth_timer_reload_bling_bling(offset: i32) -> u16 {
     ][offset as usize]
 }
 
+/// Samples per second a channel plays at with this timer reload
+pub fn timer_reload_sample_rate(timer_reload: u16) -> u32 {
+    (SAMPLE_RATE as u32 * 512) / (0x10000 - timer_reload as u32)
+}
+
 pub fn nth_micro_timer_reload(original: u16, offset: f32) -> u16 {
     let timer = 512.0;
     let max_reload = 65536.0;
@@ -1559,6 +1775,642 @@ mod tests {
     }
 }
 
+/// Plays `note` the way the sequencer does, picking the instrument's ADSR for it.
+/// `age` is samples since the note's step and `random_seed` picks between random ADSRs.
+/// False when there was no channel free for it.
+pub fn start_note(
+    channel_manager: &mut ChannelManager,
+    spu: &mut Spu,
+    note: &QueuedNote,
+    instruments: &[Instrument],
+    previous_notes: &mut [Option<u8>; TRACK_SLOTS],
+    age: usize,
+    transpose: i32,
+    random_seed: usize,
+) -> bool {
+    match &instruments[note.instrument as usize].instructions {
+        InstrumentInstructions::Adsr(adsr) => {
+            channel_manager.release_tracks(note.track);
+
+            add_adsr_to_channel(
+                channel_manager,
+                spu,
+                note,
+                (0, 24),
+                instruments,
+                &adsr,
+                VecDeque::new(),
+                0,
+                previous_notes,
+                None,
+                age,
+                transpose,
+            )
+            .is_some()
+        }
+        InstrumentInstructions::Dual(adsr_pair) => {
+            channel_manager.release_tracks(note.track);
+            let mut started = false;
+            for adsr in adsr_pair.iter() {
+                started |= add_adsr_to_channel(
+                    channel_manager,
+                    spu,
+                    note,
+                    (0, 24),
+                    instruments,
+                    &adsr,
+                    VecDeque::new(),
+                    0,
+                    previous_notes,
+                    None,
+                    age,
+                    transpose,
+                )
+                .is_some();
+            }
+
+            //println!("CHANNELS: {:?}", channel_manager);
+            started
+        }
+        InstrumentInstructions::Ranged(ranged_adsr) => {
+            channel_manager.release_tracks(note.track);
+            let adsr = ranged_adsr
+                .iter()
+                .find(|r| note.note >= r.low && note.note <= r.high)
+                .unwrap();
+
+            add_adsr_to_channel(
+                channel_manager,
+                spu,
+                note,
+                (adsr.low as u32, adsr.high as u32),
+                instruments,
+                &adsr.adsr,
+                VecDeque::new(),
+                0,
+                previous_notes,
+                None,
+                age,
+                transpose,
+            )
+            .is_some()
+        }
+        InstrumentInstructions::Cricket(cricket_adsr) => {
+            channel_manager.release_tracks(note.track);
+            let adsr = cricket_adsr
+                .iter()
+                .find(|r| note.note >= r.low && note.note <= r.high)
+                .unwrap();
+
+            let note_offset = (note.note - adsr.low) as u32;
+
+            add_adsr_to_channel(
+                channel_manager,
+                spu,
+                note,
+                (adsr.low as u32, adsr.high as u32),
+                instruments,
+                &adsr.adsr,
+                adsr.future_adsr.clone(),
+                0,
+                previous_notes,
+                Some(PossiblePitchAdjustment::Unprocessed(
+                    adsr.pitch_adjustments.clone(),
+                )),
+                age,
+                transpose,
+            )
+            .is_some()
+        }
+        InstrumentInstructions::TimedMultiple(timed_adsr) => {
+            channel_manager.release_tracks(note.track);
+
+            let (_, adsr) = timed_adsr.iter().find(|(time, _)| *time == 0).unwrap();
+
+            let mut remaining: VecDeque<_> = timed_adsr
+                .iter()
+                .filter(|(time, _)| *time != 0)
+                .cloned()
+                .collect();
+
+            // TODO: Put this sometwhere else, in data...
+            {
+                if instruments[note.instrument as usize].name == "Yoshi" {
+                    remaining[0].0 = interp_val_until(note.note as u32, (remaining[0].0, 16), 24);
+                }
+            }
+
+            add_adsr_to_channel(
+                channel_manager,
+                spu,
+                note,
+                (0, 24),
+                instruments,
+                adsr,
+                remaining,
+                0,
+                previous_notes,
+                None,
+                age,
+                transpose,
+            )
+            .is_some()
+        }
+        InstrumentInstructions::Random(adsrs) => {
+            channel_manager.release_tracks(note.track);
+
+            // TODO: Replace with random
+            let adsr = &adsrs[(random_seed
+                + previous_notes[note.track as usize].unwrap_or_default() as usize)
+                % adsrs.len()];
+
+            add_adsr_to_channel(
+                channel_manager,
+                spu,
+                note,
+                (0, 24),
+                instruments,
+                &adsr,
+                VecDeque::new(),
+                0,
+                previous_notes,
+                None,
+                age,
+                transpose,
+            )
+            .is_some()
+        }
+    }
+}
+
+fn play_drum_on_spu(
+    spu: &mut Spu,
+    channel_id: usize,
+    sample: &DrumSample,
+    pan: u8,
+    initial_volume: u32,
+    volume_multiplier: f32,
+) {
+    {
+        spu.set_channel_pan(channel_id, pan);
+        spu.set_adjusted_channel_volume(channel_id, initial_volume, volume_multiplier);
+        spu.set_channel_timer_reload(channel_id, sample.timer_reload as u32);
+        spu.set_channel_loop_pos(channel_id, sample.loop_pos);
+        spu.set_channel_length(channel_id, sample.length);
+        spu.set_channel_src_address(channel_id, sample.src_address);
+        spu.channel_play_note(channel_id, sample.is_repeating);
+    }
+}
+
+/// Plays `drum` the way the sequencer does, `age` is samples since the drum's step.
+/// False when there was no channel free for it.
+pub fn start_drum(
+    channel_manager: &mut ChannelManager,
+    spu: &mut Spu,
+    drum: &QueuedDrum,
+    rhythm_sections: &[RhythmSection; RHYTHM_SECTION_COUNT],
+    age: usize,
+) -> bool {
+    let pan_addition = drum.pan_addition;
+    let volume_multiplier = drum.volume_multiplier;
+    let drum_set = drum.section;
+    let adjust_pan =
+        |pan: u8| -> u8 { (pan as i32 + pan_addition).max(0).min(u8::MAX as i32) as u8 };
+
+    channel_manager.release_tracks(drum.pretend_track);
+    let requested = match &rhythm_sections[drum_set].instructions[drum.id] {
+        DrumInstructions::Noise {
+            sample,
+            attack,
+            decay,
+            sustain,
+            release,
+        } => channel_manager.request_channel_noise(drum.pretend_track),
+        _ => channel_manager.request_channel_pcm(drum.pretend_track),
+    };
+    let channel_id = match requested {
+        Some(channel_id) => channel_id,
+        None => return false,
+    };
+
+    match &rhythm_sections[drum_set].instructions[drum.id] {
+        DrumInstructions::Dsr {
+            sample,
+            decay,
+            sustain,
+            release,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let this_sustain = sustain.volume;
+            let decay_constant =
+                (1.0 / decay.duration as f32) * (this_sustain as f32 / initial_volume as f32).ln();
+
+            let envelope = Some(Envelope {
+                attack: None,
+                initial_volume,
+                decay: Some(DecayEnvelope::Exponential {
+                    constant: decay_constant,
+                    duration: decay.duration,
+                }),
+                sustain: Some(SustainEnvelope {
+                    volume: this_sustain,
+                    duration: sustain.duration,
+                }),
+                release: Some(release.clone()),
+            });
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::Sr {
+            sample,
+            sustain,
+            release,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let envelope = Some(Envelope {
+                attack: None,
+                initial_volume,
+                decay: None,
+                sustain: Some(SustainEnvelope {
+                    volume: sustain.volume,
+                    duration: sustain.duration,
+                }),
+                release: Some(release.clone()),
+            });
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::Decay {
+            sample,
+            decay,
+            final_volume,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let this_sustain = (*final_volume as f32).max(0.1);
+            let decay_constant =
+                (1.0 / decay.duration as f32) * (this_sustain as f32 / initial_volume as f32).ln();
+
+            let decay = match decay {
+                DecayInstructions {
+                    duration,
+                    kind: DecayKind::Exponential,
+                } => DecayEnvelope::Exponential {
+                    constant: decay_constant,
+                    duration: *duration,
+                },
+                DecayInstructions {
+                    duration,
+                    kind: DecayKind::Linear,
+                } => DecayEnvelope::Linear {
+                    duration: *duration,
+                },
+            };
+
+            let envelope = Some(Envelope {
+                attack: None,
+                initial_volume,
+                decay: Some(decay),
+                sustain: None,
+                release: None,
+            });
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::ExactVolumeAdjust {
+            sample,
+            adjustments,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let envelope = Some(Envelope {
+                attack: Some(AttackEnvelope::Exact {
+                    adjustments: adjustments.clone(),
+                }),
+                initial_volume,
+                decay: None,
+                sustain: None,
+                release: None,
+            });
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::PitchThenRelease {
+            sample,
+            sustain,
+            release,
+            adjustments,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let envelope = Some(Envelope {
+                attack: None,
+                initial_volume,
+                decay: None,
+                sustain: Some(SustainEnvelope {
+                    volume: sustain.volume,
+                    duration: sustain.duration,
+                }),
+                release: Some(release.clone()),
+            });
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                adjustments.clone(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::DsrPitchAdjust {
+            sample,
+            decay,
+            sustain,
+            release,
+            adjustments,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let this_sustain = sustain.volume;
+            let decay_constant =
+                (1.0 / decay.duration as f32) * (this_sustain as f32 / initial_volume as f32).ln();
+
+            let decay = match decay {
+                DecayInstructions {
+                    duration,
+                    kind: DecayKind::Exponential,
+                } => DecayEnvelope::Exponential {
+                    constant: decay_constant,
+                    duration: *duration,
+                },
+                DecayInstructions {
+                    duration,
+                    kind: DecayKind::Linear,
+                } => DecayEnvelope::Linear {
+                    duration: *duration,
+                },
+            };
+
+            let envelope = Some(Envelope {
+                attack: None,
+                initial_volume,
+                decay: Some(decay),
+                sustain: Some(SustainEnvelope {
+                    volume: sustain.volume,
+                    duration: sustain.duration,
+                }),
+                release: Some(release.clone()),
+            });
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                adjustments.clone(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::RepeatOnce {
+            sample,
+            repeat_time,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let envelope = None;
+            let queued_samples = vec![(sample.clone(), *repeat_time)];
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                queued_samples,
+                age,
+            );
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+        DrumInstructions::Multiple { samples } => {
+            //assert_eq!(samples[0].0.time, 0);
+            //let sample = samples[0].0.sample.clone;
+
+            //let initial_volume = sample.volume;
+            //let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let envelope = None;
+            //repeat_sample = Some((sample.clone(), *repeat_time));
+            let mut queued_samples = Vec::new();
+            for sample in samples {
+                // TODO: sample.time amount ahead of original time
+                queued_samples.push((sample.sample.clone(), sample.time));
+            }
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                samples[0].sample.volume,
+                Vec::new(),
+                queued_samples,
+                age,
+            );
+        }
+        DrumInstructions::Noise {
+            sample,
+            attack,
+            decay,
+            sustain,
+            release,
+        } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let this_sustain = sustain.volume;
+            let decay_constant =
+                (1.0 / decay.duration as f32) * (this_sustain as f32 / initial_volume as f32).ln();
+
+            let attack = match attack.clone() {
+                AttackInstructions::Exact { adjustments } => AttackEnvelope::Exact { adjustments },
+                AttackInstructions::Linear { volume, duration } => {
+                    AttackEnvelope::Linear { volume, duration }
+                }
+            };
+
+            let envelope = Some(Envelope {
+                attack: Some(attack),
+                initial_volume,
+                decay: Some(DecayEnvelope::Exponential {
+                    constant: decay_constant,
+                    duration: decay.duration,
+                }),
+                sustain: Some(SustainEnvelope {
+                    volume: sustain.volume,
+                    duration: sustain.duration,
+                }),
+                release: Some(release.clone()),
+            });
+
+            {
+                spu.set_channel_pan(channel_id, adjust_pan(sample.base_pan));
+                spu.set_adjusted_channel_volume(channel_id, initial_volume, volume_multiplier);
+                spu.set_channel_timer_reload(channel_id, timer_reload as u32);
+                spu.channel_play_noise(channel_id);
+            }
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                Vec::new(),
+                age,
+            );
+        }
+        DrumInstructions::Simple { sample } => {
+            let initial_volume = sample.volume;
+            let timer_reload = sample.timer_reload;
+            //let src_address = sample
+
+            let envelope = None;
+
+            channel_manager.allocate_drum(
+                channel_id,
+                drum.clone(),
+                envelope,
+                initial_volume,
+                Vec::new(),
+                Vec::new(),
+                age,
+            );
+
+            play_drum_on_spu(
+                spu,
+                channel_id,
+                sample,
+                adjust_pan(sample.base_pan),
+                initial_volume,
+                volume_multiplier,
+            );
+        }
+    }
+    true
+}
+
+#[derive(DeBin, SerBin)]
 pub struct Timing {
     pub tiny_tick: usize,
     pub phrase_tick: usize,
@@ -1569,26 +2421,36 @@ pub fn play_stuff(
     multiplier: usize,
     timing: &mut Timing,
     channel_manager: &mut ChannelManager,
-    record: &Record,
+    record: &mut Record,
     instruments: &[Instrument],
     rhythm_sections: &[RhythmSection; RHYTHM_SECTION_COUNT],
-    previous_notes: &mut [Option<u8>; 4],
+    previous_notes: &mut [Option<u8>; TRACK_SLOTS],
     chunks: ChunksMut<f32>,
-    my_volume: f32,
+    mixer: &mut Mixer,
 ) {
     let mut spu = spu.lock().unwrap();
 
     for samples_out in chunks {
-        let repeat_count = match record.repeats {
+        // Tempo changes wait for the next step, where the song's place maps exactly
+        if timing.tiny_tick % record.note_rate == 0 {
+            if let Some(tempo) = record.pending_tempo.take() {
+                let step = timing.tiny_tick / record.note_rate;
+                record.set_tempo(tempo);
+                timing.tiny_tick = step * record.note_rate;
+            }
+        }
+
+        let repeats = record.playback_repeats();
+        let repeat_count = match repeats {
             Repeats::None => 0,
-            _ => timing.tiny_tick / (TRACK_LENGTH * record.note_rate),
+            _ => timing.tiny_tick / record.loop_length(),
         };
-        timing.phrase_tick = match record.repeats.exact() {
-            None => timing.tiny_tick % (TRACK_LENGTH * record.note_rate),
+        timing.phrase_tick = match repeats.exact() {
+            None => timing.tiny_tick % record.loop_length(),
             Some(0) => timing.tiny_tick,
             Some(max_repeats) => {
                 if repeat_count <= max_repeats {
-                    timing.tiny_tick % (TRACK_LENGTH * record.note_rate)
+                    timing.tiny_tick % record.loop_length()
                 } else {
                     timing.tiny_tick
                 }
@@ -1596,12 +2458,11 @@ pub fn play_stuff(
         };
 
         //println!("PHR {}", timing.phrase_tick);
-        if let Some(loop_times) = record.repeats.loop_times() {
-            if timing.tiny_tick
-                == loop_times * TRACK_LENGTH * record.phrase_count * record.note_rate
-            {
-                for t in 0..16 {
-                    channel_manager.release_tracks(t, timing.tiny_tick, &record);
+        // Past the end rather than at it, in case the loop count was lowered mid-song
+        if let Some(length) = record.length() {
+            if timing.tiny_tick >= length {
+                for t in 0..FIRST_LIVE_TRACK {
+                    channel_manager.release_tracks(t);
                 }
             }
         }
@@ -1617,6 +2478,8 @@ pub fn play_stuff(
                 None => 0,
             }
         };
+        // Counted within the phrase, which holds every step the song loops over
+        let note_age = |time: u32| timing.phrase_tick - time as usize * record.note_rate;
         for note in record.notes.iter().filter(|note| {
             //println!(
             //    "NT: {}, {}",
@@ -1625,146 +2488,16 @@ pub fn play_stuff(
             //);
             (note.time as usize * record.note_rate) + swing_adjust(note.time) == timing.phrase_tick
         }) {
-            match &instruments[note.instrument as usize].instructions {
-                InstrumentInstructions::Adsr(adsr) => {
-                    channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
-
-                    add_adsr_to_channel(
-                        channel_manager,
-                        &mut spu,
-                        &note,
-                        (0, 24),
-                        &instruments,
-                        &adsr,
-                        VecDeque::new(),
-                        0,
-                        previous_notes,
-                        None,
-                        repeat_count,
-                    );
-                }
-                InstrumentInstructions::Dual(adsr_pair) => {
-                    channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
-                    for adsr in adsr_pair.iter() {
-                        add_adsr_to_channel(
-                            channel_manager,
-                            &mut spu,
-                            &note,
-                            (0, 24),
-                            &instruments,
-                            &adsr,
-                            VecDeque::new(),
-                            0,
-                            previous_notes,
-                            None,
-                            repeat_count,
-                        );
-                    }
-
-                    //println!("CHANNELS: {:?}", channel_manager);
-                }
-                InstrumentInstructions::Ranged(ranged_adsr) => {
-                    channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
-                    let adsr = ranged_adsr
-                        .iter()
-                        .find(|r| note.note >= r.low && note.note <= r.high)
-                        .unwrap();
-
-                    add_adsr_to_channel(
-                        channel_manager,
-                        &mut spu,
-                        &note,
-                        (adsr.low as u32, adsr.high as u32),
-                        &instruments,
-                        &adsr.adsr,
-                        VecDeque::new(),
-                        0,
-                        previous_notes,
-                        None,
-                        repeat_count,
-                    );
-                }
-                InstrumentInstructions::Cricket(cricket_adsr) => {
-                    channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
-                    let adsr = cricket_adsr
-                        .iter()
-                        .find(|r| note.note >= r.low && note.note <= r.high)
-                        .unwrap();
-
-                    let note_offset = (note.note - adsr.low) as u32;
-
-                    add_adsr_to_channel(
-                        channel_manager,
-                        &mut spu,
-                        &note,
-                        (adsr.low as u32, adsr.high as u32),
-                        &instruments,
-                        &adsr.adsr,
-                        adsr.future_adsr.clone(),
-                        0,
-                        previous_notes,
-                        Some(PossiblePitchAdjustment::Unprocessed(
-                            adsr.pitch_adjustments.clone(),
-                        )),
-                        repeat_count,
-                    );
-                }
-                InstrumentInstructions::TimedMultiple(timed_adsr) => {
-                    channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
-
-                    let (_, adsr) = timed_adsr.iter().find(|(time, _)| *time == 0).unwrap();
-
-                    let mut remaining: VecDeque<_> = timed_adsr
-                        .iter()
-                        .filter(|(time, _)| *time != 0)
-                        .cloned()
-                        .collect();
-
-                    // TODO: Put this sometwhere else, in data...
-                    {
-                        if instruments[note.instrument as usize].name == "Yoshi" {
-                            remaining[0].0 =
-                                interp_val_until(note.note as u32, (remaining[0].0, 16), 24);
-                        }
-                    }
-
-                    let channel_id = add_adsr_to_channel(
-                        channel_manager,
-                        &mut spu,
-                        &note,
-                        (0, 24),
-                        &instruments,
-                        adsr,
-                        remaining,
-                        0,
-                        previous_notes,
-                        None,
-                        repeat_count,
-                    );
-                }
-                InstrumentInstructions::Random(adsrs) => {
-                    channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
-
-                    // TODO: Replace with random
-                    let adsr = &adsrs[(timing.tiny_tick
-                        + previous_notes[note.track as usize].unwrap_or_default() as usize)
-                        % adsrs.len()];
-
-                    add_adsr_to_channel(
-                        channel_manager,
-                        &mut spu,
-                        &note,
-                        (0, 24),
-                        &instruments,
-                        &adsr,
-                        VecDeque::new(),
-                        0,
-                        previous_notes,
-                        None,
-                        repeat_count,
-                    );
-                }
-            }
+            start_note(
+                channel_manager,
+                &mut spu,
+                note,
+                instruments,
+                previous_notes,
+                note_age(note.time),
+                record.transpose,
+                timing.tiny_tick,
+            );
         }
 
         // TEST NOTE
@@ -1779,467 +2512,14 @@ pub fn play_stuff(
             .cloned()
             .collect();
 
-        fn play_drum_on_spu(
-            spu: &mut Spu,
-            channel_id: usize,
-            sample: &DrumSample,
-            pan: u8,
-            initial_volume: u32,
-            volume_multiplier: f32,
-        ) {
-            {
-                spu.set_channel_pan(channel_id, pan);
-                spu.set_adjusted_channel_volume(channel_id, initial_volume, volume_multiplier);
-                spu.set_channel_timer_reload(channel_id, sample.timer_reload as u32);
-                spu.set_channel_loop_pos(channel_id, sample.loop_pos);
-                spu.set_channel_length(channel_id, sample.length);
-                spu.set_channel_src_address(channel_id, sample.src_address);
-                spu.channel_play_note(channel_id, sample.is_repeating);
-            }
-        }
-
-        for drum in drums_now.into_iter() {
-            let pan_addition = drum.pan_addition;
-            let volume_multiplier = drum.volume_multiplier;
-            let drum_set = drum.section;
-            let adjust_pan =
-                |pan: u8| -> u8 { (pan as i32 + pan_addition).max(0).min(u8::MAX as i32) as u8 };
-
-            channel_manager.release_tracks(drum.pretend_track, timing.tiny_tick, &record);
-            let channel_id = match &rhythm_sections[drum_set].instructions[drum.id] {
-                DrumInstructions::Noise {
-                    sample,
-                    attack,
-                    decay,
-                    sustain,
-                    release,
-                } => channel_manager.request_channel_noise(drum.pretend_track),
-                _ => channel_manager.request_channel_pcm(drum.pretend_track),
-            }
-            .unwrap();
-
-            let true_time = drum.time as usize + repeat_count * TRACK_LENGTH;
-
-            match &rhythm_sections[drum_set].instructions[drum.id] {
-                DrumInstructions::Dsr {
-                    sample,
-                    decay,
-                    sustain,
-                    release,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let this_sustain = sustain.volume;
-                    let decay_constant = (1.0 / decay.duration as f32)
-                        * (this_sustain as f32 / initial_volume as f32).ln();
-
-                    let envelope = Some(Envelope {
-                        attack: None,
-                        initial_volume,
-                        decay: Some(DecayEnvelope::Exponential {
-                            constant: decay_constant,
-                            duration: decay.duration,
-                        }),
-                        sustain: Some(SustainEnvelope {
-                            volume: this_sustain,
-                            duration: sustain.duration,
-                        }),
-                        release: Some(release.clone()),
-                    });
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::Sr {
-                    sample,
-                    sustain,
-                    release,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let envelope = Some(Envelope {
-                        attack: None,
-                        initial_volume,
-                        decay: None,
-                        sustain: Some(SustainEnvelope {
-                            volume: sustain.volume,
-                            duration: sustain.duration,
-                        }),
-                        release: Some(release.clone()),
-                    });
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::Decay {
-                    sample,
-                    decay,
-                    final_volume,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let this_sustain = (*final_volume as f32).max(0.1);
-                    let decay_constant = (1.0 / decay.duration as f32)
-                        * (this_sustain as f32 / initial_volume as f32).ln();
-
-                    let decay = match decay {
-                        DecayInstructions {
-                            duration,
-                            kind: DecayKind::Exponential,
-                        } => DecayEnvelope::Exponential {
-                            constant: decay_constant,
-                            duration: *duration,
-                        },
-                        DecayInstructions {
-                            duration,
-                            kind: DecayKind::Linear,
-                        } => DecayEnvelope::Linear {
-                            duration: *duration,
-                        },
-                    };
-
-                    let envelope = Some(Envelope {
-                        attack: None,
-                        initial_volume,
-                        decay: Some(decay),
-                        sustain: None,
-                        release: None,
-                    });
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::ExactVolumeAdjust {
-                    sample,
-                    adjustments,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let envelope = Some(Envelope {
-                        attack: Some(AttackEnvelope::Exact {
-                            adjustments: adjustments.clone(),
-                        }),
-                        initial_volume,
-                        decay: None,
-                        sustain: None,
-                        release: None,
-                    });
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::PitchThenRelease {
-                    sample,
-                    sustain,
-                    release,
-                    adjustments,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let envelope = Some(Envelope {
-                        attack: None,
-                        initial_volume,
-                        decay: None,
-                        sustain: Some(SustainEnvelope {
-                            volume: sustain.volume,
-                            duration: sustain.duration,
-                        }),
-                        release: Some(release.clone()),
-                    });
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        adjustments.clone(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::DsrPitchAdjust {
-                    sample,
-                    decay,
-                    sustain,
-                    release,
-                    adjustments,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let this_sustain = sustain.volume;
-                    let decay_constant = (1.0 / decay.duration as f32)
-                        * (this_sustain as f32 / initial_volume as f32).ln();
-
-                    let decay = match decay {
-                        DecayInstructions {
-                            duration,
-                            kind: DecayKind::Exponential,
-                        } => DecayEnvelope::Exponential {
-                            constant: decay_constant,
-                            duration: *duration,
-                        },
-                        DecayInstructions {
-                            duration,
-                            kind: DecayKind::Linear,
-                        } => DecayEnvelope::Linear {
-                            duration: *duration,
-                        },
-                    };
-
-                    let envelope = Some(Envelope {
-                        attack: None,
-                        initial_volume,
-                        decay: Some(decay),
-                        sustain: Some(SustainEnvelope {
-                            volume: sustain.volume,
-                            duration: sustain.duration,
-                        }),
-                        release: Some(release.clone()),
-                    });
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        adjustments.clone(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::RepeatOnce {
-                    sample,
-                    repeat_time,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let envelope = None;
-                    let queued_samples = vec![(sample.clone(), *repeat_time)];
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        queued_samples,
-                        true_time,
-                    );
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-                DrumInstructions::Multiple { samples } => {
-                    //assert_eq!(samples[0].0.time, 0);
-                    //let sample = samples[0].0.sample.clone;
-
-                    //let initial_volume = sample.volume;
-                    //let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let envelope = None;
-                    //repeat_sample = Some((sample.clone(), *repeat_time));
-                    let mut queued_samples = Vec::new();
-                    for sample in samples {
-                        // TODO: sample.time amount ahead of original time
-                        queued_samples.push((sample.sample.clone(), sample.time));
-                    }
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        samples[0].sample.volume,
-                        Vec::new(),
-                        queued_samples,
-                        true_time,
-                    );
-                }
-                DrumInstructions::Noise {
-                    sample,
-                    attack,
-                    decay,
-                    sustain,
-                    release,
-                } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let this_sustain = sustain.volume;
-                    let decay_constant = (1.0 / decay.duration as f32)
-                        * (this_sustain as f32 / initial_volume as f32).ln();
-
-                    let attack = match attack.clone() {
-                        AttackInstructions::Exact { adjustments } => {
-                            AttackEnvelope::Exact { adjustments }
-                        }
-                        AttackInstructions::Linear { volume, duration } => {
-                            AttackEnvelope::Linear { volume, duration }
-                        }
-                    };
-
-                    let envelope = Some(Envelope {
-                        attack: Some(attack),
-                        initial_volume,
-                        decay: Some(DecayEnvelope::Exponential {
-                            constant: decay_constant,
-                            duration: decay.duration,
-                        }),
-                        sustain: Some(SustainEnvelope {
-                            volume: sustain.volume,
-                            duration: sustain.duration,
-                        }),
-                        release: Some(release.clone()),
-                    });
-
-                    {
-                        spu.set_channel_pan(channel_id, adjust_pan(sample.base_pan));
-                        spu.set_adjusted_channel_volume(
-                            channel_id,
-                            initial_volume,
-                            volume_multiplier,
-                        );
-                        spu.set_channel_timer_reload(channel_id, timer_reload as u32);
-                        spu.channel_play_noise(channel_id);
-                    }
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        Vec::new(),
-                        true_time,
-                    );
-                }
-                DrumInstructions::Simple { sample } => {
-                    let initial_volume = sample.volume;
-                    let timer_reload = sample.timer_reload;
-                    //let src_address = sample
-
-                    let envelope = None;
-
-                    channel_manager.allocate_drum(
-                        channel_id,
-                        drum.clone(),
-                        envelope,
-                        initial_volume,
-                        Vec::new(),
-                        Vec::new(),
-                        true_time,
-                    );
-
-                    play_drum_on_spu(
-                        &mut spu,
-                        channel_id,
-                        sample,
-                        adjust_pan(sample.base_pan),
-                        initial_volume,
-                        volume_multiplier,
-                    );
-                }
-            }
+        for drum in drums_now.iter() {
+            start_drum(
+                channel_manager,
+                &mut spu,
+                drum,
+                rhythm_sections,
+                note_age(drum.time),
+            );
         }
 
         timing.tiny_tick += 1;
@@ -2255,16 +2535,15 @@ pub fn play_stuff(
                     future_adsr,
                     start_tick,
                     queued_samples,
-                    true_time,
+                    age,
                     ..
                 } => {
+                    *age += 1;
                     //println!("aaa {}, {}", timing.tiny_tick, sound.time());
                     //  % record.note_rate?
-                    if (timing.tiny_tick - *true_time * record.note_rate) % EVENT_TIMING == 0 {
+                    if *age % EVENT_TIMING == 0 {
                         let mut should_be_freed = false;
-                        let tick = ((timing.tiny_tick - *true_time as usize * record.note_rate)
-                            / EVENT_TIMING) as u32
-                            - *start_tick;
+                        let tick = (*age / EVENT_TIMING) as u32 - *start_tick;
                         if let Some(adj) = pitch_adjustments
                             .iter()
                             .filter(|adj| adj.time <= tick)
@@ -2448,10 +2727,7 @@ pub fn play_stuff(
 
                                 if should_be_freed {
                                     if let Some((next_time, adsr)) = future_adsr.front().cloned() {
-                                        let tick = ((timing.tiny_tick
-                                            - *true_time * record.note_rate)
-                                            / EVENT_TIMING)
-                                            as u32;
+                                        let tick = (*age / EVENT_TIMING) as u32;
                                         if tick >= next_time {
                                             play_next.push(channel_id);
                                         }
@@ -2461,6 +2737,9 @@ pub fn play_stuff(
                                             0,
                                             sound.volume_multiplier(),
                                         );
+                                        log::log(Event::ChannelFreed {
+                                            channel: channel_id as u8,
+                                        });
                                         *channel = Channel::Open;
                                     }
                                 }
@@ -2475,13 +2754,11 @@ pub fn play_stuff(
                     initial_release_volume,
                     kill_tick,
                     release,
-                    true_time,
+                    age,
                 } => {
-                    if (timing.tiny_tick - *true_time as usize * record.note_rate) % EVENT_TIMING
-                        == 0
-                    {
-                        let tick = ((timing.tiny_tick - *true_time as usize * record.note_rate)
-                            / EVENT_TIMING) as u32;
+                    *age += 1;
+                    if *age % EVENT_TIMING == 0 {
+                        let tick = (*age / EVENT_TIMING) as u32;
                         let release_constant = -0.17;
                         let release_tick = tick - *kill_tick;
                         match release {
@@ -2533,6 +2810,9 @@ pub fn play_stuff(
                         spu.set_adjusted_channel_volume(channel_id, *volume, 1.0);
 
                         if *volume <= 1 {
+                            log::log(Event::ChannelFreed {
+                                channel: channel_id as u8,
+                            });
                             *channel = Channel::Open;
                         }
                     }
@@ -2549,6 +2829,7 @@ pub fn play_stuff(
                     future_adsr,
                     pitch_adjustments,
                     range,
+                    age,
                     ..
                 } => {
                     adsr = Some((
@@ -2556,19 +2837,26 @@ pub fn play_stuff(
                         note.clone(),
                         pitch_adjustments.clone(),
                         *range,
+                        *age,
                     ));
                 }
                 _ => {}
             }
-            if let Some((mut future_adsr, note, pitch_adjustments, range)) = adsr {
-                channel_manager.release_tracks(note.track, timing.tiny_tick, &record);
+            if let Some((mut future_adsr, note, pitch_adjustments, range, age)) = adsr {
+                channel_manager.release_tracks(note.track);
 
                 let (_, adsr) = future_adsr.pop_front().unwrap();
 
-                println!("{:?}", adsr);
+                if log::enabled(Category::Envelope, Level::Debug) {
+                    log::log(Event::NextAdsr {
+                        track: note.track,
+                        channel: i as u8,
+                        adsr: adsr.clone(),
+                    });
+                }
 
-                let tick = ((timing.tiny_tick - note.time as usize * record.note_rate)
-                    / EVENT_TIMING) as u32;
+                // From the note's age rather than the song's position, so live notes work too
+                let tick = (age / EVENT_TIMING) as u32;
 
                 add_adsr_to_channel(
                     channel_manager,
@@ -2581,7 +2869,8 @@ pub fn play_stuff(
                     tick,
                     previous_notes,
                     Some(PossiblePitchAdjustment::Exact(pitch_adjustments)),
-                    repeat_count,
+                    age,
+                    record.transpose,
                 );
             }
         }
@@ -2651,42 +2940,18 @@ pub fn play_stuff(
                 }
             }
         }*/*/
-        /*for &(tick, address, write) in &write_events {
-            if (glundex as f32 / 512.0 * 3.0) as u32 == hop {
-                glundex = 0;
-
-                grump += 1;
-                if (timing.tiny_tick as f32 / 4.0) > 32828.0 {
-                    hop -= 1;
-                } else if (timing.tiny_tick as f32 / 4.0) < 32828.0 {
-                    hop += 1;
-                }
-
-                timing.tiny_tick -= 32828 * 4;
-
-                println!("{}", timing.tiny_tick as f32 / 4.0);
-                println!("hop {}", hop as f32);
-            }
-
-            if (glundex as f32 / 512.0 * 3.0) as u32 == tick
-                && (glundex == 0 || ((glundex - 1) as f32 / 512.0 * 3.0) as u32 != tick)
-            {
-                match write {
-                    Write::u8(val) => spu.write8(address, val),
-                    Write::u16(val) => spu.write16(address, val),
-                    Write::u32(val) => spu.write32(address, val),
-                }
-            }
-        }*/
         //println!("MIX");
 
         //spu.transfer_output();
         //output.append(&mut spu.read_output(2048));
 
+        mixer.set_fade(record.fade(timing.tiny_tick));
+        mixer.step(&mut spu, &channel_manager.channels);
         let (left_output, right_output) = spu.mix(1);
 
-        samples_out[0] = my_volume * left_output as f32 / i16::MAX as f32;
-        samples_out[1] = my_volume * right_output as f32 / i16::MAX as f32;
+        let volume = mixer.current_master_volume();
+        samples_out[0] = volume * left_output as f32 / i16::MAX as f32;
+        samples_out[1] = volume * right_output as f32 / i16::MAX as f32;
 
         //println!("SA<PLE@: {}, {}", left_output, right_output);
         //glundex += 1;
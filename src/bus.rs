@@ -0,0 +1,291 @@
+use std::{fmt, io, sync::Arc};
+
+use crate::sample_bank::SampleBank;
+
+/// Main RAM is mirrored every 4 MiB across its 8 MiB windows
+pub const MAIN_RAM_SIZE: usize = 0x400000;
+pub const MAIN_RAM_MASK: u32 = 0x3FFFFF;
+/// What reads from unmapped addresses return
+pub const OPEN_BUS: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// A main RAM dump can't be bigger than main RAM
+    RamSize {
+        size: usize,
+    },
+    Unmapped {
+        address: u32,
+    },
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::RamSize { size } => write!(
+                f,
+                "RAM dump is {} bytes, expected at most {} bytes",
+                size, MAIN_RAM_SIZE
+            ),
+            BusError::Unmapped { address } => write!(f, "no memory mapped at {:#010X}", address),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<BusError> for io::Error {
+    fn from(err: BusError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+pub fn is_main_ram(address: u32) -> bool {
+    matches!(address & 0xFF800000, 0x02000000 | 0x02800000)
+}
+
+/// Memory that can be mapped onto the bus, addresses are full ARM7 addresses
+pub trait MemoryRegion: Send {
+    /// `None` when `address` isn't part of this region
+    fn read32(&self, address: u32) -> Option<u32>;
+    /// Returns false when `address` isn't part of this region
+    fn write32(&mut self, address: u32, val: u32) -> bool;
+}
+
+/// A dump of main RAM from its start, anything past the end of a shorter dump is unmapped
+pub struct MainRam {
+    data: Vec<u8>,
+}
+
+impl MainRam {
+    pub fn new(data: Vec<u8>) -> Result<MainRam, BusError> {
+        if data.len() > MAIN_RAM_SIZE {
+            return Err(BusError::RamSize { size: data.len() });
+        }
+        Ok(MainRam { data })
+    }
+
+    fn offset(&self, address: u32) -> Option<usize> {
+        let offset = (address & MAIN_RAM_MASK) as usize;
+        if is_main_ram(address) && offset + 4 <= self.data.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+impl MemoryRegion for MainRam {
+    fn read32(&self, address: u32) -> Option<u32> {
+        let offset = self.offset(address)?;
+        Some(u32::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+            self.data[offset + 3],
+        ]))
+    }
+
+    fn write32(&mut self, address: u32, val: u32) -> bool {
+        match self.offset(address) {
+            Some(offset) => {
+                self.data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A zeroed block of main RAM, such as a capture buffer
+pub struct RamRegion {
+    address: u32,
+    data: Vec<u8>,
+}
+
+impl RamRegion {
+    pub fn new(address: usize, size: usize) -> RamRegion {
+        RamRegion {
+            address: address as u32 & MAIN_RAM_MASK,
+            data: vec![0; size],
+        }
+    }
+
+    fn offset(&self, address: u32) -> Option<usize> {
+        let offset = (address & MAIN_RAM_MASK).wrapping_sub(self.address) as usize;
+        if is_main_ram(address) && offset + 4 <= self.data.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+impl MemoryRegion for RamRegion {
+    fn read32(&self, address: u32) -> Option<u32> {
+        let offset = self.offset(address)?;
+        Some(u32::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+            self.data[offset + 3],
+        ]))
+    }
+
+    fn write32(&mut self, address: u32, val: u32) -> bool {
+        match self.offset(address) {
+            Some(offset) => {
+                self.data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A read-only view of a region several buses share, like one RAM dump behind every player
+/// in a playlist. Writes go nowhere, so whatever gets written, the capture buffers, needs a
+/// region of its own on each bus.
+#[derive(Clone)]
+pub struct SharedRegion {
+    region: Arc<dyn MemoryRegion + Sync>,
+}
+
+impl SharedRegion {
+    pub fn new(region: Arc<dyn MemoryRegion + Sync>) -> SharedRegion {
+        SharedRegion { region }
+    }
+}
+
+impl MemoryRegion for SharedRegion {
+    fn read32(&self, address: u32) -> Option<u32> {
+        self.region.read32(address)
+    }
+
+    fn write32(&mut self, _address: u32, _val: u32) -> bool {
+        false
+    }
+}
+
+/// Where samples that aren't in the game's RAM get loaded, in VRAM the game never plays
+/// samples from
+pub const SAMPLE_SPACE_ADDRESS: u32 = 0x06000000;
+pub const SAMPLE_SPACE_SIZE: usize = 0x1000000;
+
+/// `SAMPLE_SPACE_SIZE` bytes at `SAMPLE_SPACE_ADDRESS`, only as much as has been written is
+/// kept and the rest reads as 0
+#[derive(Default)]
+pub struct SampleSpace {
+    data: Vec<u8>,
+}
+
+impl SampleSpace {
+    pub fn new() -> SampleSpace {
+        SampleSpace::default()
+    }
+
+    fn offset(address: u32) -> Option<usize> {
+        let offset = address.wrapping_sub(SAMPLE_SPACE_ADDRESS) as usize;
+        if offset + 4 <= SAMPLE_SPACE_SIZE {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+impl MemoryRegion for SampleSpace {
+    fn read32(&self, address: u32) -> Option<u32> {
+        let offset = SampleSpace::offset(address)?;
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.data.get(offset + i).copied().unwrap_or(0);
+        }
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    fn write32(&mut self, address: u32, val: u32) -> bool {
+        match SampleSpace::offset(address) {
+            Some(offset) => {
+                if self.data.len() < offset + 4 {
+                    self.data.resize(offset + 4, 0);
+                }
+                self.data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl MemoryRegion for SampleBank {
+    fn read32(&self, address: u32) -> Option<u32> {
+        if is_main_ram(address) && self.contains(address as usize) {
+            Some(self.read32(address as usize))
+        } else {
+            None
+        }
+    }
+
+    fn write32(&mut self, address: u32, val: u32) -> bool {
+        if is_main_ram(address) && self.contains(address as usize) {
+            self.write32(address as usize, val);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spu::Nds;
+
+    #[test]
+    fn test_bus_regions() {
+        assert_eq!(
+            Nds::new(vec![0; MAIN_RAM_SIZE + 4]).err(),
+            Some(BusError::RamSize {
+                size: MAIN_RAM_SIZE + 4
+            })
+        );
+
+        // A short dump is mapped as far as it goes
+        let mut nds = Nds::new(vec![0x11; 1024]).unwrap();
+        assert_eq!(nds.read32(0x020003FC), Ok(0x11111111));
+        assert_eq!(nds.read32(0x02400000), Ok(0x11111111));
+        assert_eq!(
+            nds.read32(0x02000400),
+            Err(BusError::Unmapped {
+                address: 0x02000400
+            })
+        );
+        assert_eq!(
+            nds.write32(0x02000400, 0),
+            Err(BusError::Unmapped {
+                address: 0x02000400
+            })
+        );
+
+        let mut nds = Nds::empty();
+        nds.map_region(Box::new(RamRegion::new(0x02001000, 16)));
+
+        assert_eq!(nds.write32(0x02001004, 0xDEADBEEF), Ok(()));
+        assert_eq!(nds.read32(0x02001004), Ok(0xDEADBEEF));
+        // Main RAM mirrors
+        assert_eq!(nds.read32(0x02401004), Ok(0xDEADBEEF));
+        assert_eq!(
+            nds.read32(0x02001010),
+            Err(BusError::Unmapped {
+                address: 0x02001010
+            })
+        );
+        assert_eq!(
+            nds.read32(0x04000000),
+            Err(BusError::Unmapped {
+                address: 0x04000000
+            })
+        );
+    }
+}
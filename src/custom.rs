@@ -0,0 +1,281 @@
+use std::io;
+
+use crate::{
+    audio::*,
+    spu::SampleFormat,
+    wav::{decode_wav, SampleLoop},
+};
+
+pub use crate::spu::{encode_adpcm, EncodedAdpcm, ADPCM_HEADER_SIZE};
+
+/// What the hardware's loop start register can point at, in bytes
+const MAX_LOOP_POS: usize = 0xFFFF * 4;
+/// What the hardware's length register can cover, in bytes
+const MAX_LENGTH: usize = 0x3FFFFF * 4;
+
+/// An envelope for a custom instrument, with times in samples at `SAMPLE_RATE`. The SPU only
+/// changes a note's volume every `EVENT_TIMING` samples, so they're rounded down to that.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CustomAdsr {
+    /// The loudest the note gets, up to 2047
+    pub volume: u32,
+    /// How long the note takes to rise from silence to `volume`
+    pub attack: usize,
+    /// How long it then takes to fall to `sustain`
+    pub decay: usize,
+    pub sustain: u32,
+    /// How long `sustain` is held before the note releases by itself
+    pub sustain_duration: usize,
+    /// How long the note takes to fade out once released
+    pub release: usize,
+}
+
+impl CustomAdsr {
+    fn adsr(&self, mut sample: Sample, format: SampleFormat) -> Adsr {
+        let ticks = |samples: usize| (samples / EVENT_TIMING) as u32;
+        let attack = ticks(self.attack);
+        // Keyed on silent when there's an attack to ramp up
+        let initial_volume = if attack == 0 { self.volume } else { 0 };
+        sample.volume = (initial_volume, initial_volume);
+        let attack = (attack > 0).then(|| InstrumentAttack::Exact {
+            adjustments: (1..=attack)
+                .map(|time| {
+                    let volume = self.volume * time / attack;
+                    InstrumentTimedVolumeAdjustment {
+                        time,
+                        volume: (volume, volume),
+                    }
+                })
+                .collect(),
+        });
+        let decay = ticks(self.decay);
+        // Freed once it's faded to 1%
+        let release = ticks(self.release);
+        let ratio = if release == 0 {
+            0.0
+        } else {
+            0.01_f32.powf(1.0 / release as f32)
+        };
+
+        Adsr {
+            sample: InstrumentSample::Custom(sample, format),
+            attack,
+            decay: (decay > 0).then_some(InstrumentDecay::Linear { duration: decay }),
+            sustain: Some(InstrumentSustain {
+                volume: (self.sustain, self.sustain),
+                duration: ticks(self.sustain_duration),
+            }),
+            release: Some(InstrumentRelease::Geometric { ratio }),
+        }
+    }
+}
+
+/// An instrument made from a sample of its own instead of one in the game's RAM
+#[derive(Debug, Clone)]
+pub struct CustomInstrument {
+    pub name: String,
+    /// Mono
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    /// The MIDI note the sample sounds at when played at `sample_rate`
+    pub root_note: u8,
+    /// Loops while the note is held, None plays the sample once
+    pub sample_loop: Option<SampleLoop>,
+    pub adsr: CustomAdsr,
+    /// How the sample is stored in memory, PCM16 keeps it as is and ADPCM takes a quarter of
+    /// the space
+    pub format: SampleFormat,
+}
+
+impl CustomInstrument {
+    /// Reads the sample from a 16-bit PCM WAV file, mixing it down to mono, along with the
+    /// loop from its `smpl` chunk if it has one
+    pub fn from_wav(
+        name: &str,
+        wav: &[u8],
+        root_note: u8,
+        adsr: CustomAdsr,
+        format: SampleFormat,
+    ) -> io::Result<CustomInstrument> {
+        let wav = decode_wav(wav)?;
+        let channels = wav.channels.max(1) as usize;
+        let samples = wav
+            .samples
+            .chunks(channels)
+            .map(|frame| {
+                (frame.iter().map(|&sample| sample as i32).sum::<i32>() / frame.len() as i32) as i16
+            })
+            .collect();
+
+        Ok(CustomInstrument {
+            name: name.to_string(),
+            samples,
+            sample_rate: wav.sample_rate,
+            root_note,
+            sample_loop: wav.sample_loop,
+            adsr,
+            format,
+        })
+    }
+
+    /// The timer reload for a mio note of 0, which sounds as `BASE_MIDI_NOTE`
+    pub fn base_timer_reload(&self) -> io::Result<u16> {
+        let semitones = BASE_MIDI_NOTE as f64 - self.root_note as f64;
+        let rate = self.sample_rate as f64 * 2_f64.powf(semitones / 12.0);
+        let divider = (SAMPLE_RATE as f64 * 512.0 / rate).round();
+        if !(1.0..=65536.0).contains(&divider) {
+            return Err(invalid_input(format!(
+                "{} Hz at root note {} is out of the SPU's range",
+                self.sample_rate, self.root_note
+            )));
+        }
+        Ok((0x10000 - divider as u32) as u16)
+    }
+
+    /// The sample as it's laid out in memory and how to play it from `src_address`. The
+    /// hardware counts loop points in words, so the start is padded with silence to put the
+    /// loop on one and the loop is trimmed to whole words.
+    pub fn encode(&self, src_address: usize) -> io::Result<(Vec<u8>, Sample)> {
+        let (samples, loop_start) = match self.sample_loop {
+            Some(SampleLoop { start, end }) => {
+                if start > end || end >= self.samples.len() {
+                    return Err(invalid_input(format!(
+                        "loop {}..={} is outside the {} samples",
+                        start,
+                        end,
+                        self.samples.len()
+                    )));
+                }
+                (&self.samples[..=end], Some(start))
+            }
+            None => (&self.samples[..], None),
+        };
+
+        let (data, loop_pos, length) = match self.format {
+            SampleFormat::Adpcm => {
+                let encoded = encode_adpcm(samples, loop_start);
+                (encoded.data, encoded.loop_pos, encoded.length)
+            }
+            format => encode_pcm(samples, loop_start, format),
+        };
+        if loop_start.is_some() && length == 0 {
+            return Err(invalid_input("loop is shorter than a word".to_string()));
+        }
+        if loop_pos > MAX_LOOP_POS || length > MAX_LENGTH {
+            return Err(invalid_input(format!(
+                "{} samples are too long for a channel to play",
+                self.samples.len()
+            )));
+        }
+
+        let sample = Sample {
+            // Set by the envelope
+            volume: (0, 0),
+            base_timer_reload: self.base_timer_reload()?,
+            loop_pos,
+            length,
+            src_address,
+            is_repeating: self.sample_loop.is_some(),
+        };
+        Ok((data, sample))
+    }
+
+    /// Plays `sample` across every note, see `encode`
+    pub fn instrument(&self, sample: Sample) -> Instrument {
+        Instrument {
+            name: self.name.clone(),
+            instructions: InstrumentInstructions::Adsr(self.adsr.adsr(sample, self.format)),
+            pitch_adjustments: Vec::new(),
+        }
+    }
+}
+
+/// Lays `samples` out like `encode_adpcm` does, returning the data, loop position and length
+fn encode_pcm(
+    samples: &[i16],
+    loop_start: Option<usize>,
+    format: SampleFormat,
+) -> (Vec<u8>, usize, usize) {
+    let bytes_per_sample = if format == SampleFormat::Pcm8 { 1 } else { 2 };
+    let per_word = 4 / bytes_per_sample;
+    let padding = loop_start.map_or(0, |start| (per_word - start % per_word) % per_word);
+    let samples = match loop_start {
+        Some(start) => &samples[..start + (samples.len() - start) / per_word * per_word],
+        None => samples,
+    };
+
+    let mut data = vec![0; padding * bytes_per_sample];
+    for &sample in samples {
+        match format {
+            SampleFormat::Pcm8 => data.push((sample >> 8) as u8),
+            _ => data.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+    // One-shot samples end on a word too, padded with silence
+    data.resize(data.len().div_ceil(4) * 4, 0);
+
+    let loop_pos = loop_start.map_or(0, |start| (padding + start) * bytes_per_sample);
+    let length = data.len() - loop_pos;
+    (data, loop_pos, length)
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_sample_layout() {
+        let adsr = CustomAdsr {
+            volume: 1024,
+            attack: 0,
+            decay: 0,
+            sustain: 1024,
+            sustain_duration: 0,
+            release: 0,
+        };
+        let mut custom = CustomInstrument {
+            name: "Saw".to_string(),
+            samples: (0..11).map(|i| i * 1000).collect(),
+            sample_rate: SAMPLE_RATE as u32,
+            root_note: BASE_MIDI_NOTE,
+            sample_loop: Some(SampleLoop { start: 3, end: 9 }),
+            adsr,
+            format: SampleFormat::Pcm16,
+        };
+
+        // At its own rate on the base note, the timer ticks once per output sample
+        assert_eq!(custom.base_timer_reload().unwrap(), 0xFE00);
+        custom.root_note = BASE_MIDI_NOTE + 12;
+        assert_eq!(custom.base_timer_reload().unwrap(), 0xFC00);
+
+        // A sample of silence puts the loop on a word, and the loop keeps 6 of its 7 samples
+        let (data, sample) = custom.encode(0x06000000).unwrap();
+        assert_eq!((sample.loop_pos, sample.length), (8, 12));
+        assert_eq!(data.len(), 20);
+        assert_eq!(&data[..4], &[0, 0, 0, 0]);
+        assert_eq!(i16::from_le_bytes([data[8], data[9]]), 3000);
+        assert_eq!(i16::from_le_bytes([data[18], data[19]]), 8000);
+        assert!(sample.is_repeating);
+
+        custom.format = SampleFormat::Pcm8;
+        custom.sample_loop = None;
+        let (data, sample) = custom.encode(0x06000000).unwrap();
+        assert_eq!((sample.loop_pos, sample.length), (0, 12));
+        assert_eq!(data[10], (10000 >> 8) as u8);
+
+        // ADPCM words hold eight samples after the header
+        custom.format = SampleFormat::Adpcm;
+        let (data, sample) = custom.encode(0x06000000).unwrap();
+        assert_eq!((sample.loop_pos, sample.length), (4, 8));
+        assert_eq!(data.len(), 12);
+        custom.sample_loop = Some(SampleLoop { start: 3, end: 9 });
+        assert!(custom.encode(0x06000000).is_err());
+
+        custom.sample_rate = 100;
+        assert!(custom.encode(0x06000000).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use nanoserde::{DeBin, SerBin};
+
+use crate::{
+    player::{CAPTURE_BUFFER_ADDRESSES, CAPTURE_BUFFER_SIZE},
+    spu::Spu,
+};
+
+const SOUND_CONTROL_HIGH: u32 = 0x04000501;
+const MASTER_VOLUME: u32 = 0x04000500;
+const CAPTURE_CONTROL: u32 = 0x04000508;
+const CAPTURE_DEST_ADDRESSES: [u32; 2] = [0x04000510, 0x04000518];
+const CAPTURE_LENGTHS: [u32; 2] = [0x04000514, 0x0400051C];
+/// Channels 1 and 3 play back what capture units 0 and 1 recorded
+const ECHO_CHANNELS: [u32; 2] = [0x04000410, 0x04000430];
+
+/// Enable, with the output coming straight from the mixer and channels 1/3 mixed back in
+const MIXER_OUTPUT: u8 = 0x80;
+/// Enable, with the left output from channel 1, the right from channel 3,
+/// and neither of them going to the mixer
+const CAPTURE_OUTPUT: u8 = 0xB9;
+/// Key on, PCM16, looping
+const ECHO_CHANNEL_START: u8 = 0xA8;
+/// PCM16 and looping, with volume shift 0
+const ECHO_CHANNEL_CONTROL: u32 = 0x28000000;
+/// Start capturing the mixer as PCM16, looping
+const CAPTURE_START: u8 = 0x80;
+
+/// Bytes per captured PCM16 sample
+const SAMPLE_SIZE: usize = 2;
+/// Each capture timer tick is this many timer steps of an SPU sample
+const TIMER_STEP: u32 = 512;
+
+/// The feedback loop through the capture units the game sets up. Channels 1 and 3 play
+/// back the left and right mix a little later, and when enabled they're also fed back into
+/// the mixer, so each echo gets captured again.
+///
+/// Disabled, the output still goes through the capture buffers like it did on the DS, only
+/// without any feedback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeBin, SerBin)]
+pub struct Echo {
+    pub enabled: bool,
+    /// Samples at `SAMPLE_RATE` between a sound and its echo. Past `max_buffered_delay()`
+    /// the capture units record at a lower rate, which makes the echoes duller.
+    pub delay: usize,
+    /// Volume of channels 1/3, 0..=127. Each echo is this much quieter than the last.
+    pub feedback: u8,
+    /// 0 keeps the left echo on the left, 127 bounces each echo to the other side
+    pub pan: u8,
+}
+
+impl Default for Echo {
+    /// What the game sets up
+    fn default() -> Self {
+        Echo {
+            enabled: false,
+            delay: Echo::max_buffered_delay(),
+            feedback: 127,
+            pan: 0,
+        }
+    }
+}
+
+impl Echo {
+    pub const MIN_DELAY: usize = 16;
+    pub const MAX_DELAY: usize = (0x10000 / TIMER_STEP as usize) * Echo::max_buffered_delay();
+
+    /// The longest delay the capture buffers hold at full rate
+    pub const fn max_buffered_delay() -> usize {
+        CAPTURE_BUFFER_SIZE / SAMPLE_SIZE
+    }
+
+    /// (samples in each capture buffer, capture and echo channel timer reload)
+    fn buffer(&self) -> (usize, u16) {
+        let delay = self.delay.clamp(Echo::MIN_DELAY, Echo::MAX_DELAY);
+        // Buffer lengths are in words
+        let buffer_samples = delay.min(Echo::max_buffered_delay()) & !1;
+        let period = (TIMER_STEP as usize * delay + buffer_samples / 2) / buffer_samples;
+        (buffer_samples, (0x10000 - period.min(0x10000)) as u16)
+    }
+
+    /// Programs the capture units and channels 1/3 from scratch, restarting the buffers
+    pub fn setup(&self, spu: &mut Spu) {
+        let (buffer_samples, timer_reload) = self.buffer();
+        let length_words = (buffer_samples * SAMPLE_SIZE / 4) as u32;
+
+        for (i, (&channel, &address)) in ECHO_CHANNELS
+            .iter()
+            .zip(&CAPTURE_BUFFER_ADDRESSES)
+            .enumerate()
+        {
+            // Key off, so keying on again restarts playback from the buffer's start
+            spu.write8(channel + 3, 0);
+            spu.write8(CAPTURE_CONTROL + i as u32, 0);
+
+            spu.write32(channel, self.channel_control(i));
+            spu.write16(channel + 0x8, timer_reload);
+            spu.write16(channel + 0xA, 0);
+            spu.write32(channel + 0xC, length_words);
+            spu.write32(channel + 0x4, address as u32);
+
+            spu.write32(CAPTURE_DEST_ADDRESSES[i], address as u32);
+            spu.write16(CAPTURE_LENGTHS[i], length_words as u16);
+        }
+
+        spu.write8(SOUND_CONTROL_HIGH, self.sound_control());
+        for &channel in &ECHO_CHANNELS {
+            spu.write8(channel + 3, ECHO_CHANNEL_START);
+        }
+        spu.write16(
+            CAPTURE_CONTROL,
+            u16::from_le_bytes([CAPTURE_START, CAPTURE_START]),
+        );
+        spu.write8(MASTER_VOLUME, 127);
+    }
+
+    /// Applies everything but the delay without restarting the buffers
+    pub fn update_mix(&self, spu: &mut Spu) {
+        spu.write8(SOUND_CONTROL_HIGH, self.sound_control());
+        for (i, &channel) in ECHO_CHANNELS.iter().enumerate() {
+            let control = self.channel_control(i).to_le_bytes();
+            spu.write8(channel, control[0]);
+            spu.write8(channel + 2, control[2]);
+        }
+    }
+
+    fn sound_control(&self) -> u8 {
+        if self.enabled {
+            MIXER_OUTPUT
+        } else {
+            CAPTURE_OUTPUT
+        }
+    }
+
+    /// Channel 1 is `echo` 0 and channel 3 is `echo` 1
+    fn channel_control(&self, echo: usize) -> u32 {
+        let (volume, pan) = if self.enabled {
+            (self.feedback.min(127), self.pan.min(127))
+        } else {
+            (127, 0)
+        };
+        let pan = if echo == 0 { pan } else { 127 - pan };
+        ECHO_CHANNEL_CONTROL | (pan as u32) << 16 | volume as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audio::SAMPLE_RATE, fixtures, player::Player};
+
+    #[test]
+    fn test_echo_repeats() {
+        let ram = fixtures::zeroed_ram();
+        let mio = fixtures::song(40, &[(0, 12)]);
+
+        let render = |echo: Echo| {
+            let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+            player.set_echo(echo);
+            let mut data = vec![0.0; SAMPLE_RATE / 2 * 2];
+            player.render_native(&mut data);
+            data
+        };
+        let energy = |data: &[f32]| data.iter().map(|sample| sample * sample).sum::<f32>();
+
+        let mut expected = vec![0.0; SAMPLE_RATE / 2 * 2];
+        Player::new(&mio, &ram, 1.0)
+            .unwrap()
+            .render_native(&mut expected);
+        assert_eq!(render(Echo::default()), expected);
+
+        let echo = Echo {
+            enabled: true,
+            delay: 4000,
+            feedback: 100,
+            pan: 0,
+        };
+        assert_eq!(echo.buffer(), (1024, (0x10000 - 2000) as u16));
+        let echoed = render(echo);
+        let dry = render(Echo {
+            feedback: 0,
+            ..echo
+        });
+        assert!(energy(&echoed) > energy(&dry) * 1.1);
+    }
+}
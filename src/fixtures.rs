@@ -0,0 +1,81 @@
+//! Songs and RAM built by hand for tests, in the layout `Record::from_mio` reads
+
+use crate::{audio::TRACK_LENGTH, bus::MAIN_RAM_SIZE};
+
+const TEMPO_OFFSET: usize = 0x101;
+const SEGMENT_COUNT_OFFSET: usize = 0x102;
+const SEGMENT_LENGTH: usize = 0x114;
+const SONG_OFFSET: usize = 0x107;
+const DRUM_OFFSET: usize = 0x187;
+const VOLUME_OFFSET: usize = 0x207;
+const PAN_OFFSET: usize = 0x20C;
+const INSTRUMENT_OFFSET: usize = 0x211;
+/// The drum lanes take the place of a fifth track
+pub const DRUM_TRACK: usize = 4;
+
+/// One segment at 120 BPM without swing, no notes or drums, and every track at full volume
+/// and centered
+pub fn mio() -> Vec<u8> {
+    let mut mio = vec![255; 8192];
+    mio[0x100] = 0;
+    mio[TEMPO_OFFSET] = 6;
+    mio[SEGMENT_COUNT_OFFSET] = 1;
+    for track in 0..=DRUM_TRACK {
+        mio[VOLUME_OFFSET + track] = 4;
+        mio[PAN_OFFSET + track] = 2;
+    }
+    mio
+}
+
+/// `notes` as (time, note) on the first track, played with `instrument`
+pub fn song(instrument: u8, notes: &[(usize, u8)]) -> Vec<u8> {
+    let mut mio = mio();
+    set_instrument(&mut mio, 0, instrument);
+    for &(time, note) in notes {
+        set_note(&mut mio, 0, time, note);
+    }
+    mio
+}
+
+/// Adds segments after the first with its volumes, pans and instruments but no notes or
+/// drums, each one `TRACK_LENGTH` steps long
+pub fn set_segment_count(mio: &mut [u8], count: usize) {
+    mio[SEGMENT_COUNT_OFFSET] = count as u8;
+    let settings = VOLUME_OFFSET..INSTRUMENT_OFFSET + DRUM_TRACK + 1;
+    for segment in 1..count {
+        mio.copy_within(settings.clone(), settings.start + segment * SEGMENT_LENGTH);
+    }
+}
+
+/// `tempo` counts in steps of 10 BPM up from 60
+pub fn set_tempo(mio: &mut [u8], tempo: u8) {
+    mio[TEMPO_OFFSET] = tempo;
+}
+
+/// The drum track's instrument is its rhythm section
+pub fn set_instrument(mio: &mut [u8], track: usize, instrument: u8) {
+    mio[INSTRUMENT_OFFSET + track] = instrument;
+}
+
+/// 0 is hard left, 2 centered and 4 hard right
+pub fn set_pan(mio: &mut [u8], track: usize, pan: u8) {
+    mio[PAN_OFFSET + track] = pan;
+}
+
+pub fn set_note(mio: &mut [u8], track: usize, time: usize, note: u8) {
+    mio[SONG_OFFSET + track * TRACK_LENGTH + time] = note;
+}
+
+pub fn set_drum(mio: &mut [u8], lane: usize, time: usize, drum: u8) {
+    mio[DRUM_OFFSET + lane * TRACK_LENGTH + time] = drum;
+}
+
+/// Enough for PSG instruments, which don't read samples
+pub fn zeroed_ram() -> Vec<u8> {
+    vec![0; MAIN_RAM_SIZE]
+}
+
+/// A pattern for sample instruments to play, without silent stretches
+pub fn patterned_ram() -> Vec<u8> {
+    (0..MAIN_RAM_SIZE).map(|i| (i * 31 % 251) as u8).collect()
+}
@@ -0,0 +1,284 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::SAMPLE_RATE,
+    player::Player,
+    stems::{render_stems, stem_name, Stems, STEM_COUNT},
+    wav::{decode_wav, to_pcm16},
+};
+
+/// Renders `frame_count` frames of a song the way golden outputs are made
+pub fn render_golden(mio_data: &[u8], ram: &[u8], frame_count: usize) -> io::Result<Stems> {
+    let mut player = Player::new(mio_data, ram, 1.0)?;
+    Ok(render_stems(&mut player, frame_count))
+}
+
+/// Reads back what `Stems::write_wav_files` wrote to `directory`
+pub fn read_golden(directory: &Path) -> io::Result<Stems> {
+    let read_stem = |name: &str| -> io::Result<(u32, Vec<f32>)> {
+        let wav = decode_wav(&std::fs::read(directory.join(format!("{}.wav", name)))?)?;
+        if wav.channels != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}.wav isn't stereo", name),
+            ));
+        }
+        let samples = wav
+            .samples
+            .iter()
+            .map(|&sample| sample as f32 / i16::MAX as f32)
+            .collect();
+        Ok((wav.sample_rate, samples))
+    };
+
+    let (sample_rate, mix) = read_stem("mix")?;
+    let mut tracks: [Vec<f32>; STEM_COUNT] = Default::default();
+    for (track, samples) in tracks.iter_mut().enumerate() {
+        *samples = read_stem(&stem_name(track))?.1;
+    }
+
+    Ok(Stems {
+        sample_rate,
+        mix,
+        tracks,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub frame: usize,
+    /// 0 for left, 1 for right
+    pub channel: usize,
+    pub expected: i16,
+    pub actual: i16,
+}
+
+/// RMS level in dBFS
+#[derive(Debug, Clone, PartialEq)]
+pub struct StemEnergy {
+    pub name: String,
+    pub expected: f32,
+    pub actual: f32,
+}
+
+/// How a render differs from its golden output, in 16-bit sample units
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldenDiff {
+    pub name: String,
+    pub tolerance: u16,
+    pub expected_frames: usize,
+    pub actual_frames: usize,
+    /// The first mix sample further than `tolerance` from the golden one
+    pub first_divergence: Option<Divergence>,
+    pub divergent_samples: usize,
+    pub max_difference: u16,
+    /// The mix followed by each track
+    pub energy: Vec<StemEnergy>,
+}
+
+impl GoldenDiff {
+    pub fn passed(&self) -> bool {
+        self.first_divergence.is_none() && self.expected_frames == self.actual_frames
+    }
+}
+
+impl fmt::Display for GoldenDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.passed() {
+            return writeln!(
+                f,
+                "{}: ok (max difference {})",
+                self.name, self.max_difference
+            );
+        }
+
+        writeln!(f, "{}: FAILED", self.name)?;
+        if self.expected_frames != self.actual_frames {
+            writeln!(
+                f,
+                "  length: expected {} frames, rendered {}",
+                self.expected_frames, self.actual_frames
+            )?;
+        }
+        if let Some(divergence) = &self.first_divergence {
+            writeln!(
+                f,
+                "  first divergence at frame {} ({:.3}s) {}: expected {}, rendered {}",
+                divergence.frame,
+                divergence.frame as f32 / SAMPLE_RATE as f32,
+                ["left", "right"][divergence.channel],
+                divergence.expected,
+                divergence.actual
+            )?;
+        }
+        writeln!(
+            f,
+            "  {} samples off by more than {}, at most by {}",
+            self.divergent_samples, self.tolerance, self.max_difference
+        )?;
+        writeln!(f, "  energy (dBFS)     expected   rendered")?;
+        for energy in &self.energy {
+            writeln!(
+                f,
+                "  {:<16} {:>9.2} {:>10.2}{}",
+                energy.name,
+                energy.expected,
+                energy.actual,
+                if (energy.expected - energy.actual).abs() > 0.1 {
+                    "  <"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn energy(samples: &[f32]) -> f32 {
+    let mean_square = samples
+        .iter()
+        .map(|&sample| sample as f64 * sample as f64)
+        .sum::<f64>()
+        / samples.len().max(1) as f64;
+    // Silence bottoms out instead of going to -inf
+    (10.0 * mean_square.max(1e-12).log10()) as f32
+}
+
+/// Compares the mixes sample by sample, allowing `tolerance` 16-bit steps of difference
+pub fn compare_stems(name: &str, expected: &Stems, actual: &Stems, tolerance: u16) -> GoldenDiff {
+    let expected_mix = to_pcm16(&expected.mix);
+    let actual_mix = to_pcm16(&actual.mix);
+
+    let mut first_divergence = None;
+    let mut divergent_samples = 0;
+    let mut max_difference = 0;
+    for (i, (&expected, &actual)) in expected_mix.iter().zip(&actual_mix).enumerate() {
+        let difference = (expected as i32 - actual as i32).unsigned_abs() as u16;
+        max_difference = max_difference.max(difference);
+        if difference > tolerance {
+            divergent_samples += 1;
+            first_divergence.get_or_insert(Divergence {
+                frame: i / 2,
+                channel: i % 2,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    let mut energy = vec![StemEnergy {
+        name: "mix".to_string(),
+        expected: self::energy(&expected.mix),
+        actual: self::energy(&actual.mix),
+    }];
+    for (track, (expected, actual)) in expected.tracks.iter().zip(&actual.tracks).enumerate() {
+        energy.push(StemEnergy {
+            name: stem_name(track),
+            expected: self::energy(expected),
+            actual: self::energy(actual),
+        });
+    }
+
+    GoldenDiff {
+        name: name.to_string(),
+        tolerance,
+        expected_frames: expected.mix.len() / 2,
+        actual_frames: actual.mix.len() / 2,
+        first_divergence,
+        divergent_samples,
+        max_difference,
+        energy,
+    }
+}
+
+/// A directory of `.mio` songs with golden outputs kept in `goldens/<song name>/`
+pub struct GoldenCorpus {
+    pub songs: PathBuf,
+    pub goldens: PathBuf,
+    pub frame_count: usize,
+    pub tolerance: u16,
+}
+
+impl GoldenCorpus {
+    /// Songs sorted by name
+    pub fn song_names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.songs)? {
+            let path = entry?.path();
+            if path.extension() == Some("mio".as_ref()) {
+                if let Some(name) = path.file_stem() {
+                    names.push(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn render(&self, name: &str, ram: &[u8]) -> io::Result<Stems> {
+        let mio_data = std::fs::read(self.songs.join(format!("{}.mio", name)))?;
+        render_golden(&mio_data, ram, self.frame_count)
+    }
+
+    /// Renders every song and compares it against its golden output
+    pub fn check(&self, ram: &[u8]) -> io::Result<Vec<GoldenDiff>> {
+        self.song_names()?
+            .iter()
+            .map(|name| {
+                let expected = read_golden(&self.goldens.join(name))?;
+                let actual = self.render(name, ram)?;
+                Ok(compare_stems(name, &expected, &actual, self.tolerance))
+            })
+            .collect()
+    }
+
+    /// Replaces the golden outputs with fresh renders, for when a change in output is intended
+    pub fn bless(&self, ram: &[u8]) -> io::Result<()> {
+        for name in self.song_names()? {
+            let directory = self.goldens.join(&name);
+            std::fs::create_dir_all(&directory)?;
+            self.render(&name, ram)?.write_wav_files(&directory)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_golden_round_trip() {
+        let ram = fixtures::zeroed_ram();
+        let mio = fixtures::song(40, &[(0, 12)]);
+
+        let directory = std::env::temp_dir().join(format!("wahdio-golden-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let rendered = render_golden(&mio, &ram, 8000).unwrap();
+        rendered.write_wav_files(&directory).unwrap();
+        let golden = read_golden(&directory).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let diff = compare_stems("ding", &golden, &rendered, 0);
+        assert!(diff.passed(), "{}", diff);
+        assert!(diff.energy[1].actual > diff.energy[2].actual);
+
+        let mut changed = render_golden(&mio, &ram, 8000).unwrap();
+        for sample in &mut changed.mix[6001..] {
+            *sample *= 0.5;
+        }
+        let diff = compare_stems("ding", &golden, &changed, 1);
+        let divergence = diff.first_divergence.unwrap();
+        assert!(!diff.passed());
+        assert!(divergence.frame >= 3000);
+        assert_eq!(
+            divergence.expected,
+            to_pcm16(&golden.mix[divergence.frame * 2 + divergence.channel..][..1])[0]
+        );
+    }
+}
@@ -1,27 +1,60 @@
 mod utils;
 
-use record::Record;
-use std::sync::{Arc, Mutex};
 use tinyaudio::run_output_device;
 use tinyaudio::BaseAudioOutputDevice;
 use tinyaudio::OutputDeviceParameters;
 
+use std::sync::{Arc, Mutex};
+
 use wasm_bindgen::prelude::*;
 
 mod spu;
 
-use spu::{AudioBitDepth, Nds, Spu};
+pub mod bus;
 
 mod drums;
 mod ins;
 mod record;
 mod audio;
+#[cfg(test)]
+mod fixtures;
+pub mod accuracy;
+pub mod custom;
+pub mod echo;
+pub mod golden;
+pub mod log;
+pub mod mixer;
+pub mod player;
+pub mod playlist;
+pub mod renderer;
+pub mod resample;
+pub mod sample_bank;
+pub mod sample_export;
+pub mod sf2;
+pub mod stems;
+pub mod trace;
+pub mod wav;
+
+pub use spu::{AudioBitDepth, AudioInterpolation};
 
-use drums::drum_instructions;
-use ins::instrument_instructions;
 use audio::*;
+use custom::{CustomAdsr, CustomInstrument};
+use echo::Echo;
+use player::Player;
+use playlist::Playlist;
+use renderer::{Layout, OutputBuffer, RenderStats, Renderer};
+use sample_bank::SampleBank;
+use sample_export::SampleFormat;
 
 static mut DEVICE: Option<Box<dyn BaseAudioOutputDevice>> = None;
+/// The player behind `DEVICE`, for changing playback while it runs
+static PLAYER: Mutex<Option<Arc<Mutex<Player>>>> = Mutex::new(None);
+/// The playlist behind `DEVICE` when `play_playlist` started it
+static PLAYLIST: Mutex<Option<Arc<Mutex<Playlist>>>> = Mutex::new(None);
+/// Used by every `play_music` from the next one on
+static OUTPUT_BUFFER: Mutex<OutputBuffer> = Mutex::new(OutputBuffer::Frames(
+    renderer::DEFAULT_BUFFER_SIZE,
+));
 
 #[wasm_bindgen]
 extern "C" {
@@ -38,134 +71,447 @@ pub fn stop_music() {
     unsafe {
         DEVICE = None;
     }
+    *PLAYER.lock().unwrap() = None;
+    *PLAYLIST.lock().unwrap() = None;
+}
+
+/// Runs `f` on the player that's currently playing, if any, which for a playlist is the
+/// current song's
+fn with_player<R>(f: impl FnOnce(&mut Player) -> R) -> Option<R> {
+    if let Some(player) = PLAYER.lock().unwrap().as_ref() {
+        return Some(f(&mut player.lock().unwrap()));
+    }
+    with_playlist(|playlist| playlist.player().map(f)).flatten()
 }
 
+fn with_playlist<R>(f: impl FnOnce(&mut Playlist) -> R) -> Option<R> {
+    PLAYLIST
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|playlist| f(&mut playlist.lock().unwrap()))
+}
+
+/// Frames the audio device asks for at a time, from the next `play_music` on
 #[wasm_bindgen]
-pub fn play_music(mio_data: &[u8], ram: &[u8], my_volume: f32) {
-    utils::set_panic_hook();
-    let multiplier = 8;
-    let params = OutputDeviceParameters {
-        channels_count: 2,
-        sample_rate: SAMPLE_RATE,
-        channel_sample_count: 1024 * multiplier,
+pub fn set_output_buffer_size(frames: usize) {
+    *OUTPUT_BUFFER.lock().unwrap() = OutputBuffer::Frames(frames);
+}
+
+/// Like `set_output_buffer_size`, in milliseconds at whatever rate the music plays at.
+/// Lower is more responsive, but keep an eye on `output_underruns` while tuning it.
+#[wasm_bindgen]
+pub fn set_output_latency(latency_ms: f32) {
+    *OUTPUT_BUFFER.lock().unwrap() = OutputBuffer::Latency(latency_ms);
+}
+
+/// The stats of whatever `DEVICE` or a renderer's host is playing, kept by the playlist
+/// rather than its current song's player when it's a playlist
+fn render_stats() -> RenderStats {
+    if let Some(player) = PLAYER.lock().unwrap().as_ref() {
+        return player.lock().unwrap().render_stats;
+    }
+    with_playlist(|playlist| playlist.render_stats).unwrap_or_default()
+}
+
+/// Output blocks that took longer to render than to play since the music started,
+/// each one likely an audible glitch
+#[wasm_bindgen]
+pub fn output_underruns() -> u32 {
+    render_stats().underruns as u32
+}
+
+/// The slowest block's render time over its duration, past 1.0 is an underrun
+#[wasm_bindgen]
+pub fn output_worst_load() -> f64 {
+    render_stats().worst_load
+}
+
+/// 0 logs everything down to trace events and 3 only warnings, anything higher turns
+/// logging to the console off
+#[wasm_bindgen]
+pub fn set_log_level(level: u8) {
+    match log::Level::ALL.get(level as usize) {
+        Some(&level) => {
+            log::set_level(level);
+            log::set_sink(log::Sink::Console);
+        }
+        None => log::set_sink(log::Sink::None),
+    }
+}
+
+/// Categories are "sequencer", "channel_alloc", "envelope", "spu_registers" and "output"
+#[wasm_bindgen]
+pub fn set_log_category(category: &str, enabled: bool) -> Result<(), JsValue> {
+    let category = log::Category::from_name(category)
+        .ok_or_else(|| JsValue::from_str(&format!("unknown log category {}", category)))?;
+    log::set_category(category, enabled);
+    Ok(())
+}
+
+/// Switches the song that's playing between the clean output and the DS's 10-bit output stage
+#[wasm_bindgen]
+pub fn set_ds_output(enabled: bool) {
+    with_player(|player| {
+        player.set_bit_depth(if enabled {
+            AudioBitDepth::_10bit
+        } else {
+            AudioBitDepth::_16bit
+        })
+    });
+}
+
+/// Smoothing between the samples instruments play, "none" like the DS does or "linear",
+/// "cosine" or "cubic"
+#[wasm_bindgen]
+pub fn set_interpolation(interpolation: &str) -> Result<(), JsValue> {
+    let interpolation = AudioInterpolation::from_name(interpolation).ok_or_else(|| {
+        JsValue::from_str(&format!("unknown interpolation {}", interpolation))
+    })?;
+    with_player(|player| player.set_interpolation(interpolation));
+    Ok(())
+}
+
+/// Changes the capture echo of the song that's playing, see `Echo`
+#[wasm_bindgen]
+pub fn set_echo(enabled: bool, delay: usize, feedback: u8, pan: u8) {
+    with_player(|player| {
+        player.set_echo(Echo {
+            enabled,
+            delay,
+            feedback,
+            pan,
+        })
+    });
+}
+
+/// `track` is 0-3 for the melodic tracks and 4-7 for the drum lanes, others are ignored
+#[wasm_bindgen]
+pub fn set_track_volume(track: usize, gain: f32) {
+    if track < stems::STEM_COUNT {
+        with_player(|player| player.set_track_volume(track, gain));
+    }
+}
+
+/// -127 moves everything on `track` hard left, 127 hard right
+#[wasm_bindgen]
+pub fn set_track_pan(track: usize, pan_offset: i32) {
+    if track < stems::STEM_COUNT {
+        with_player(|player| player.set_track_pan(track, pan_offset));
+    }
+}
+
+#[wasm_bindgen]
+pub fn set_master_volume(volume: f32) {
+    with_player(|player| player.set_master_volume(volume));
+}
+
+/// Beats per minute, taking over from the song's own tempo at its next step
+#[wasm_bindgen]
+pub fn set_tempo(tempo: u32) {
+    with_player(|player| player.set_tempo(tempo));
+}
+
+/// None loops the song like it normally would
+#[wasm_bindgen]
+pub fn set_loop_count(count: Option<u32>, fade_out: bool) {
+    with_player(|player| {
+        player.set_loop_count(count.map(|count| count as usize));
+        player.set_fade_out(fade_out);
+    });
+}
+
+#[wasm_bindgen]
+pub fn set_transpose(semitones: i32) {
+    with_player(|player| player.set_transpose(semitones));
+}
+
+/// Plays one note of an instrument over whatever is playing, for `duration_ms` before
+/// its release. False when nothing is playing or the instrument has no such note.
+#[wasm_bindgen]
+pub fn preview_note(instrument_index: usize, note: u8, duration_ms: f32) -> bool {
+    let duration = (duration_ms.max(0.0) / 1000.0 * SAMPLE_RATE as f32) as usize;
+    with_player(|player| player.preview_note(instrument_index, note, duration)).unwrap_or(false)
+}
+
+/// Holds a note on live `track` until `note_off`, over whatever is playing. Live tracks
+/// play one note at a time, so a chord needs a track per note.
+#[wasm_bindgen]
+pub fn note_on(track: usize, instrument_index: usize, note: u8) -> bool {
+    with_player(|player| player.note_on(track, instrument_index, note)).unwrap_or(false)
+}
+
+#[wasm_bindgen]
+pub fn note_off(track: usize, note: u8) {
+    with_player(|player| player.note_off(track, note));
+}
+
+/// Plays one drum over whatever is playing, `volume` 0.0..=1.0 like a song's drum volume
+/// and `pan` -64..=64 like a song's drum pan
+#[wasm_bindgen]
+pub fn trigger_drum(section: usize, drum_id: usize, volume: f32, pan: i32) -> bool {
+    with_player(|player| player.trigger_drum(section, drum_id, volume, pan)).unwrap_or(false)
+}
+
+/// Loads a 16-bit WAV into whatever is playing as a new instrument and returns its index.
+/// It loops if the WAV has a `smpl` loop. `root_note` is the MIDI note the WAV sounds at,
+/// times are in milliseconds and `sustain` is 0.0..=1.0 of the peak.
+#[wasm_bindgen]
+pub fn add_custom_instrument(
+    wav: &[u8],
+    root_note: u8,
+    attack_ms: f32,
+    decay_ms: f32,
+    sustain: f32,
+    hold_ms: f32,
+    release_ms: f32,
+) -> Result<usize, JsValue> {
+    let samples = |ms: f32| (ms.max(0.0) / 1000.0 * SAMPLE_RATE as f32) as usize;
+    // About as loud as the game's instruments peak
+    let volume = 1024;
+    let adsr = CustomAdsr {
+        volume,
+        attack: samples(attack_ms),
+        decay: samples(decay_ms),
+        sustain: (sustain.clamp(0.0, 1.0) * volume as f32) as u32,
+        sustain_duration: samples(hold_ms),
+        release: samples(release_ms),
     };
+    let custom = CustomInstrument::from_wav("Custom", wav, root_note, adsr, SampleFormat::Pcm16)
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    with_player(|player| player.add_custom_instrument(&custom))
+        .ok_or_else(|| JsValue::from_str("nothing is playing"))?
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Plays instrument `with` wherever the song plays instrument `index`
+#[wasm_bindgen]
+pub fn substitute_instrument(index: usize, with: usize) -> bool {
+    with_player(|player| player.substitute_instrument(index, with)).unwrap_or(false)
+}
+
+/// Undoes `substitute_instrument` and drops custom instruments
+#[wasm_bindgen]
+pub fn reset_instruments() {
+    with_player(|player| player.reset_instruments());
+}
+
+/// The instruments and rhythm sections in a RAM dump as the bytes of an SF2 file
+#[wasm_bindgen]
+pub fn export_soundfont(ram: &[u8]) -> Result<Vec<u8>, JsValue> {
+    sf2::export_sf2(ram).map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Every device and resampler needs at least one frame a second
+fn check_sample_rate(sample_rate: u32) -> Result<(), JsValue> {
+    if sample_rate == 0 {
+        return Err(JsValue::from_str("sample rate must be above 0"));
+    }
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn play_music(mio_data: &[u8], ram: &[u8], my_volume: f32) -> Result<(), JsValue> {
+    play_music_at_rate(mio_data, ram, my_volume, SAMPLE_RATE as u32)
+}
+
+/// Plays through a device running at `sample_rate`, e.g. 44100 or 48000 where the
+/// native rate isn't supported
+#[wasm_bindgen]
+pub fn play_music_at_rate(
+    mio_data: &[u8],
+    ram: &[u8],
+    my_volume: f32,
+    sample_rate: u32,
+) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    check_sample_rate(sample_rate)?;
 
     // No longer send in entire ram
     //assert_eq!(ram.len(), 4 * 1024 * 1024);
 
-    let nds = Arc::new(Mutex::new(Nds::new(ram.to_vec())));
-
-    let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
-
-    //1, 0, 0 Set Cnt: Volume: 128, VolumeShift: 4, Pan: 0, KeyOn: 0
-    spu.write32(67109904, 671088767);
-    //1: Set TimerReload: 65024
-    //0: Set Capture TimerReload: 65024
-    spu.write16(67109912, 65024);
-    //1: Set LoopPos: 0
-    spu.write16(67109914, 0);
-    //1: Set Length: 2048
-    spu.write32(67109916, 512);
-    //1: Set SrcAddr: 35253536
-    spu.write32(67109908, 35253536);
-
-    //0: Set Capture Cnt: 0
-    spu.write8(67110152, 0);
-    //0: Set Capture DstAddr: 35253536
-    spu.write32(67110160, 35253536);
-    //0: Set Capture Length: 2048
-    spu.write16(67110164, 512);
-
-    //3, 0, 0 Set Cnt: Volume: 128, VolumeShift: 4, Pan: 128, KeyOn: 0
-    spu.write32(67109936, 679411839);
-    //3: Set TimerReload: 65024
-    //1: Set Capture TimerReload: 65024
-    spu.write16(67109944, 65024);
-    //3: Set LoopPos: 0
-    spu.write16(67109946, 0);
-    //3: Set Length: 2048
-    spu.write32(67109948, 512);
-    //3: Set SrcAddr: 35255584
-    spu.write32(67109940, 35255584);
-
-    //1: Set Capture Cnt: 0
-    spu.write8(67110153, 0);
-    //1: Set Capture DstAddr: 35255584
-    spu.write32(67110168, 35255584);
-    //1: Set Capture Length: 2048
-    spu.write16(67110172, 512);
-    //_?: Set Cnt: 47487
-    spu.write8(67110145, 185);
-
-    //1, 0, 0 Set Cnt: Volume: 128, VolumeShift: 4, Pan: 0, KeyOn: 1
-    spu.write8(67109907, 168);
-
-    //3, 0, 0 Set Cnt: Volume: 128, VolumeShift: 4, Pan: 128, KeyOn: 1
-    spu.write8(67109939, 168);
-
-    //0: Set Capture Cnt: 128
-    //1: Set Capture Cnt: 128
-    spu.write16(67110152, 32896);
-
-    //Set MasterVolume: 127
-    spu.write8(67110144, 127);
-
-    // =============================
-
-    let spu = Arc::new(Mutex::new(spu));
-
-    let instruments = instrument_instructions();
-
-    let rhythm_sections: [RhythmSection; RHYTHM_SECTION_COUNT] = drum_instructions();
-
-    let record = Record::from_mio(&mio_data);
-
-    let mut channel_manager = ChannelManager {
-        channels: [
-            Channel::Open,
-            Channel::Blocked,
-            Channel::Open,
-            Channel::Blocked,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-            Channel::Open,
-        ],
+    let player =
+        Player::new(mio_data, ram, my_volume).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    start_playback(player, sample_rate);
+    Ok(())
+}
+
+/// Compresses the parts of a RAM dump that are played from into a sample bank
+#[wasm_bindgen]
+pub fn build_sample_bank(ram: &[u8]) -> Vec<u8> {
+    SampleBank::from_ram(ram).encode()
+}
+
+/// Like `play_music_at_rate`, with RAM served from a sample bank made by `build_sample_bank`
+#[wasm_bindgen]
+pub fn play_music_from_sample_bank(
+    mio_data: &[u8],
+    sample_bank: &[u8],
+    my_volume: f32,
+    sample_rate: u32,
+) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    check_sample_rate(sample_rate)?;
+    let sample_bank =
+        SampleBank::decode(sample_bank).map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+    start_playback(
+        Player::from_sample_bank(mio_data, sample_bank, my_volume),
+        sample_rate,
+    );
+    Ok(())
+}
+
+/// Starts an empty playlist on `ram`, songs added with `queue_song` play back to back
+/// without the device or RAM being set up again
+#[wasm_bindgen]
+pub fn play_playlist(ram: &[u8], my_volume: f32, sample_rate: u32) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    check_sample_rate(sample_rate)?;
+    let playlist = Playlist::new(ram.to_vec(), my_volume)
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    start_playlist(playlist, sample_rate);
+    Ok(())
+}
+
+/// Like `play_playlist`, with RAM served from a sample bank made by `build_sample_bank`
+#[wasm_bindgen]
+pub fn play_playlist_from_sample_bank(
+    sample_bank: &[u8],
+    my_volume: f32,
+    sample_rate: u32,
+) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    check_sample_rate(sample_rate)?;
+    let sample_bank =
+        SampleBank::decode(sample_bank).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    start_playlist(
+        Playlist::from_sample_bank(sample_bank, my_volume),
+        sample_rate,
+    );
+    Ok(())
+}
+
+/// Adds a song to the end of the playlist, played `loop_count` times
+#[wasm_bindgen]
+pub fn queue_song(mio_data: &[u8], loop_count: u32) {
+    with_playlist(|playlist| playlist.push(mio_data, loop_count as usize));
+}
+
+/// Songs left in the playlist after the one playing
+#[wasm_bindgen]
+pub fn queued_songs() -> usize {
+    with_playlist(|playlist| playlist.queue().len()).unwrap_or(0)
+}
+
+/// Milliseconds each song fades into the next over, 0 for gapless
+#[wasm_bindgen]
+pub fn set_crossfade(crossfade_ms: f32) {
+    let samples = (crossfade_ms.max(0.0) / 1000.0 * SAMPLE_RATE as f32) as usize;
+    with_playlist(|playlist| playlist.set_crossfade(samples));
+}
+
+/// Counts up from 0 each time a playlist song starts, -1 before the first one.
+/// Poll it to tell when the next song begins.
+#[wasm_bindgen]
+pub fn playlist_song_index() -> i32 {
+    with_playlist(|playlist| playlist.song_index())
+        .flatten()
+        .map_or(-1, |index| index as i32)
+}
+
+/// Renders `mio_data` for a host that pulls the output itself, like an AudioWorkletProcessor
+/// calling `render` from `process`. Planar blocks come out as left then right, ready to copy
+/// into Web Audio's output channels. It becomes the song the `set_*` functions change.
+#[wasm_bindgen]
+pub fn create_renderer(
+    mio_data: &[u8],
+    ram: &[u8],
+    my_volume: f32,
+    sample_rate: u32,
+    block_size: usize,
+    planar: bool,
+) -> Result<Renderer, JsValue> {
+    utils::set_panic_hook();
+    check_sample_rate(sample_rate)?;
+    let player =
+        Player::new(mio_data, ram, my_volume).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(start_rendering(player, sample_rate, block_size, planar))
+}
+
+/// Like `create_renderer`, with RAM served from a sample bank made by `build_sample_bank`
+#[wasm_bindgen]
+pub fn create_renderer_from_sample_bank(
+    mio_data: &[u8],
+    sample_bank: &[u8],
+    my_volume: f32,
+    sample_rate: u32,
+    block_size: usize,
+    planar: bool,
+) -> Result<Renderer, JsValue> {
+    utils::set_panic_hook();
+    check_sample_rate(sample_rate)?;
+    let sample_bank =
+        SampleBank::decode(sample_bank).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(start_rendering(
+        Player::from_sample_bank(mio_data, sample_bank, my_volume),
+        sample_rate,
+        block_size,
+        planar,
+    ))
+}
+
+/// Stops whatever was playing and hands playback over to the renderer's host
+fn start_rendering(player: Player, sample_rate: u32, block_size: usize, planar: bool) -> Renderer {
+    stop_music();
+    let layout = if planar {
+        Layout::Planar
+    } else {
+        Layout::Interleaved
+    };
+    let renderer = Renderer::new(player, sample_rate, block_size, layout);
+    *PLAYER.lock().unwrap() = renderer.player();
+    renderer
+}
+
+fn start_playback(player: Player, sample_rate: u32) {
+    let params = OutputDeviceParameters {
+        channels_count: 2,
+        sample_rate: sample_rate as usize,
+        channel_sample_count: OUTPUT_BUFFER.lock().unwrap().frames(sample_rate),
     };
 
-    let mut previous_notes: [Option<u8>; 4] = [None, None, None, None];
+    let mut renderer = Renderer::new(
+        player,
+        sample_rate,
+        params.channel_sample_count,
+        Layout::Interleaved,
+    );
+    *PLAYER.lock().unwrap() = renderer.player();
+    *PLAYLIST.lock().unwrap() = None;
+
+    unsafe {
+        DEVICE = run_output_device(params, move |data| renderer.render(data)).ok();
+    }
+}
 
-    let mut timing = Timing {
-        tiny_tick: 0,
-        phrase_tick: 0,
+fn start_playlist(playlist: Playlist, sample_rate: u32) {
+    stop_music();
+    let params = OutputDeviceParameters {
+        channels_count: 2,
+        sample_rate: sample_rate as usize,
+        channel_sample_count: OUTPUT_BUFFER.lock().unwrap().frames(sample_rate),
     };
 
+    let mut renderer = Renderer::from_playlist(
+        playlist,
+        sample_rate,
+        params.channel_sample_count,
+        Layout::Interleaved,
+    );
+    *PLAYLIST.lock().unwrap() = renderer.playlist();
+
     unsafe {
-        DEVICE = run_output_device(params, {
-            let spu = spu.clone();
-            move |data| {
-                play_stuff(
-                    spu.clone(),
-                    multiplier,
-                    &mut timing,
-                    &mut channel_manager,
-                    &record,
-                    &instruments,
-                    &&rhythm_sections,
-                    &mut previous_notes,
-                    data.chunks_mut(params.channels_count),
-                    my_volume,
-                );
-            }
-        })
-        .ok();
+        DEVICE = run_output_device(params, move |data| renderer.render(data)).ok();
     }
 }
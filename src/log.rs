@@ -0,0 +1,406 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+};
+
+use crate::audio::{Adsr, TimedPitchAdjustment};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Trace, Level::Debug, Level::Info, Level::Warn];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Sequencer,
+    ChannelAlloc,
+    Envelope,
+    SpuRegisters,
+    Output,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Sequencer,
+        Category::ChannelAlloc,
+        Category::Envelope,
+        Category::SpuRegisters,
+        Category::Output,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Sequencer => "sequencer",
+            Category::ChannelAlloc => "channel_alloc",
+            Category::Envelope => "envelope",
+            Category::SpuRegisters => "spu_registers",
+            Category::Output => "output",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Category> {
+        Category::ALL
+            .iter()
+            .cloned()
+            .find(|category| category.name() == name)
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A song was loaded
+    Tempo {
+        tempo: u32,
+        swing: bool,
+        note_rate: usize,
+    },
+    /// A playlist moved on to its `index`th song
+    SongStarted {
+        index: usize,
+    },
+    /// A note's pitch, `offset` semitones away from `original`
+    TimerReload {
+        original: u16,
+        offset: i32,
+        timer_reload: u16,
+    },
+
+    ChannelAllocated {
+        channel: u8,
+        track: u8,
+    },
+    /// The track moved on, the channel plays out its release
+    ChannelReleased {
+        channel: u8,
+        track: u8,
+    },
+    /// The channel went silent and can be reused
+    ChannelFreed {
+        channel: u8,
+    },
+    NoChannelAvailable {
+        track: u8,
+    },
+
+    /// A timed instrument moving on to its next ADSR
+    NextAdsr {
+        track: u8,
+        channel: u8,
+        adsr: Adsr,
+    },
+    PitchAdjustments {
+        adjustments: Vec<TimedPitchAdjustment>,
+    },
+
+    KeyOn {
+        channel: u8,
+        volume: u8,
+        volume_shift: u8,
+        pan: u8,
+    },
+    SoundControl {
+        control: u16,
+    },
+    UnsupportedCaptureMode {
+        unit: u8,
+        control: u8,
+    },
+    /// Sound DMA can't read from the ARM7 BIOS, zeroes are played instead
+    BiosRead {
+        channel: u8,
+        address: usize,
+    },
+    ChannelPan {
+        channel: u8,
+        pan: u8,
+    },
+    ChannelPlay {
+        channel: u8,
+        control: u32,
+    },
+    ChannelPlayPsg {
+        channel: u8,
+        control: u32,
+        table_index: u8,
+    },
+
+    /// Rendering a block took longer than playing it
+    Underrun {
+        render_ms: f32,
+        buffer_ms: f32,
+    },
+}
+
+impl Event {
+    pub fn category(&self) -> Category {
+        match self {
+            Event::Tempo { .. } | Event::SongStarted { .. } | Event::TimerReload { .. } => {
+                Category::Sequencer
+            }
+            Event::ChannelAllocated { .. }
+            | Event::ChannelReleased { .. }
+            | Event::ChannelFreed { .. }
+            | Event::NoChannelAvailable { .. } => Category::ChannelAlloc,
+            Event::NextAdsr { .. } | Event::PitchAdjustments { .. } => Category::Envelope,
+            Event::KeyOn { .. }
+            | Event::SoundControl { .. }
+            | Event::UnsupportedCaptureMode { .. }
+            | Event::BiosRead { .. }
+            | Event::ChannelPan { .. }
+            | Event::ChannelPlay { .. }
+            | Event::ChannelPlayPsg { .. } => Category::SpuRegisters,
+            Event::Underrun { .. } => Category::Output,
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        match self {
+            Event::NoChannelAvailable { .. }
+            | Event::UnsupportedCaptureMode { .. }
+            | Event::BiosRead { .. }
+            | Event::Underrun { .. } => Level::Warn,
+            Event::Tempo { .. } | Event::SongStarted { .. } => Level::Info,
+            Event::ChannelAllocated { .. }
+            | Event::ChannelReleased { .. }
+            | Event::ChannelFreed { .. }
+            | Event::NextAdsr { .. }
+            | Event::KeyOn { .. }
+            | Event::SoundControl { .. }
+            | Event::ChannelPlay { .. }
+            | Event::ChannelPlayPsg { .. } => Level::Debug,
+            Event::TimerReload { .. }
+            | Event::PitchAdjustments { .. }
+            | Event::ChannelPan { .. } => Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Tempo {
+                tempo,
+                swing,
+                note_rate,
+            } => write!(
+                f,
+                "tempo {}{}, {} samples per note",
+                tempo,
+                if *swing { " with swing" } else { "" },
+                note_rate
+            ),
+            Event::SongStarted { index } => write!(f, "song {} started", index),
+            Event::TimerReload {
+                original,
+                offset,
+                timer_reload,
+            } => write!(f, "{} {:+} semitones: {}", original, offset, timer_reload),
+            Event::ChannelAllocated { channel, track } => {
+                write!(f, "channel {} allocated to track {}", channel, track)
+            }
+            Event::ChannelReleased { channel, track } => {
+                write!(f, "channel {} released by track {}", channel, track)
+            }
+            Event::ChannelFreed { channel } => write!(f, "channel {} freed", channel),
+            Event::NoChannelAvailable { track } => {
+                write!(f, "no channel available for track {}", track)
+            }
+            Event::NextAdsr {
+                track,
+                channel,
+                adsr,
+            } => write!(
+                f,
+                "track {} channel {} next ADSR: {:?}",
+                track, channel, adsr
+            ),
+            Event::PitchAdjustments { adjustments } => {
+                write!(f, "pitch adjustments: {:?}", adjustments)
+            }
+            Event::KeyOn {
+                channel,
+                volume,
+                volume_shift,
+                pan,
+            } => write!(
+                f,
+                "channel {} key on: volume {}, volume shift {}, pan {}",
+                channel, volume, volume_shift, pan
+            ),
+            Event::SoundControl { control } => write!(f, "sound control {:#06X}", control),
+            Event::UnsupportedCaptureMode { unit, control } => write!(
+                f,
+                "unsupported mode {:#04X} for capture unit {}",
+                control, unit
+            ),
+            Event::BiosRead { channel, address } => write!(
+                f,
+                "channel {} read from the BIOS at {:#010X}",
+                channel, address
+            ),
+            Event::ChannelPan { channel, pan } => write!(f, "channel {} pan {}", channel, pan),
+            Event::ChannelPlay { channel, control } => {
+                write!(f, "channel {} play {:#010X}", channel, control)
+            }
+            Event::ChannelPlayPsg {
+                channel,
+                control,
+                table_index,
+            } => write!(
+                f,
+                "channel {} play PSG {:#010X} (duty {})",
+                channel, control, table_index
+            ),
+            Event::Underrun {
+                render_ms,
+                buffer_ms,
+            } => write!(
+                f,
+                "underrun: {:.2}ms to render {:.2}ms of output",
+                render_ms, buffer_ms
+            ),
+        }
+    }
+}
+
+pub enum Sink {
+    None,
+    /// stderr natively, the JS console on the web
+    Console,
+    /// Called on whichever thread logged the event, which may be the audio thread.
+    /// Logging from inside the callback deadlocks.
+    Callback(Box<dyn Fn(&Event) + Send>),
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+static CATEGORIES: AtomicU8 = AtomicU8::new(0x1F);
+static SINK: Mutex<Sink> = Mutex::new(Sink::Console);
+
+pub fn set_sink(sink: Sink) {
+    *SINK.lock().unwrap() = sink;
+}
+
+/// Events below `level` are dropped, warnings and above are all that's logged by default
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_category(category: Category, enabled: bool) {
+    if enabled {
+        CATEGORIES.fetch_or(category.bit(), Ordering::Relaxed);
+    } else {
+        CATEGORIES.fetch_and(!category.bit(), Ordering::Relaxed);
+    }
+}
+
+/// For skipping the work of building events nobody will see
+pub fn enabled(category: Category, level: Level) -> bool {
+    level as u8 >= LEVEL.load(Ordering::Relaxed)
+        && CATEGORIES.load(Ordering::Relaxed) & category.bit() != 0
+}
+
+pub fn log(event: Event) {
+    if !enabled(event.category(), event.level()) {
+        return;
+    }
+
+    match &*SINK.lock().unwrap() {
+        Sink::None => {}
+        Sink::Console => console_log(&format!(
+            "[{}] {}: {}",
+            event.level().name(),
+            event.category().name(),
+            event
+        )),
+        Sink::Callback(callback) => callback(&event),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn console_log(message: &str) {
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = console, js_name = log)]
+        fn js_console_log(s: &str);
+    }
+
+    js_console_log(message);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn console_log(message: &str) {
+    eprintln!("{}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, player::Player};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_log_callback() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let logged = events.clone();
+        set_sink(Sink::Callback(Box::new(move |event| {
+            logged.lock().unwrap().push(event.clone())
+        })));
+        set_level(Level::Info);
+        set_category(Category::Sequencer, false);
+
+        // Other tests log while the callback is set, so only events with a tempo none of
+        // them play at are counted
+        let mut mio = fixtures::mio();
+        fixtures::set_tempo(&mut mio, 23);
+        let ram = fixtures::zeroed_ram();
+        Player::new(&mio, &ram, 1.0).unwrap();
+        set_category(Category::Sequencer, true);
+        Player::new(&mio, &ram, 1.0).unwrap();
+
+        set_sink(Sink::Console);
+        set_level(Level::Warn);
+
+        let tempos = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    Event::Tempo {
+                        tempo: 290,
+                        swing: false,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(tempos, 1);
+        assert!(!enabled(Category::SpuRegisters, Level::Debug));
+    }
+}
@@ -0,0 +1,174 @@
+use nanoserde::{DeBin, SerBin};
+
+use crate::{
+    audio::{Channel, SAMPLE_RATE},
+    spu::Spu,
+    stems::STEM_COUNT,
+};
+
+/// Samples a change takes to fully apply, so moving a slider doesn't click
+const RAMP_SAMPLES: f32 = SAMPLE_RATE as f32 / 50.0;
+const GAIN_STEP: f32 = 1.0 / RAMP_SAMPLES;
+const PAN_STEP: f32 = 128.0 / RAMP_SAMPLES;
+
+pub const MAX_PAN_OFFSET: i32 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, DeBin, SerBin)]
+struct TrackMix {
+    gain: f32,
+    pan: f32,
+}
+
+impl Default for TrackMix {
+    fn default() -> Self {
+        TrackMix {
+            gain: 1.0,
+            pan: 0.0,
+        }
+    }
+}
+
+fn approach(current: f32, target: f32, step: f32) -> f32 {
+    if (target - current).abs() <= step {
+        target
+    } else {
+        current + step * (target - current).signum()
+    }
+}
+
+/// Per-track gain and pan offset, and a master volume, on top of what the song sets.
+/// Tracks are indexed like stems, 4 melodic tracks followed by 4 drum lanes.
+#[derive(Debug, Clone, PartialEq, DeBin, SerBin)]
+pub struct Mixer {
+    targets: [TrackMix; STEM_COUNT],
+    current: [TrackMix; STEM_COUNT],
+    master_volume: f32,
+    current_master_volume: f32,
+    /// Set by the sequencer for `Record::fade_out`, already smooth so it isn't ramped
+    fade: f32,
+}
+
+impl Mixer {
+    pub fn new(master_volume: f32) -> Mixer {
+        Mixer {
+            targets: Default::default(),
+            current: Default::default(),
+            master_volume,
+            current_master_volume: master_volume,
+            fade: 1.0,
+        }
+    }
+
+    /// 1.0 plays the track as written, 0.0 mutes it
+    pub fn set_track_volume(&mut self, track: usize, gain: f32) {
+        self.targets[track].gain = gain.max(0.0);
+    }
+
+    pub fn track_volume(&self, track: usize) -> f32 {
+        self.targets[track].gain
+    }
+
+    /// Added to the pan of every note on the track, negative towards the left
+    pub fn set_track_pan(&mut self, track: usize, pan_offset: i32) {
+        self.targets[track].pan = pan_offset.clamp(-MAX_PAN_OFFSET, MAX_PAN_OFFSET) as f32;
+    }
+
+    pub fn track_pan(&self, track: usize) -> i32 {
+        self.targets[track].pan as i32
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume.max(0.0);
+    }
+
+    pub fn master_volume(&self) -> f32 {
+        self.master_volume
+    }
+
+    /// Where the master volume has ramped to with any fade-out applied,
+    /// what the last sample was scaled by
+    pub fn current_master_volume(&self) -> f32 {
+        self.current_master_volume * self.fade
+    }
+
+    /// Takes on `other`'s settings right away instead of ramping to them, for a player
+    /// picking up where another left off
+    pub fn copy_settings(&mut self, other: &Mixer) {
+        self.targets = other.targets;
+        self.current = other.targets;
+        self.master_volume = other.master_volume;
+        self.current_master_volume = other.master_volume;
+    }
+
+    pub fn set_fade(&mut self, fade: f32) {
+        self.fade = fade;
+    }
+
+    /// Moves every setting a step towards its target and applies the tracks' mix to the
+    /// channels they own. Called once per sample, after the sequencer and before `Spu::mix`.
+    pub fn step(&mut self, spu: &mut Spu, channels: &[Channel]) {
+        for (current, target) in self.current.iter_mut().zip(&self.targets) {
+            current.gain = approach(current.gain, target.gain, GAIN_STEP);
+            current.pan = approach(current.pan, target.pan, PAN_STEP);
+        }
+        self.current_master_volume =
+            approach(self.current_master_volume, self.master_volume, GAIN_STEP);
+
+        for (channel_id, channel) in channels.iter().enumerate() {
+            match channel {
+                Channel::Used { sound, .. } | Channel::Freeing { sound, .. } => {
+                    // Tracks played live have no stem and play unmixed
+                    let mix = self
+                        .current
+                        .get(sound.track() as usize)
+                        .copied()
+                        .unwrap_or_default();
+                    spu.set_channel_mix(channel_id, mix.gain, mix.pan.round() as i32);
+                }
+                Channel::Open | Channel::Withheld | Channel::Blocked => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fixtures, player::Player, stems::render_stems};
+
+    #[test]
+    fn test_track_volume() {
+        let ram = fixtures::zeroed_ram();
+        let mut mio = fixtures::song(40, &[(0, 12)]);
+        fixtures::set_instrument(&mut mio, 1, 41);
+        fixtures::set_note(&mut mio, 1, 0, 24);
+
+        let energy = |data: &[f32]| data.iter().map(|sample| sample * sample).sum::<f32>();
+        // Past the ramp and the capture buffer the output goes through
+        let settled = 4000 * 2;
+
+        let expected = render_stems(&mut Player::new(&mio, &ram, 1.0).unwrap(), 16000);
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        player.set_track_volume(1, 0.0);
+        player.set_track_pan(0, -64);
+        let stems = render_stems(&mut player, 16000);
+
+        assert!(energy(&expected.tracks[1][settled..]) > 0.0);
+        assert_eq!(energy(&stems.tracks[1][settled..]), 0.0);
+        let left_heavy = |track: &[f32]| {
+            let left = energy(&track.iter().step_by(2).cloned().collect::<Vec<_>>());
+            let right = energy(&track.iter().skip(1).step_by(2).cloned().collect::<Vec<_>>());
+            left / right
+        };
+        assert!(
+            left_heavy(&stems.tracks[0][settled..]) > left_heavy(&expected.tracks[0][settled..])
+        );
+
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        player.set_master_volume(0.5);
+        let mut quiet = vec![0.0; 32000];
+        player.render_native(&mut quiet);
+        for (quiet, expected) in quiet[settled..].iter().zip(&expected.mix[settled..]) {
+            assert_eq!(*quiet, expected * 0.5);
+        }
+    }
+}
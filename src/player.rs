@@ -0,0 +1,1059 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use nanoserde::{DeBin, SerBin};
+
+use crate::{
+    audio::*,
+    bus::{BusError, RamRegion, SampleSpace, SAMPLE_SPACE_ADDRESS, SAMPLE_SPACE_SIZE},
+    custom::CustomInstrument,
+    drums::drum_instructions,
+    echo::Echo,
+    ins::instrument_instructions,
+    log::{self, Event},
+    mixer::Mixer,
+    record::Record,
+    renderer::RenderStats,
+    resample::Resampler,
+    sample_bank::SampleBank,
+    spu::{AudioBitDepth, AudioInterpolation, Nds, Spu},
+    trace::SpuTrace,
+};
+
+/// Where `Echo::setup` points the two capture units, and how many bytes each writes
+pub const CAPTURE_BUFFER_ADDRESSES: [usize; 2] = [35253536, 35255584];
+pub const CAPTURE_BUFFER_SIZE: usize = 2048;
+
+/// SOUNDBIAS, and the midpoint the BIOS ramps it up to at boot
+const SOUND_BIAS: u32 = 0x04000504;
+const SOUND_BIAS_LEVEL: u16 = 0x200;
+
+const SAVE_STATE_MAGIC: &[u8; 4] = b"WSAV";
+pub const SAVE_STATE_VERSION: u16 = 1;
+
+/// The track `preview_note` plays on
+pub const PREVIEW_TRACK: u8 = TRACK_SLOTS as u8 - 1;
+/// The track `trigger_drum` plays on
+pub const DRUM_PAD_TRACK: u8 = PREVIEW_TRACK - 1;
+/// Tracks `note_on` can play on, each one note at a time
+pub const LIVE_TRACK_COUNT: usize = (DRUM_PAD_TRACK - FIRST_LIVE_TRACK) as usize;
+
+pub struct Player {
+    pub spu: Arc<Mutex<Spu>>,
+    pub timing: Timing,
+    pub channel_manager: ChannelManager,
+    pub record: Record,
+    pub instruments: Vec<Instrument>,
+    pub rhythm_sections: [RhythmSection; RHYTHM_SECTION_COUNT],
+    pub previous_notes: [Option<u8>; TRACK_SLOTS],
+    pub mixer: Mixer,
+    /// Kept by whichever `Renderer` plays this
+    pub render_stats: RenderStats,
+    echo: Echo,
+    /// Set by `set_tempo`, kept when another song is loaded
+    tempo_override: Option<u32>,
+    resampler: Option<Resampler>,
+    /// Samples until the previewed note is released
+    preview_release: Option<usize>,
+    /// Bytes of custom samples loaded, None until the first one maps `SampleSpace`
+    sample_space_used: Option<usize>,
+}
+
+impl Player {
+    pub fn new(mio_data: &[u8], ram: &[u8], my_volume: f32) -> Result<Player, BusError> {
+        Ok(Player::with_nds(
+            mio_data,
+            Nds::new(ram.to_vec())?,
+            my_volume,
+        ))
+    }
+
+    pub fn from_sample_bank(mio_data: &[u8], sample_bank: SampleBank, my_volume: f32) -> Player {
+        // Banks made with `SampleBank::from_ranges` may leave the capture buffers out
+        let missing_buffers: Vec<usize> = CAPTURE_BUFFER_ADDRESSES
+            .iter()
+            .cloned()
+            .filter(|&address| !sample_bank.contains(address))
+            .collect();
+
+        let mut nds = Nds::from_sample_bank(sample_bank);
+        for address in missing_buffers {
+            nds.map_region(Box::new(RamRegion::new(address, CAPTURE_BUFFER_SIZE)));
+        }
+        Player::with_nds(mio_data, nds, my_volume)
+    }
+
+    /// For buses put together by hand, which need RAM at `CAPTURE_BUFFER_ADDRESSES`
+    pub fn with_nds(mio_data: &[u8], nds: Nds, my_volume: f32) -> Player {
+        let nds = Arc::new(Mutex::new(nds));
+
+        let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
+        let echo = Echo::default();
+        echo.setup(&mut spu);
+        spu.write16(SOUND_BIAS, SOUND_BIAS_LEVEL);
+
+        Player {
+            spu: Arc::new(Mutex::new(spu)),
+            timing: Timing {
+                tiny_tick: 0,
+                phrase_tick: 0,
+            },
+            channel_manager: ChannelManager {
+                channels: [
+                    Channel::Open,
+                    Channel::Blocked,
+                    Channel::Open,
+                    Channel::Blocked,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                    Channel::Open,
+                ],
+            },
+            record: Record::from_mio(mio_data),
+            instruments: instrument_instructions(),
+            rhythm_sections: drum_instructions(),
+            previous_notes: [None; TRACK_SLOTS],
+            mixer: Mixer::new(my_volume),
+            render_stats: RenderStats::default(),
+            echo,
+            tempo_override: None,
+            resampler: None,
+            preview_release: None,
+            sample_space_used: None,
+        }
+    }
+
+    /// Resamples the output of `render` to `sample_rate`, the SPU keeps running at `SAMPLE_RATE`
+    pub fn set_output_rate(&mut self, sample_rate: u32) {
+        self.resampler = if sample_rate == SAMPLE_RATE as u32 {
+            None
+        } else {
+            Some(Resampler::new(SAMPLE_RATE as u32, sample_rate, 2))
+        };
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.resampler
+            .as_ref()
+            .map(|resampler| resampler.output_rate())
+            .unwrap_or(SAMPLE_RATE as u32)
+    }
+
+    /// `AudioBitDepth::_10bit` for the output as heard on a DS, `_16bit` for the clean mix
+    pub fn set_bit_depth(&mut self, depth: AudioBitDepth) {
+        self.spu.lock().unwrap().set_bit_depth(depth);
+    }
+
+    pub fn bit_depth(&self) -> AudioBitDepth {
+        self.spu.lock().unwrap().bit_depth()
+    }
+
+    /// How every channel smooths between the samples it reads
+    pub fn set_interpolation(&mut self, interpolation: AudioInterpolation) {
+        self.spu.lock().unwrap().set_interpolation(interpolation);
+    }
+
+    pub fn interpolation(&self) -> AudioInterpolation {
+        self.spu.lock().unwrap().interpolation()
+    }
+
+    pub fn echo(&self) -> Echo {
+        self.echo
+    }
+
+    /// Changing the delay restarts the capture buffers, dropping any echoes still in them
+    pub fn set_echo(&mut self, echo: Echo) {
+        let mut spu = self.spu.lock().unwrap();
+        if echo.delay != self.echo.delay {
+            echo.setup(&mut spu);
+        } else {
+            echo.update_mix(&mut spu);
+        }
+        self.echo = echo;
+    }
+
+    /// 1.0 plays `track` as written, 0.0 mutes it. Tracks are indexed like stems.
+    /// Like the pan and master volume, it ramps to the new value over a few milliseconds.
+    pub fn set_track_volume(&mut self, track: usize, gain: f32) {
+        self.mixer.set_track_volume(track, gain);
+    }
+
+    /// Added to the pan of every note on `track`, negative towards the left
+    pub fn set_track_pan(&mut self, track: usize, pan_offset: i32) {
+        self.mixer.set_track_pan(track, pan_offset);
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.mixer.set_master_volume(volume);
+    }
+
+    /// Plays the song at `tempo` beats per minute instead of its own, from the next step on
+    pub fn set_tempo(&mut self, tempo: u32) {
+        self.tempo_override = Some(tempo);
+        self.record.request_tempo(tempo);
+    }
+
+    pub fn tempo(&self) -> u32 {
+        self.record.pending_tempo.unwrap_or(self.record.tempo)
+    }
+
+    /// Plays the song `count` times before releasing every track, even if it would loop
+    /// forever. None goes back to the song's own looping. `record.length()` is then how
+    /// many samples to render for the whole song.
+    pub fn set_loop_count(&mut self, count: Option<usize>) {
+        self.record.loop_count = count;
+    }
+
+    /// Fades out over the final loop, for songs that end
+    pub fn set_fade_out(&mut self, enabled: bool) {
+        self.record.fade_out = enabled;
+    }
+
+    /// Shifts every melodic note by `semitones`, from the next note on
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.record.set_transpose(semitones);
+    }
+
+    pub fn transpose(&self) -> i32 {
+        self.record.transpose
+    }
+
+    /// Switches to another song from its first step, keeping the SPU, mixer, echo,
+    /// transpose, tempo override and fade-out. Notes still sounding play out their release
+    /// over the new song's start, and notes played live carry on.
+    pub fn load_song(&mut self, mio_data: &[u8]) {
+        for track in 0..FIRST_LIVE_TRACK {
+            self.channel_manager.release_tracks(track);
+        }
+        let transpose = self.record.transpose;
+        let fade_out = self.record.fade_out;
+        self.record = Record::from_mio(mio_data);
+        self.record.set_transpose(transpose);
+        self.record.fade_out = fade_out;
+        if let Some(tempo) = self.tempo_override {
+            self.record.request_tempo(tempo);
+        }
+        self.timing = Timing {
+            tiny_tick: 0,
+            phrase_tick: 0,
+        };
+        self.previous_notes[..FIRST_LIVE_TRACK as usize].fill(None);
+    }
+
+    /// Takes on `other`'s mixer, echo, transpose, tempo override, fade-out, bit depth and
+    /// interpolation, for another player carrying on where it left off. The output rate
+    /// isn't copied, it belongs to whatever resamples the two.
+    pub fn copy_settings(&mut self, other: &Player) {
+        self.mixer.copy_settings(&other.mixer);
+        self.set_echo(other.echo);
+        self.set_transpose(other.transpose());
+        self.tempo_override = other.tempo_override;
+        if let Some(tempo) = self.tempo_override {
+            self.record.request_tempo(tempo);
+        }
+        self.record.fade_out = other.record.fade_out;
+        self.set_bit_depth(other.bit_depth());
+        self.set_interpolation(other.interpolation());
+    }
+
+    /// Cuts off every note at once, instead of letting them play out their release
+    pub fn stop_sounds(&mut self) {
+        let mut spu = self.spu.lock().unwrap();
+        for (channel_id, channel) in self.channel_manager.channels.iter_mut().enumerate() {
+            if let Channel::Used { sound, .. } | Channel::Freeing { sound, .. } = channel {
+                spu.set_adjusted_channel_volume(channel_id, 0, sound.volume_multiplier());
+                log::log(Event::ChannelFreed {
+                    channel: channel_id as u8,
+                });
+                *channel = Channel::Open;
+            }
+        }
+    }
+
+    /// Whether `instrument` exists and has an ADSR covering `note`
+    fn can_play(&self, instrument: usize, note: u8) -> bool {
+        self.instruments.get(instrument).is_some_and(|instrument| {
+            instrument
+                .instructions
+                .adsrs()
+                .iter()
+                .any(|&(_, low, high)| (low..=high).contains(&note))
+        })
+    }
+
+    /// Plays `note` on `track` right away, the way the sequencer would have. False when
+    /// there was no channel free for it.
+    fn start_live_note(&mut self, track: u8, instrument: usize, note: u8) -> bool {
+        let note = QueuedNote {
+            time: 0,
+            instrument: instrument as u32,
+            note,
+            track,
+            pan_addition: 0,
+            volume_multiplier: 1.0,
+        };
+        start_note(
+            &mut self.channel_manager,
+            &mut self.spu.lock().unwrap(),
+            &note,
+            &self.instruments,
+            &mut self.previous_notes,
+            0,
+            self.record.transpose,
+            self.timing.tiny_tick,
+        )
+    }
+
+    /// Plays one note of `instrument` through its envelopes and pitch adjustments, released
+    /// after `duration` samples at `SAMPLE_RATE`. It plays over the song if there is one, and
+    /// cuts off the last preview. False when the instrument has nothing for `note`, or
+    /// there's no channel free for it.
+    pub fn preview_note(&mut self, instrument: usize, note: u8, duration: usize) -> bool {
+        if !self.can_play(instrument, note) {
+            return false;
+        }
+        if !self.start_live_note(PREVIEW_TRACK, instrument, note) {
+            self.preview_release = None;
+            return false;
+        }
+        self.preview_release = Some(duration);
+        true
+    }
+
+    /// Starts `note` on live `track`, releasing the track's last note, and holds it in its
+    /// sustain until `note_off`. Instruments without a sustain play out like they do in a
+    /// song. Live tracks share channels with the song, so a busy song can cut them short,
+    /// and false is returned when there's no channel free for the note at all.
+    pub fn note_on(&mut self, track: usize, instrument: usize, note: u8) -> bool {
+        if track >= LIVE_TRACK_COUNT || !self.can_play(instrument, note) {
+            return false;
+        }
+        let track = FIRST_LIVE_TRACK + track as u8;
+        if !self.start_live_note(track, instrument, note) {
+            return false;
+        }
+
+        for channel in &mut self.channel_manager.channels {
+            if let Channel::Used {
+                sound,
+                envelope:
+                    Some(Envelope {
+                        sustain: Some(sustain),
+                        ..
+                    }),
+                ..
+            } = channel
+            {
+                if sound.track() == track {
+                    sustain.duration = u32::MAX;
+                }
+            }
+        }
+        true
+    }
+
+    /// Releases live `track` if it's still playing `note`, so letting go of a key that was
+    /// already replaced by another doesn't cut the new one off
+    pub fn note_off(&mut self, track: usize, note: u8) {
+        if track >= LIVE_TRACK_COUNT {
+            return;
+        }
+        let track = FIRST_LIVE_TRACK + track as u8;
+        let playing = self.channel_manager.channels.iter().any(|channel| {
+            matches!(
+                channel,
+                Channel::Used { sound: QueuedSound::Note(playing), .. }
+                    if playing.track == track && playing.note == note
+            )
+        });
+        if playing {
+            self.channel_manager.release_tracks(track);
+        }
+    }
+
+    /// Plays drum `drum_id` of rhythm section `section` right away, the way a song's drum
+    /// lane would. Each hit releases the last one like in a lane. `volume` scales the drum
+    /// like a song's drum volume, 1.0 being the loudest a song plays it, and `pan` is added
+    /// to the drum's own pan, -64 to 64 covering what a song can set. False when there's no
+    /// such drum or no channel free for it.
+    pub fn trigger_drum(&mut self, section: usize, drum_id: usize, volume: f32, pan: i32) -> bool {
+        if section >= RHYTHM_SECTION_COUNT || drum_id >= DRUM_COUNT {
+            return false;
+        }
+        let drum = QueuedDrum {
+            time: 0,
+            section,
+            id: drum_id,
+            pretend_track: DRUM_PAD_TRACK,
+            pan_addition: pan,
+            volume_multiplier: volume.max(0.0),
+        };
+        start_drum(
+            &mut self.channel_manager,
+            &mut self.spu.lock().unwrap(),
+            &drum,
+            &self.rhythm_sections,
+            0,
+        )
+    }
+
+    /// Loads `custom`'s sample and adds it after the game's instruments, returning the index
+    /// to preview it or play it live with
+    pub fn add_custom_instrument(&mut self, custom: &CustomInstrument) -> io::Result<usize> {
+        let instrument = self.load_custom_instrument(custom)?;
+        self.instruments.push(instrument);
+        Ok(self.instruments.len() - 1)
+    }
+
+    /// Plays instrument `with` wherever the song plays instrument `index`, for remixes with
+    /// custom instruments or the game's own. False when either doesn't exist.
+    pub fn substitute_instrument(&mut self, index: usize, with: usize) -> bool {
+        match self.instruments.get(with).cloned() {
+            Some(instrument) if index < self.instruments.len() => {
+                self.instruments[index] = instrument;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Goes back to the game's instruments, dropping added ones. Their samples stay loaded,
+    /// the space isn't reused.
+    pub fn reset_instruments(&mut self) {
+        self.instruments = instrument_instructions();
+    }
+
+    /// Writes `custom`'s sample after the ones already in `SampleSpace`
+    fn load_custom_instrument(&mut self, custom: &CustomInstrument) -> io::Result<Instrument> {
+        let nds = self.spu.lock().unwrap().nds();
+        let mut nds = nds.lock().unwrap();
+        let used = match self.sample_space_used {
+            Some(used) => used,
+            None => {
+                nds.map_region(Box::new(SampleSpace::new()));
+                self.sample_space_used = Some(0);
+                0
+            }
+        };
+
+        let src_address = SAMPLE_SPACE_ADDRESS as usize + used;
+        let (data, sample) = custom.encode(src_address)?;
+        if used + data.len() > SAMPLE_SPACE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("no room left for {}'s sample", custom.name),
+            ));
+        }
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let val = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            nds.write32((src_address + i * 4) as u32, val)?;
+        }
+        self.sample_space_used = Some(used + data.len());
+        Ok(custom.instrument(sample))
+    }
+
+    /// Snapshots the SPU, the sequencer and the playback settings along with any notes
+    /// played live, loading it later resumes from this exact sample
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut s = Vec::new();
+        s.extend_from_slice(SAVE_STATE_MAGIC);
+        SAVE_STATE_VERSION.ser_bin(&mut s);
+        self.record.tempo.ser_bin(&mut s);
+        self.record.pending_tempo.ser_bin(&mut s);
+        self.record.loop_count.ser_bin(&mut s);
+        self.record.fade_out.ser_bin(&mut s);
+        self.record.transpose.ser_bin(&mut s);
+        self.tempo_override.ser_bin(&mut s);
+        self.timing.ser_bin(&mut s);
+        self.channel_manager.ser_bin(&mut s);
+        self.previous_notes.ser_bin(&mut s);
+        self.preview_release.ser_bin(&mut s);
+        self.mixer.ser_bin(&mut s);
+        self.echo.ser_bin(&mut s);
+        self.spu.lock().unwrap().save_state(&mut s);
+        s
+    }
+
+    /// Restores a state made by `save_state` while playing the same song and RAM.
+    /// The resampler starts over, so only native rate output carries on bit for bit.
+    pub fn load_state(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if bytes.len() < SAVE_STATE_MAGIC.len()
+            || &bytes[..SAVE_STATE_MAGIC.len()] != SAVE_STATE_MAGIC
+        {
+            return Err(invalid("not a save state".to_string()));
+        }
+        let o = &mut SAVE_STATE_MAGIC.len();
+        let corrupt = |err| invalid(format!("corrupt save state: {:?}", err));
+
+        let version = u16::de_bin(o, bytes).map_err(corrupt)?;
+        if version != SAVE_STATE_VERSION {
+            return Err(invalid(format!(
+                "unsupported save state version {}",
+                version
+            )));
+        }
+
+        let tempo = u32::de_bin(o, bytes).map_err(corrupt)?;
+        let pending_tempo = DeBin::de_bin(o, bytes).map_err(corrupt)?;
+        let loop_count = DeBin::de_bin(o, bytes).map_err(corrupt)?;
+        let fade_out = bool::de_bin(o, bytes).map_err(corrupt)?;
+        let transpose = i32::de_bin(o, bytes).map_err(corrupt)?;
+        let tempo_override = DeBin::de_bin(o, bytes).map_err(corrupt)?;
+        let timing = Timing::de_bin(o, bytes).map_err(corrupt)?;
+        let channel_manager = ChannelManager::de_bin(o, bytes).map_err(corrupt)?;
+        let previous_notes = DeBin::de_bin(o, bytes).map_err(corrupt)?;
+        let preview_release = DeBin::de_bin(o, bytes).map_err(corrupt)?;
+        let mixer = Mixer::de_bin(o, bytes).map_err(corrupt)?;
+        let echo = Echo::de_bin(o, bytes).map_err(corrupt)?;
+        self.spu
+            .lock()
+            .unwrap()
+            .load_state(o, bytes)
+            .map_err(corrupt)?;
+
+        // The song's place is counted in samples, which only line up at the saved tempo
+        self.record.set_tempo(tempo);
+        self.record.pending_tempo = pending_tempo;
+        self.record.loop_count = loop_count;
+        self.record.fade_out = fade_out;
+        self.record.set_transpose(transpose);
+        self.tempo_override = tempo_override;
+        self.timing = timing;
+        self.channel_manager = channel_manager;
+        self.previous_notes = previous_notes;
+        self.preview_release = preview_release;
+        self.mixer = mixer;
+        // The SPU state already holds the capture units it set up
+        self.echo = echo;
+        let output_rate = self.output_rate();
+        self.set_output_rate(output_rate);
+        Ok(())
+    }
+
+    /// Records everything the sequencer tells the SPU from here on, see `trace::TraceReplayer`
+    pub fn start_trace(&mut self) {
+        self.spu.lock().unwrap().start_trace();
+    }
+
+    /// An empty trace when `start_trace` wasn't called
+    pub fn stop_trace(&mut self) -> SpuTrace {
+        self.spu.lock().unwrap().stop_trace().unwrap_or(SpuTrace {
+            initial_state: None,
+            events: Vec::new(),
+            length: 0,
+        })
+    }
+
+    /// Fills interleaved stereo `data` with the next samples of the song at the output rate
+    pub fn render(&mut self, data: &mut [f32]) {
+        match self.resampler.take() {
+            Some(mut resampler) => {
+                resampler.render(data, |native| self.render_native(native));
+                self.resampler = Some(resampler);
+            }
+            None => self.render_native(data),
+        }
+    }
+
+    /// Fills interleaved stereo `data` with the next samples of the song at `SAMPLE_RATE`
+    pub fn render_native(&mut self, mut data: &mut [f32]) {
+        // Split where the preview is released, so it's released on the exact sample
+        if let Some(samples) = self.preview_release {
+            let frames = samples.min(data.len() / 2);
+            let (before, after) = data.split_at_mut(frames * 2);
+            self.sequence(before);
+            data = after;
+            self.preview_release = if frames == samples {
+                self.channel_manager.release_tracks(PREVIEW_TRACK);
+                None
+            } else {
+                Some(samples - frames)
+            };
+        }
+        self.sequence(data);
+    }
+
+    fn sequence(&mut self, data: &mut [f32]) {
+        play_stuff(
+            self.spu.clone(),
+            1,
+            &mut self.timing,
+            &mut self.channel_manager,
+            &mut self.record,
+            &self.instruments,
+            &self.rhythm_sections,
+            &mut self.previous_notes,
+            data.chunks_mut(2),
+            &mut self.mixer,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        custom::CustomAdsr,
+        fixtures,
+        spu::{SampleFormat, SpuCall},
+        wav::{encode_sample_wav, SampleLoop},
+    };
+
+    #[test]
+    fn test_save_state_resumes_identically() {
+        let ram = fixtures::patterned_ram();
+        let mut mio = fixtures::mio();
+        fixtures::set_instrument(&mut mio, 0, 0);
+        fixtures::set_instrument(&mut mio, 1, 40);
+        fixtures::set_instrument(&mut mio, fixtures::DRUM_TRACK, 0);
+        for time in 0..8 {
+            fixtures::set_note(&mut mio, 0, time * 4, time as u8);
+            fixtures::set_note(&mut mio, 1, time * 4 + 2, 12);
+            fixtures::set_drum(&mut mio, 0, time * 4, 0);
+        }
+
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        let mut data = vec![0.0; 20000];
+        player.render_native(&mut data);
+        // Settings and live notes that a fresh player wouldn't have
+        player.set_tempo(150);
+        player.set_transpose(3);
+        player.set_loop_count(Some(1));
+        player.set_fade_out(true);
+        player.set_track_volume(1, 0.5);
+        player.set_track_pan(0, -40);
+        player.set_master_volume(0.8);
+        player.set_echo(Echo {
+            enabled: true,
+            feedback: 64,
+            ..Echo::default()
+        });
+        assert!(player.preview_note(40, 12, 6000));
+        assert!(player.note_on(0, 41, 24));
+        let state = player.save_state();
+
+        let mut expected = vec![0.0; 20000];
+        player.render_native(&mut expected);
+
+        let mut restored = Player::new(&mio, &ram, 1.0).unwrap();
+        restored.load_state(&state).unwrap();
+        let mut output = vec![0.0; 20000];
+        restored.render_native(&mut output);
+
+        assert!(expected.iter().any(|&sample| sample != 0.0));
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let ram = fixtures::patterned_ram();
+        let mio = fixtures::song(0, &[(0, 12)]);
+        let render = |interpolation: AudioInterpolation| {
+            let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+            player.set_interpolation(interpolation);
+            assert_eq!(player.interpolation(), interpolation);
+            let mut data = vec![0.0; 16000 * 2];
+            player.render_native(&mut data);
+            data
+        };
+
+        let outputs: Vec<Vec<f32>> = AudioInterpolation::ALL
+            .iter()
+            .map(|&mode| render(mode))
+            .collect();
+        assert!(outputs[0].iter().any(|&sample| sample != 0.0));
+        for (i, output) in outputs.iter().enumerate() {
+            for other in &outputs[i + 1..] {
+                assert_ne!(output, other);
+            }
+        }
+    }
+
+    fn psg_mio(notes: &[(usize, u8)]) -> Vec<u8> {
+        fixtures::song(40, notes)
+    }
+
+    fn traced(player: &mut Player, samples: usize) -> Vec<(u64, SpuCall)> {
+        player.start_trace();
+        let mut data = vec![0.0; samples * 2];
+        player.render_native(&mut data);
+        player
+            .stop_trace()
+            .events
+            .iter()
+            .map(|event| (event.sample, event.call))
+            .collect()
+    }
+
+    #[test]
+    fn test_tempo_and_transpose() {
+        let ram = fixtures::zeroed_ram();
+        let key_ons = |events: &[(u64, SpuCall)]| -> Vec<u64> {
+            events
+                .iter()
+                .filter(|(_, call)| matches!(call, SpuCall::ChannelPlayPsg { .. }))
+                .map(|(sample, _)| *sample)
+                .collect()
+        };
+        let timer_reloads = |events: &[(u64, SpuCall)]| -> Vec<u32> {
+            events
+                .iter()
+                .filter_map(|(_, call)| match call {
+                    SpuCall::SetChannelTimerReload { timer_reload, .. } => Some(*timer_reload),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mio = psg_mio(&[(0, 12), (16, 12)]);
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        player.set_tempo(60);
+        assert_eq!(key_ons(&traced(&mut player, 140000)), [0, 16 * 8206]);
+
+        // Asked for mid-step, the new tempo starts with step 2
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        let mut data = vec![0.0; 5000 * 2];
+        player.render_native(&mut data);
+        player.set_tempo(60);
+        assert_eq!(player.tempo(), 60);
+        assert_eq!(
+            key_ons(&traced(&mut player, 140000)),
+            [2 * 4103 - 5000 + 14 * 8206]
+        );
+
+        let mut transposed = Player::new(&psg_mio(&[(0, 12)]), &ram, 1.0).unwrap();
+        transposed.set_transpose(3);
+        let mut expected = Player::new(&psg_mio(&[(0, 15)]), &ram, 1.0).unwrap();
+        assert_eq!(
+            timer_reloads(&traced(&mut transposed, 8000)),
+            timer_reloads(&traced(&mut expected, 8000))
+        );
+
+        let mut past_table = Player::new(&psg_mio(&[(0, 24)]), &ram, 1.0).unwrap();
+        past_table.set_transpose(2);
+        let mut top = Player::new(&psg_mio(&[(0, 24)]), &ram, 1.0).unwrap();
+        assert!(
+            timer_reloads(&traced(&mut past_table, 8000))[0]
+                > timer_reloads(&traced(&mut top, 8000))[0]
+        );
+    }
+
+    #[test]
+    fn test_loop_count_and_fade_out() {
+        let ram = fixtures::zeroed_ram();
+        let mio = psg_mio(&[(0, 12), (8, 12)]);
+        let loop_length = 32 * 4103;
+
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        player.set_loop_count(Some(3));
+        assert_eq!(player.record.length(), Some(3 * loop_length));
+        let key_ons: Vec<u64> = traced(&mut player, 4 * loop_length)
+            .iter()
+            .filter(|(_, call)| matches!(call, SpuCall::ChannelPlayPsg { .. }))
+            .map(|(sample, _)| *sample)
+            .collect();
+        let expected: Vec<u64> = (0..3)
+            .flat_map(|times| [0, 8 * 4103].map(|step| (times * loop_length + step) as u64))
+            .collect();
+        assert_eq!(key_ons, expected);
+
+        let render = |fade_out: bool| {
+            let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+            player.set_loop_count(Some(2));
+            player.set_fade_out(fade_out);
+            let mut data = vec![0.0; 3 * loop_length * 2];
+            player.render_native(&mut data);
+            data
+        };
+        let faded = render(true);
+        let unfaded = render(false);
+        assert_eq!(faded[..loop_length * 2], unfaded[..loop_length * 2]);
+        for frame in loop_length..2 * loop_length {
+            let fade = 1.0 - (frame - loop_length) as f32 / loop_length as f32;
+            for channel in 0..2 {
+                let i = frame * 2 + channel;
+                assert!((faded[i] - unfaded[i] * fade).abs() < 1e-4);
+            }
+        }
+        assert!(faded[loop_length * 4..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_loop_count_with_segments() {
+        let ram = fixtures::zeroed_ram();
+        let mut mio = psg_mio(&[(0, 12)]);
+        fixtures::set_segment_count(&mut mio, 2);
+        let loop_length = 2 * 32 * 4103;
+
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        player.set_loop_count(Some(2));
+        let mut data = vec![0.0; (loop_length + 10) * 2];
+        player.render_native(&mut data);
+        // The note started over with the second loop, 10 samples ago
+        let ages: Vec<usize> = player
+            .channel_manager
+            .channels
+            .iter()
+            .filter_map(|channel| match channel {
+                Channel::Used { sound, age, .. } if sound.track() == 0 => Some(*age),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ages, [10]);
+    }
+
+    #[test]
+    fn test_preview_note() {
+        let ram = fixtures::zeroed_ram();
+        let mut player = Player::new(&psg_mio(&[]), &ram, 1.0).unwrap();
+        assert!(!player.preview_note(40, 200, 3000));
+        assert!(!player.preview_note(1000, 12, 3000));
+
+        player.start_trace();
+        assert!(player.preview_note(40, 12, 3000));
+        let mut data = vec![0.0; 2999 * 2];
+        player.render_native(&mut data);
+        let held = |player: &Player| {
+            player.channel_manager.channels.iter().any(|channel| {
+                matches!(channel, Channel::Used { sound, .. } if sound.track() == PREVIEW_TRACK)
+            })
+        };
+        assert!(held(&player));
+        player.render_native(&mut data[..2]);
+        assert!(!held(&player));
+        let preview: Vec<_> = player
+            .stop_trace()
+            .events
+            .iter()
+            .map(|event| (event.sample, event.call))
+            .collect();
+
+        // Until its release, the same as the note in a song
+        let mut song = Player::new(&psg_mio(&[(0, 12)]), &ram, 1.0).unwrap();
+        let expected = traced(&mut song, 3000);
+        assert_eq!(
+            preview
+                .iter()
+                .filter(|(sample, _)| *sample < 3000)
+                .collect::<Vec<_>>(),
+            expected.iter().collect::<Vec<_>>()
+        );
+
+        // With every PSG channel taken there's none left to preview on
+        for channel in &mut player.channel_manager.channels[8..14] {
+            *channel = Channel::Blocked;
+        }
+        assert!(!player.preview_note(40, 12, 1000));
+        assert!(!held(&player));
+        assert!(player.preview_release.is_none());
+    }
+
+    #[test]
+    fn test_live_notes() {
+        let ram = fixtures::zeroed_ram();
+        let mut player = Player::new(&psg_mio(&[(0, 12), (16, 14)]), &ram, 1.0).unwrap();
+        let sustained = player
+            .instruments
+            .iter()
+            .position(|instrument| {
+                matches!(
+                    &instrument.instructions,
+                    InstrumentInstructions::Adsr(Adsr {
+                        sustain: Some(_),
+                        ..
+                    })
+                )
+            })
+            .unwrap();
+        assert!(!player.note_on(LIVE_TRACK_COUNT, sustained, 12));
+
+        assert!(player.note_on(0, sustained, 12));
+        assert!(player.note_on(1, 40, 19));
+        let track_volume = |player: &Player, track: u8| {
+            player
+                .channel_manager
+                .channels
+                .iter()
+                .find_map(|channel| match channel {
+                    Channel::Used { sound, volume, .. } if sound.track() == track => Some(*volume),
+                    _ => None,
+                })
+        };
+        // Just after the song's last note, long past where the instrument's sustain ends
+        // when it isn't held
+        let mut data = vec![0.0; (16 * 4103 + 200) * 2];
+        player.render_native(&mut data);
+        let mut unheld = Player::new(&psg_mio(&[]), &ram, 1.0).unwrap();
+        unheld.preview_note(sustained, 12, usize::MAX);
+        unheld.render_native(&mut data);
+        assert!(
+            track_volume(&player, FIRST_LIVE_TRACK).unwrap()
+                > track_volume(&unheld, PREVIEW_TRACK).unwrap_or(0)
+        );
+        assert!(track_volume(&player, FIRST_LIVE_TRACK + 1).is_some());
+
+        player.note_off(0, 13);
+        assert!(track_volume(&player, FIRST_LIVE_TRACK).is_some());
+        player.note_off(0, 12);
+        assert!(track_volume(&player, FIRST_LIVE_TRACK).is_none());
+        assert!(track_volume(&player, FIRST_LIVE_TRACK + 1).is_some());
+        // The song carried on around them
+        assert!(track_volume(&player, 0).is_some());
+
+        // A preview and five live notes take all six PSG channels, leaving none for a sixth
+        let mut full = Player::new(&psg_mio(&[]), &ram, 1.0).unwrap();
+        assert!(full.preview_note(40, 12, usize::MAX));
+        for track in 0..5 {
+            assert!(full.note_on(track, 40, 12));
+        }
+        assert!(!full.note_on(5, 40, 12));
+        assert!(track_volume(&full, FIRST_LIVE_TRACK + 5).is_none());
+    }
+
+    #[test]
+    fn test_drum_pads() {
+        let ram = fixtures::zeroed_ram();
+        let mut player = Player::new(&psg_mio(&[]), &ram, 1.0).unwrap();
+        assert!(!player.trigger_drum(RHYTHM_SECTION_COUNT, 0, 1.0, 0));
+        assert!(!player.trigger_drum(0, DRUM_COUNT, 1.0, 0));
+
+        let pad_channel = |player: &Player| {
+            player
+                .channel_manager
+                .channels
+                .iter()
+                .position(|channel| {
+                    matches!(channel, Channel::Used { sound, .. } if sound.track() == DRUM_PAD_TRACK)
+                })
+                .unwrap()
+        };
+        let mut data = vec![0.0; 2000 * 2];
+        for section in 0..RHYTHM_SECTION_COUNT {
+            for drum_id in 0..DRUM_COUNT {
+                assert!(player.trigger_drum(section, drum_id, 1.0, 0));
+                let channel = pad_channel(&player);
+                match player.rhythm_sections[section].instructions[drum_id] {
+                    DrumInstructions::Noise { .. } => assert!(channel >= 14),
+                    _ => assert!(channel < 14),
+                }
+                player.render_native(&mut data);
+            }
+        }
+
+        let volumes = |volume: f32| {
+            let mut player = Player::new(&psg_mio(&[]), &ram, 1.0).unwrap();
+            player.start_trace();
+            player.trigger_drum(0, 0, volume, 0);
+            player.render_native(&mut [0.0; 2000 * 2]);
+            player
+                .stop_trace()
+                .events
+                .iter()
+                .filter_map(|event| match event.call {
+                    SpuCall::SetChannelVolume { volume, .. } => Some(volume),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let (loud, quiet) = (volumes(1.0), volumes(0.5));
+        assert!(loud[0] > 0);
+        assert_eq!(quiet[0], loud[0] / 2);
+
+        // A sampled drum has nowhere to play once every channel is taken
+        let sampled = player.rhythm_sections[0]
+            .instructions
+            .iter()
+            .position(|drum| !matches!(drum, DrumInstructions::Noise { .. }))
+            .unwrap();
+        for channel in &mut player.channel_manager.channels {
+            *channel = Channel::Blocked;
+        }
+        assert!(!player.trigger_drum(0, sampled, 1.0, 0));
+    }
+
+    #[test]
+    fn test_custom_instruments() {
+        // A sine with a period of 64 samples, which plays at 64 samples a period on note 0
+        let sine: Vec<i16> = (0..640)
+            .map(|i| ((i as f32 / 64.0 * std::f32::consts::TAU).sin() * 16000.0) as i16)
+            .collect();
+        let sample_loop = Some(SampleLoop { start: 0, end: 639 });
+        let wav = encode_sample_wav(&sine, SAMPLE_RATE as u32, BASE_MIDI_NOTE, sample_loop);
+        let adsr = CustomAdsr {
+            volume: 1024,
+            attack: 0,
+            decay: 0,
+            sustain: 1024,
+            sustain_duration: SAMPLE_RATE,
+            release: 2000,
+        };
+        let custom =
+            CustomInstrument::from_wav("Sine", &wav, BASE_MIDI_NOTE, adsr, SampleFormat::Pcm16)
+                .unwrap();
+
+        let ram = fixtures::zeroed_ram();
+        let mut player = Player::new(&psg_mio(&[]), &ram, 1.0).unwrap();
+        let index = player.add_custom_instrument(&custom).unwrap();
+        assert_eq!(index, instrument_instructions().len());
+
+        player.start_trace();
+        assert!(player.preview_note(index, 0, 4000));
+        let mut data = vec![0.0; 4000 * 2];
+        player.render_native(&mut data);
+        let calls: Vec<SpuCall> = player
+            .stop_trace()
+            .events
+            .iter()
+            .map(|event| event.call)
+            .collect();
+        assert!(calls.iter().any(|call| matches!(
+            call,
+            SpuCall::SetChannelSrcAddress { src_address, .. }
+                if *src_address == SAMPLE_SPACE_ADDRESS
+        )));
+        assert!(calls.iter().any(|call| matches!(
+            call,
+            SpuCall::ChannelPlaySample {
+                is_repeating: true,
+                format: SampleFormat::Pcm16,
+                ..
+            }
+        )));
+
+        // Rising through zero once a period, once the output latency has passed
+        let left: Vec<f32> = data.chunks(2).map(|frame| frame[0]).collect();
+        let rising: Vec<usize> = (1..left.len())
+            .filter(|&i| left[i - 1] < 0.0 && left[i] >= 0.0)
+            .collect();
+        assert!(rising.len() > 20);
+        assert!(rising.windows(2).all(|pair| pair[1] - pair[0] == 64));
+
+        // The song's instrument swapped out, loaded after the first sample
+        let mut song = Player::new(&psg_mio(&[(0, 12)]), &ram, 1.0).unwrap();
+        song.add_custom_instrument(&custom).unwrap();
+        let index = song.add_custom_instrument(&custom).unwrap();
+        assert!(song.substitute_instrument(40, index));
+        assert!(!song.substitute_instrument(40, index + 1));
+        let calls = traced(&mut song, 1000);
+        assert!(calls.iter().any(|(_, call)| matches!(
+            call,
+            SpuCall::SetChannelSrcAddress { src_address, .. }
+                if *src_address == SAMPLE_SPACE_ADDRESS + 1280
+        )));
+        song.reset_instruments();
+        assert_eq!(song.instruments.len(), index - 1);
+    }
+}
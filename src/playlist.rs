@@ -0,0 +1,364 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    audio::SAMPLE_RATE,
+    bus::{BusError, MainRam, MemoryRegion, RamRegion, SharedRegion},
+    log::{self, Event},
+    player::{Player, CAPTURE_BUFFER_ADDRESSES, CAPTURE_BUFFER_SIZE},
+    renderer::RenderStats,
+    resample::Resampler,
+    sample_bank::SampleBank,
+    spu::Nds,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    pub mio_data: Vec<u8>,
+    /// Times through the song before moving on, at least once
+    pub loop_count: usize,
+}
+
+/// Plays a queue of songs back to back on the same RAM. Without a crossfade each song
+/// starts on the sample the previous one ends, on the same SPU, with the last notes'
+/// releases ringing into it. With one, the next song starts on a second player that fades
+/// in while the first fades out, with the first one's settings.
+pub struct Playlist {
+    memory: Arc<dyn MemoryRegion + Sync>,
+    my_volume: f32,
+    queue: VecDeque<PlaylistEntry>,
+    /// Made when first needed, the second one only for crossfades
+    decks: Vec<Player>,
+    current: usize,
+    /// The deck fading out, how far it's faded and over how many samples
+    outgoing: Option<(usize, usize, usize)>,
+    /// Samples at `SAMPLE_RATE`
+    crossfade: usize,
+    songs_started: usize,
+    on_song_start: Option<Box<dyn FnMut(usize) + Send>>,
+    resampler: Option<Resampler>,
+    /// Where the outgoing deck renders while crossfading
+    scratch: Vec<f32>,
+    /// Kept by whichever `Renderer` plays this
+    pub render_stats: RenderStats,
+}
+
+impl Playlist {
+    pub fn new(ram: Vec<u8>, my_volume: f32) -> Result<Playlist, BusError> {
+        Ok(Playlist::with_memory(
+            Arc::new(MainRam::new(ram)?),
+            my_volume,
+        ))
+    }
+
+    pub fn from_sample_bank(sample_bank: SampleBank, my_volume: f32) -> Playlist {
+        Playlist::with_memory(Arc::new(sample_bank), my_volume)
+    }
+
+    fn with_memory(memory: Arc<dyn MemoryRegion + Sync>, my_volume: f32) -> Playlist {
+        Playlist {
+            memory,
+            my_volume,
+            queue: VecDeque::new(),
+            decks: Vec::new(),
+            current: 0,
+            outgoing: None,
+            crossfade: 0,
+            songs_started: 0,
+            on_song_start: None,
+            resampler: None,
+            scratch: Vec::new(),
+            render_stats: RenderStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, mio_data: &[u8], loop_count: usize) {
+        self.queue.push_back(PlaylistEntry {
+            mio_data: mio_data.to_vec(),
+            loop_count: loop_count.max(1),
+        });
+    }
+
+    pub fn queue(&self) -> &VecDeque<PlaylistEntry> {
+        &self.queue
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Samples at `SAMPLE_RATE` each song fades into the next over, 0 for gapless
+    pub fn set_crossfade(&mut self, samples: usize) {
+        self.crossfade = samples;
+    }
+
+    pub fn crossfade(&self) -> usize {
+        self.crossfade
+    }
+
+    /// Called with the song's index, counting every song the playlist has started, on
+    /// whichever thread renders. `Event::SongStarted` is logged as well.
+    pub fn set_on_song_start(&mut self, callback: Option<Box<dyn FnMut(usize) + Send>>) {
+        self.on_song_start = callback;
+    }
+
+    /// The index of the song playing, None before the first one starts
+    pub fn song_index(&self) -> Option<usize> {
+        self.songs_started.checked_sub(1)
+    }
+
+    /// The player of the song playing, for the same live changes as a single song
+    pub fn player(&mut self) -> Option<&mut Player> {
+        self.decks.get_mut(self.current)
+    }
+
+    pub fn set_output_rate(&mut self, sample_rate: u32) {
+        self.resampler = if sample_rate == SAMPLE_RATE as u32 {
+            None
+        } else {
+            Some(Resampler::new(SAMPLE_RATE as u32, sample_rate, 2))
+        };
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.resampler
+            .as_ref()
+            .map(|resampler| resampler.output_rate())
+            .unwrap_or(SAMPLE_RATE as u32)
+    }
+
+    /// A bus reading from the shared RAM, with capture buffers of its own
+    fn deck(&self, mio_data: &[u8]) -> Player {
+        let mut nds = Nds::empty();
+        nds.map_region(Box::new(SharedRegion::new(self.memory.clone())));
+        for &address in &CAPTURE_BUFFER_ADDRESSES {
+            let mut buffer = RamRegion::new(address, CAPTURE_BUFFER_SIZE);
+            for offset in (0..CAPTURE_BUFFER_SIZE).step_by(4) {
+                let address = (address + offset) as u32;
+                if let Some(val) = self.memory.read32(address) {
+                    buffer.write32(address, val);
+                }
+            }
+            nds.map_region(Box::new(buffer));
+        }
+        Player::with_nds(mio_data, nds, self.my_volume)
+    }
+
+    /// Samples left of the current song, None when nothing is playing
+    fn remaining(&self) -> Option<usize> {
+        let player = self.decks.get(self.current)?;
+        let length = player.record.length()?;
+        Some(length.saturating_sub(player.timing.tiny_tick))
+    }
+
+    /// Starts the next song on `deck`, cutting off whatever it was playing
+    fn start_next(&mut self, deck: usize) {
+        let entry = match self.queue.pop_front() {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if deck < self.decks.len() {
+            let player = &mut self.decks[deck];
+            if deck != self.current {
+                player.stop_sounds();
+            }
+            player.load_song(&entry.mio_data);
+        } else {
+            let player = self.deck(&entry.mio_data);
+            self.decks.push(player);
+        }
+        // Whatever was changed on the song playing carries on into the next one
+        if deck != self.current {
+            let (first, second) = self.decks.split_at_mut(1);
+            let (player, from) = if deck == 0 {
+                (&mut first[0], &second[0])
+            } else {
+                (&mut second[0], &first[0])
+            };
+            player.copy_settings(from);
+        }
+        self.decks[deck].set_loop_count(Some(entry.loop_count));
+        self.current = deck;
+
+        let index = self.songs_started;
+        self.songs_started += 1;
+        log::log(Event::SongStarted { index });
+        if let Some(callback) = &mut self.on_song_start {
+            callback(index);
+        }
+    }
+
+    /// Fills interleaved stereo `data` at the output rate
+    pub fn render(&mut self, data: &mut [f32]) {
+        match self.resampler.take() {
+            Some(mut resampler) => {
+                resampler.render(data, |native| self.render_native(native));
+                self.resampler = Some(resampler);
+            }
+            None => self.render_native(data),
+        }
+    }
+
+    /// Fills interleaved stereo `data` at `SAMPLE_RATE`, split wherever a song starts so
+    /// it starts on the exact sample
+    pub fn render_native(&mut self, data: &mut [f32]) {
+        let mut position = 0;
+        while position < data.len() {
+            let frames_left = (data.len() - position) / 2;
+            let frames = match (self.remaining(), self.outgoing) {
+                (None, _) => {
+                    if self.queue.is_empty() {
+                        data[position..].fill(0.0);
+                        return;
+                    }
+                    self.start_next(0);
+                    continue;
+                }
+                (Some(_), Some((_, faded, length))) => frames_left.min(length - faded),
+                (Some(remaining), None) if !self.queue.is_empty() => {
+                    if remaining == 0 {
+                        self.start_next(self.current);
+                        continue;
+                    }
+                    if remaining <= self.crossfade {
+                        let fading = self.current;
+                        self.start_next(1 - fading);
+                        self.outgoing = Some((fading, 0, remaining));
+                        continue;
+                    }
+                    frames_left.min(remaining - self.crossfade)
+                }
+                (Some(_), None) => frames_left,
+            };
+
+            let block = &mut data[position..position + frames * 2];
+            self.decks[self.current].render_native(block);
+            if let Some((deck, faded, length)) = self.outgoing {
+                self.scratch.resize(block.len(), 0.0);
+                self.decks[deck].render_native(&mut self.scratch);
+                for (i, (frame, outgoing)) in
+                    block.chunks_mut(2).zip(self.scratch.chunks(2)).enumerate()
+                {
+                    // Equal power, so the overlap doesn't dip in loudness
+                    let fade_in = (faded + i) as f32 / length as f32;
+                    let (gain_in, gain_out) = (fade_in.sqrt(), (1.0 - fade_in).sqrt());
+                    for (sample, outgoing) in frame.iter_mut().zip(outgoing) {
+                        *sample = *sample * gain_in + outgoing * gain_out;
+                    }
+                }
+                self.outgoing = if faded + frames < length {
+                    Some((deck, faded + frames, length))
+                } else {
+                    None
+                };
+            }
+            position += frames * 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        echo::Echo,
+        fixtures,
+        spu::{AudioBitDepth, AudioInterpolation},
+    };
+    use std::sync::Mutex;
+
+    fn song(note: u8) -> Vec<u8> {
+        fixtures::song(40, &[(0, note)])
+    }
+
+    #[test]
+    fn test_playlist_transitions() {
+        let ram = fixtures::zeroed_ram();
+        let length = {
+            let mut player = Player::new(&song(12), &ram, 1.0).unwrap();
+            player.set_loop_count(Some(2));
+            player.record.length().unwrap()
+        };
+
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let playlist = |crossfade| {
+            let mut playlist = Playlist::new(ram.clone(), 1.0).unwrap();
+            playlist.push(&song(12), 2);
+            playlist.push(&song(24), 1);
+            playlist.set_crossfade(crossfade);
+            let started = started.clone();
+            playlist.set_on_song_start(Some(Box::new(move |index| {
+                started.lock().unwrap().push(index)
+            })));
+            playlist
+        };
+
+        // Gapless, the first song plays exactly like on its own
+        let mut expected = vec![0.0; length * 2];
+        let mut player = Player::new(&song(12), &ram, 1.0).unwrap();
+        player.set_loop_count(Some(2));
+        player.render_native(&mut expected);
+
+        let mut gapless = playlist(0);
+        let mut data = vec![0.0; length * 2];
+        gapless.render_native(&mut data);
+        assert_eq!(data, expected);
+        assert_eq!(*started.lock().unwrap(), [0]);
+        gapless.render_native(&mut data[..2]);
+        assert_eq!(*started.lock().unwrap(), [0, 1]);
+        assert_eq!(gapless.song_index(), Some(1));
+        assert_eq!(gapless.decks.len(), 1);
+
+        started.lock().unwrap().clear();
+        let mut crossfaded = playlist(4000);
+        let mut data = vec![0.0; (length - 4000) * 2];
+        crossfaded.render_native(&mut data);
+        assert_eq!(data, expected[..data.len()]);
+        assert_eq!(*started.lock().unwrap(), [0]);
+        let mut data = vec![0.0; 8000 * 2];
+        crossfaded.render_native(&mut data);
+        assert_eq!(*started.lock().unwrap(), [0, 1]);
+        assert_eq!(crossfaded.decks.len(), 2);
+        assert!(crossfaded.outgoing.is_none());
+        assert!(data[8000..].iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn test_crossfade_keeps_settings() {
+        let mut playlist = Playlist::new(fixtures::zeroed_ram(), 1.0).unwrap();
+        playlist.push(&song(12), 1);
+        playlist.push(&song(24), 1);
+        playlist.set_crossfade(4000);
+
+        let mut data = vec![0.0; 2000 * 2];
+        playlist.render_native(&mut data);
+        let player = playlist.player().unwrap();
+        player.set_track_volume(0, 0.25);
+        player.set_track_pan(1, -30);
+        player.set_master_volume(0.5);
+        player.set_transpose(2);
+        player.set_tempo(150);
+        player.set_bit_depth(AudioBitDepth::_10bit);
+        player.set_interpolation(AudioInterpolation::Cubic);
+        let echo = Echo {
+            enabled: true,
+            feedback: 64,
+            ..Echo::default()
+        };
+        player.set_echo(echo);
+
+        while playlist.song_index() == Some(0) {
+            playlist.render_native(&mut data);
+        }
+        assert_eq!(playlist.current, 1);
+        let player = playlist.player().unwrap();
+        assert_eq!(player.mixer.track_volume(0), 0.25);
+        assert_eq!(player.mixer.track_pan(1), -30);
+        assert_eq!(player.mixer.master_volume(), 0.5);
+        assert_eq!(player.transpose(), 2);
+        assert_eq!(player.tempo(), 150);
+        assert_eq!(player.bit_depth(), AudioBitDepth::_10bit);
+        assert_eq!(player.interpolation(), AudioInterpolation::Cubic);
+        assert_eq!(player.echo(), echo);
+    }
+}
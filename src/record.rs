@@ -1,4 +1,7 @@
-use crate::audio::{QueuedDrum, QueuedNote, NOTE_RATE, SAMPLE_RATE, TRACK_LENGTH};
+use crate::{
+    audio::{QueuedDrum, QueuedNote, NOTE_RATE, SAMPLE_RATE, TRACK_LENGTH},
+    log::{self, Event},
+};
 
 const TRACK_COUNT: usize = 4;
 const SIMULTANEOUS_DRUMS: usize = 4;
@@ -8,6 +11,8 @@ pub enum Repeats {
     None,
     Once,
     Endless,
+    /// Plays the song this many times, set with `Record::loop_count`
+    Times(usize),
 }
 
 impl Repeats {
@@ -16,6 +21,7 @@ impl Repeats {
             Repeats::None => Some(0),
             Repeats::Once => Some(1),
             Repeats::Endless => None,
+            Repeats::Times(times) => Some(times.max(1) - 1),
         }
     }
 
@@ -24,6 +30,7 @@ impl Repeats {
             Repeats::None => Some(1),
             Repeats::Once => Some(2),
             Repeats::Endless => None,
+            Repeats::Times(times) => Some(times.max(1)),
         }
     }
 }
@@ -32,12 +39,93 @@ pub struct Record {
     pub notes: Vec<QueuedNote>,
     pub drums: Vec<QueuedDrum>,
     pub repeats: Repeats,
+    /// Overrides `repeats` when set, see `playback_repeats`
+    pub loop_count: Option<usize>,
+    /// Fades the output out over the final loop
+    pub fade_out: bool,
     pub phrase_count: usize,
+    /// Beats per minute, what `note_rate` and `swing_offset` are worked out from
+    pub tempo: u32,
     pub note_rate: usize,
     pub swing_offset: Option<usize>,
+    /// Switched to by `play_stuff` at the start of the next step, see `request_tempo`
+    pub pending_tempo: Option<u32>,
+    /// Semitones every melodic note is shifted by, drums are left alone
+    pub transpose: i32,
+}
+
+/// Game songs don't store a tempo, they all play at this one
+const GAME_TEMPO: u32 = 120;
+pub const MIN_TEMPO: u32 = 20;
+pub const MAX_TEMPO: u32 = 960;
+pub const MAX_TRANSPOSE: i32 = 24;
+
+/// Samples per step, and how much swing delays every other step
+fn note_rate(tempo: u32, is_swing: bool) -> (usize, Option<usize>) {
+    let tempo_rate = 120.0 / tempo as f32;
+    let adjusted_rate = tempo_rate / 8.0;
+
+    let note_rate = ((SAMPLE_RATE as f32) * adjusted_rate) as usize;
+    let swing_jump: usize = note_rate / 3;
+
+    (note_rate, if is_swing { Some(swing_jump) } else { None })
 }
 
 impl Record {
+    /// Samples until every track is released, or None when the song loops forever
+    pub fn length(&self) -> Option<usize> {
+        self.playback_repeats()
+            .loop_times()
+            .map(|loop_times| loop_times * self.loop_length())
+    }
+
+    /// Samples in one time through the song
+    pub fn loop_length(&self) -> usize {
+        TRACK_LENGTH * self.phrase_count * self.note_rate
+    }
+
+    /// How the song repeats, which `loop_count` can make bounded for endless songs
+    pub fn playback_repeats(&self) -> Repeats {
+        match self.loop_count {
+            Some(times) => Repeats::Times(times),
+            None => self.repeats,
+        }
+    }
+
+    /// Output gain at `tiny_tick`, ramping down to silence over the final loop
+    /// when `fade_out` is set and the song ends
+    pub fn fade(&self, tiny_tick: usize) -> f32 {
+        match self.length() {
+            Some(length) if self.fade_out => {
+                let fade_start = length - self.loop_length();
+                if tiny_tick <= fade_start {
+                    1.0
+                } else {
+                    let faded = (tiny_tick - fade_start) as f32 / self.loop_length() as f32;
+                    1.0 - faded.min(1.0)
+                }
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Changes `note_rate` and `swing_offset` right away. While playing, use `request_tempo`
+    /// so notes already sounding and the song's place aren't thrown off.
+    pub fn set_tempo(&mut self, tempo: u32) {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+        let (note_rate, swing_offset) = note_rate(self.tempo, self.swing_offset.is_some());
+        self.note_rate = note_rate;
+        self.swing_offset = swing_offset;
+    }
+
+    pub fn request_tempo(&mut self, tempo: u32) {
+        self.pending_tempo = Some(tempo.clamp(MIN_TEMPO, MAX_TEMPO));
+    }
+
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+    }
+
     pub fn from_mio(mio_data: &[u8]) -> Record {
         match mio_data.len() {
             8192 => Self::from_record(mio_data),
@@ -130,7 +218,7 @@ impl Record {
                     queued_notes.push(QueuedNote {
                         time: i as u32,
                         instrument: instrument_used as u32,
-                        note: note,
+                        note,
                         track: track_index as u8,
                         volume_multiplier,
                         pan_addition,
@@ -143,9 +231,14 @@ impl Record {
             notes: queued_notes,
             drums: queued_drums,
             repeats,
+            loop_count: None,
+            fade_out: false,
             phrase_count: 1,
+            tempo: GAME_TEMPO,
             note_rate: NOTE_RATE,
             swing_offset: None,
+            pending_tempo: None,
+            transpose: 0,
         }
     }
 
@@ -164,14 +257,7 @@ impl Record {
 
         let tempo: u32 = mio_data[TEMPO_OFFSET] as u32 * 10 + 60;
         let is_swing = mio_data[SWING_OFFSET] != 0;
-        let tempo_rate = 120.0 / tempo as f32;
-        let adjusted_rate = tempo_rate / 8.0;
-        println!("TEMPO: {:?}, {}, {}", tempo, tempo_rate, adjusted_rate);
-
-        let note_rate = ((SAMPLE_RATE as f32) * adjusted_rate) as usize;
-        let swing_jump: usize = note_rate / 3;
-
-        let swing_offset = if is_swing { Some(swing_jump) } else { None };
+        let (note_rate, swing_offset) = note_rate(tempo, is_swing);
 
         let mut queued_drums = Vec::new();
 
@@ -255,7 +341,7 @@ impl Record {
                         queued_notes.push(QueuedNote {
                             time: (32 * segment_index) as u32 + i as u32,
                             instrument: instrument_used as u32,
-                            note: note,
+                            note,
                             track: track_index as u8,
                             volume_multiplier,
                             pan_addition,
@@ -265,15 +351,24 @@ impl Record {
             }
         }
 
-        println!("NOTE RATE: {}", note_rate);
+        log::log(Event::Tempo {
+            tempo,
+            swing: is_swing,
+            note_rate,
+        });
 
         Record {
             notes: queued_notes,
             drums: queued_drums,
             repeats: Repeats::None,
+            loop_count: None,
+            fade_out: false,
             phrase_count: mio_data[END_INDEX] as usize,
+            tempo,
             note_rate,
             swing_offset,
+            pending_tempo: None,
+            transpose: 0,
         }
     }
 }
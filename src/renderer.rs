@@ -0,0 +1,347 @@
+use std::sync::{Arc, Mutex};
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    log::{self, Event},
+    player::Player,
+    playlist::Playlist,
+};
+
+/// What `play_music` has always used, about 250ms at `SAMPLE_RATE`
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 8;
+/// Below this the callback overhead alone risks underruns
+pub const MIN_BUFFER_SIZE: usize = 64;
+
+/// How much output the audio device asks for at a time. Smaller buffers make live changes
+/// like `Player::set_track_volume` heard sooner, but leave less slack before an underrun.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputBuffer {
+    Frames(usize),
+    /// Milliseconds, converted to frames at the output rate
+    Latency(f32),
+}
+
+impl Default for OutputBuffer {
+    fn default() -> Self {
+        OutputBuffer::Frames(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+impl OutputBuffer {
+    pub fn frames(self, sample_rate: u32) -> usize {
+        let frames = match self {
+            OutputBuffer::Frames(frames) => frames,
+            OutputBuffer::Latency(latency) => {
+                (latency.max(0.0) / 1000.0 * sample_rate as f32).ceil() as usize
+            }
+        };
+        frames.max(MIN_BUFFER_SIZE)
+    }
+
+    /// Milliseconds
+    pub fn latency(self, sample_rate: u32) -> f32 {
+        self.frames(sample_rate) as f32 * 1000.0 / sample_rate as f32
+    }
+}
+
+/// How well rendering keeps up. An underrun is a block that took longer to render than it
+/// lasts, which the device can only cover by repeating or dropping audio. A few at startup
+/// are normal. A steady count means the buffer is too small for the machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderStats {
+    pub blocks: u64,
+    pub underruns: u64,
+    /// Slowest block's render time over its duration, past 1.0 is an underrun
+    pub worst_load: f64,
+}
+
+impl RenderStats {
+    fn record(&mut self, render_time: f64, duration: f64) {
+        self.blocks += 1;
+        let load = render_time / duration;
+        self.worst_load = self.worst_load.max(load);
+        if load > 1.0 {
+            self.underruns += 1;
+            log::log(Event::Underrun {
+                render_ms: (render_time * 1000.0) as f32,
+                buffer_ms: (duration * 1000.0) as f32,
+            });
+        }
+    }
+}
+
+/// Seconds since some fixed point, only ever compared with itself
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> f64 {
+    use std::{sync::OnceLock, time::Instant};
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs_f64()
+}
+
+/// `Date.now` is there in AudioWorkletGlobalScope too, unlike `performance`
+#[cfg(target_arch = "wasm32")]
+fn now() -> f64 {
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = Date, js_name = now)]
+        fn date_now() -> f64;
+    }
+
+    date_now() / 1000.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Left and right samples alternate
+    Interleaved,
+    /// The block's left samples followed by its right ones, like Web Audio's channel data
+    Planar,
+}
+
+/// What a `Renderer` can play
+trait Render {
+    /// Fills interleaved stereo `data` at the output rate
+    fn render(&mut self, data: &mut [f32]);
+    fn output_rate(&self) -> u32;
+    fn render_stats(&mut self) -> &mut RenderStats;
+}
+
+impl Render for Player {
+    fn render(&mut self, data: &mut [f32]) {
+        Player::render(self, data);
+    }
+
+    fn output_rate(&self) -> u32 {
+        Player::output_rate(self)
+    }
+
+    fn render_stats(&mut self) -> &mut RenderStats {
+        &mut self.render_stats
+    }
+}
+
+impl Render for Playlist {
+    fn render(&mut self, data: &mut [f32]) {
+        Playlist::render(self, data);
+    }
+
+    fn output_rate(&self) -> u32 {
+        Playlist::output_rate(self)
+    }
+
+    fn render_stats(&mut self) -> &mut RenderStats {
+        &mut self.render_stats
+    }
+}
+
+enum Source {
+    Player(Arc<Mutex<Player>>),
+    Playlist(Arc<Mutex<Playlist>>),
+}
+
+/// Renders one block of `source` in `layout`, keeping its stats
+fn render_block(
+    source: &mut impl Render,
+    layout: Layout,
+    interleaved: &mut Vec<f32>,
+    data: &mut [f32],
+) {
+    let start = now();
+    match layout {
+        Layout::Interleaved => source.render(data),
+        Layout::Planar => {
+            let frames = data.len() / 2;
+            interleaved.resize(frames * 2, 0.0);
+            source.render(interleaved);
+
+            let (left, right) = data.split_at_mut(frames);
+            for (frame, (left, right)) in interleaved
+                .chunks(2)
+                .zip(left.iter_mut().zip(right.iter_mut()))
+            {
+                *left = frame[0];
+                *right = frame[1];
+            }
+        }
+    }
+
+    let duration = (data.len() / 2) as f64 / source.output_rate() as f64;
+    source.render_stats().record(now() - start, duration);
+}
+
+/// Pull-based output for hosts that own the audio device, like an AudioWorkletProcessor,
+/// a game engine or a test harness. Each `render` fills the next block of stereo output,
+/// from a single song or a playlist.
+#[wasm_bindgen]
+pub struct Renderer {
+    source: Source,
+    layout: Layout,
+    block_size: usize,
+    /// Where planar blocks are rendered before being split into their channels
+    interleaved: Vec<f32>,
+}
+
+impl Renderer {
+    /// `block_size` is the frames each `render` call is expected to ask for, other sizes
+    /// work but have to allocate
+    pub fn new(
+        mut player: Player,
+        sample_rate: u32,
+        block_size: usize,
+        layout: Layout,
+    ) -> Renderer {
+        player.set_output_rate(sample_rate);
+        Renderer::with_source(
+            Source::Player(Arc::new(Mutex::new(player))),
+            block_size,
+            layout,
+        )
+    }
+
+    pub fn from_playlist(
+        mut playlist: Playlist,
+        sample_rate: u32,
+        block_size: usize,
+        layout: Layout,
+    ) -> Renderer {
+        playlist.set_output_rate(sample_rate);
+        Renderer::with_source(
+            Source::Playlist(Arc::new(Mutex::new(playlist))),
+            block_size,
+            layout,
+        )
+    }
+
+    fn with_source(source: Source, block_size: usize, layout: Layout) -> Renderer {
+        Renderer {
+            source,
+            layout,
+            block_size,
+            interleaved: match layout {
+                Layout::Interleaved => Vec::new(),
+                Layout::Planar => vec![0.0; block_size * 2],
+            },
+        }
+    }
+
+    /// For changing playback from another thread while this one renders, None when
+    /// playing a playlist
+    pub fn player(&self) -> Option<Arc<Mutex<Player>>> {
+        match &self.source {
+            Source::Player(player) => Some(player.clone()),
+            Source::Playlist(_) => None,
+        }
+    }
+
+    /// Like `player`, None when playing a single song
+    pub fn playlist(&self) -> Option<Arc<Mutex<Playlist>>> {
+        match &self.source {
+            Source::Player(_) => None,
+            Source::Playlist(playlist) => Some(playlist.clone()),
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+#[wasm_bindgen]
+impl Renderer {
+    /// Fills `data` with the next `data.len() / 2` frames
+    pub fn render(&mut self, data: &mut [f32]) {
+        let interleaved = &mut self.interleaved;
+        match &self.source {
+            Source::Player(player) => {
+                render_block(&mut *player.lock().unwrap(), self.layout, interleaved, data)
+            }
+            Source::Playlist(playlist) => render_block(
+                &mut *playlist.lock().unwrap(),
+                self.layout,
+                interleaved,
+                data,
+            ),
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn sample_rate(&self) -> u32 {
+        match &self.source {
+            Source::Player(player) => player.lock().unwrap().output_rate(),
+            Source::Playlist(playlist) => playlist.lock().unwrap().output_rate(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_output_buffer() {
+        assert_eq!(OutputBuffer::default().frames(32824), DEFAULT_BUFFER_SIZE);
+        assert_eq!(OutputBuffer::Latency(20.0).frames(48000), 960);
+        assert_eq!(OutputBuffer::Latency(0.0).frames(48000), MIN_BUFFER_SIZE);
+
+        let mut stats = RenderStats::default();
+        stats.record(0.001, 0.01);
+        stats.record(0.02, 0.01);
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.underruns, 1);
+        assert_eq!(stats.worst_load, 2.0);
+    }
+
+    #[test]
+    fn test_planar_matches_interleaved() {
+        let ram = fixtures::zeroed_ram();
+        let mut mio = fixtures::song(40, &[(0, 12)]);
+        fixtures::set_pan(&mut mio, 0, 0);
+
+        let renderer =
+            |layout| Renderer::new(Player::new(&mio, &ram, 1.0).unwrap(), 48000, 128, layout);
+        let mut interleaved = renderer(Layout::Interleaved);
+        let mut planar = renderer(Layout::Planar);
+        assert_eq!(planar.sample_rate(), 48000);
+
+        let mut expected = vec![0.0; 256];
+        let mut block = vec![0.0; 256];
+        let mut heard = false;
+        for _ in 0..100 {
+            interleaved.render(&mut expected);
+            planar.render(&mut block);
+            for (i, frame) in expected.chunks(2).enumerate() {
+                assert_eq!(frame, [block[i], block[128 + i]]);
+            }
+            heard |= block.iter().any(|&sample| sample != 0.0);
+        }
+        assert!(heard);
+        assert_eq!(
+            planar.player().unwrap().lock().unwrap().render_stats.blocks,
+            100
+        );
+    }
+
+    #[test]
+    fn test_playlist_render_stats() {
+        let mut playlist = Playlist::new(fixtures::zeroed_ram(), 1.0).unwrap();
+        playlist.push(&fixtures::song(40, &[(0, 12)]), 1);
+
+        let mut renderer = Renderer::from_playlist(playlist, 48000, 128, Layout::Interleaved);
+        assert!(renderer.player().is_none());
+        assert_eq!(renderer.sample_rate(), 48000);
+        let mut block = vec![0.0; 256];
+        for _ in 0..10 {
+            renderer.render(&mut block);
+        }
+        let playlist = renderer.playlist().unwrap();
+        assert_eq!(playlist.lock().unwrap().render_stats.blocks, 10);
+    }
+}
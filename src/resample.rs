@@ -0,0 +1,213 @@
+use std::f64::consts::PI;
+
+/// Zero crossings of the sinc on each side of the output position
+const HALF_TAPS: usize = 16;
+const TAPS: usize = HALF_TAPS * 2;
+/// Sub-sample positions the filter is precomputed at, the rest are linearly interpolated
+const PHASES: usize = 256;
+/// Frames requested from the source at a time when pulling
+const PULL_FRAMES: usize = 64;
+
+/// Band-limited (Blackman windowed sinc) resampler for interleaved audio
+pub struct Resampler {
+    channels: usize,
+    output_rate: u32,
+    step: f64,
+    position: f64,
+    history: Vec<f32>,
+    table: Vec<f32>,
+}
+
+impl Resampler {
+    /// Panics if either rate is 0
+    pub fn new(input_rate: u32, output_rate: u32, channels: usize) -> Resampler {
+        assert!(
+            input_rate > 0 && output_rate > 0,
+            "can't resample from {} Hz to {} Hz",
+            input_rate,
+            output_rate
+        );
+        let step = input_rate as f64 / output_rate as f64;
+        // Lower the cutoff below the output's nyquist frequency when downsampling
+        let cutoff = (output_rate as f64 / input_rate as f64).min(1.0) * 0.95;
+
+        let mut table = Vec::with_capacity((PHASES + 1) * TAPS);
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            let taps: Vec<f64> = (0..TAPS)
+                .map(|tap| {
+                    let distance = fraction + HALF_TAPS as f64 - 1.0 - tap as f64;
+                    windowed_sinc(distance, cutoff)
+                })
+                .collect();
+            let gain: f64 = taps.iter().sum();
+            table.extend(taps.iter().map(|tap| (tap / gain) as f32));
+        }
+
+        Resampler {
+            channels,
+            output_rate,
+            step,
+            // Start on the first real frame, after the zeroed history
+            position: HALF_TAPS as f64,
+            history: vec![0.0; HALF_TAPS * channels],
+            table,
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Frames of delay between input and output
+    pub fn latency(&self) -> usize {
+        HALF_TAPS
+    }
+
+    /// Resamples `input` and appends every output frame it makes available
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.history.extend_from_slice(input);
+
+        let mut frame = vec![0.0; self.channels];
+        while self.can_produce() {
+            self.produce(&mut frame);
+            output.extend_from_slice(&frame);
+        }
+    }
+
+    /// Pads the input with silence so the final frames make it out
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let silence = vec![0.0; (HALF_TAPS + 1) * self.channels];
+        self.process(&silence, output);
+    }
+
+    /// Fills `output` completely, pulling input from `source` as it's needed
+    pub fn render(&mut self, output: &mut [f32], mut source: impl FnMut(&mut [f32])) {
+        for frame in output.chunks_mut(self.channels) {
+            while !self.can_produce() {
+                let start = self.history.len();
+                self.history
+                    .resize(start + PULL_FRAMES * self.channels, 0.0);
+                source(&mut self.history[start..]);
+            }
+            self.produce(frame);
+        }
+    }
+
+    fn can_produce(&self) -> bool {
+        (self.position as usize + HALF_TAPS) < self.history.len() / self.channels
+    }
+
+    fn produce(&mut self, frame: &mut [f32]) {
+        let index = self.position as usize;
+        let phase = (self.position - index as f64) * PHASES as f64;
+        let phase_index = phase as usize;
+        let phase_fraction = (phase - phase_index as f64) as f32;
+
+        let taps_a = &self.table[phase_index * TAPS..(phase_index + 1) * TAPS];
+        let taps_b = &self.table[(phase_index + 1) * TAPS..(phase_index + 2) * TAPS];
+        let first = (index + 1 - HALF_TAPS) * self.channels;
+
+        for (channel, out) in frame.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for tap in 0..TAPS {
+                let coefficient = taps_a[tap] + (taps_b[tap] - taps_a[tap]) * phase_fraction;
+                sum += self.history[first + tap * self.channels + channel] * coefficient;
+            }
+            *out = sum;
+        }
+
+        self.position += self.step;
+
+        // Forget frames that have fallen out of the filter's reach
+        let consumed = self.position as usize + 1 - HALF_TAPS;
+        if consumed >= PULL_FRAMES {
+            self.history.drain(..consumed * self.channels);
+            self.position -= consumed as f64;
+        }
+    }
+}
+
+fn windowed_sinc(distance: f64, cutoff: f64) -> f64 {
+    let window_position = distance / HALF_TAPS as f64;
+    if window_position.abs() >= 1.0 {
+        return 0.0;
+    }
+    let window =
+        0.42 + 0.5 * (PI * window_position).cos() + 0.08 * (2.0 * PI * window_position).cos();
+
+    let x = distance * cutoff;
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    };
+
+    cutoff * sinc * window
+}
+
+/// Resamples a whole interleaved buffer at once
+pub fn resample(samples: &[f32], channels: usize, input_rate: u32, output_rate: u32) -> Vec<f32> {
+    if input_rate == output_rate {
+        return samples.to_vec();
+    }
+
+    let mut resampler = Resampler::new(input_rate, output_rate, channels);
+    let mut output = Vec::with_capacity(
+        (samples.len() as u64 * output_rate as u64 / input_rate as u64) as usize + channels,
+    );
+    resampler.process(samples, &mut output);
+    resampler.flush(&mut output);
+
+    let frame_count = (samples.len() / channels) as u64 * output_rate as u64 / input_rate as u64;
+    output.truncate(frame_count as usize * channels);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, sample_rate: u32, frame_count: usize) -> Vec<f32> {
+        (0..frame_count)
+            .map(|i| (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn test_resample_sine() {
+        let input = sine(1000.0, 32824, 32824);
+        let output = resample(&input, 1, 32824, 48000);
+        let expected = sine(1000.0, 48000, 48000);
+
+        assert_eq!(output.len(), expected.len());
+        // Skip the edges where the filter runs into silence
+        for i in 100..output.len() - 100 {
+            assert!((output[i] - expected[i]).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_render_matches_process() {
+        let input = sine(440.0, 32824, 4096);
+        let mut processed = Vec::new();
+        Resampler::new(32824, 44100, 1).process(&input, &mut processed);
+
+        let mut rendered = vec![0.0; processed.len()];
+        let mut position = 0;
+        Resampler::new(32824, 44100, 1).render(&mut rendered, |data| {
+            for sample in data.iter_mut() {
+                *sample = input.get(position).cloned().unwrap_or(0.0);
+                position += 1;
+            }
+        });
+
+        assert_eq!(processed, rendered);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_output_rate() {
+        Resampler::new(32824, 0, 2);
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::{self, Read, Write};
+
+use libflate::deflate::{Decoder, Encoder};
+use nanoserde::{DeBin, SerBin};
+
+use crate::{
+    bus::MAIN_RAM_MASK,
+    drums::drum_instructions,
+    ins::instrument_instructions,
+    player::{CAPTURE_BUFFER_ADDRESSES, CAPTURE_BUFFER_SIZE},
+    sample_export::{drum_regions, instrument_regions},
+};
+
+const MAGIC: &[u8; 4] = b"WSBK";
+const VERSION: u8 = 1;
+/// Bytes a channel's FIFO can buffer past the end of a sample
+const FIFO_READ_AHEAD: usize = 16;
+/// Regions closer together than this are stored as one
+const MERGE_GAP: usize = 64;
+
+/// A run of main RAM, `address` is masked to main RAM like `Nds` reads are
+#[derive(SerBin, DeBin, Debug, Clone, PartialEq, Eq)]
+pub struct BankRegion {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl BankRegion {
+    fn end(&self) -> usize {
+        self.address as usize + self.data.len()
+    }
+}
+
+/// The parts of main RAM the instrument and drum tables actually play from,
+/// with everything else left out
+#[derive(SerBin, DeBin, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SampleBank {
+    /// Sorted by address and never overlapping
+    regions: Vec<BankRegion>,
+}
+
+impl SampleBank {
+    /// Keeps every sample referenced by `instrument_instructions()` and `drum_instructions()`,
+    /// along with room for the capture buffers
+    pub fn from_ram(ram: &[u8]) -> SampleBank {
+        let mut ranges = Vec::new();
+
+        let regions = instrument_instructions()
+            .iter()
+            .flat_map(instrument_regions)
+            .chain(
+                drum_instructions()
+                    .iter()
+                    .flat_map(|section| section.instructions.iter().flat_map(drum_regions)),
+            )
+            .collect::<Vec<_>>();
+        for region in regions {
+            let start = region.src_address & MAIN_RAM_MASK as usize;
+            ranges.push((
+                start,
+                start + region.loop_pos + region.length + FIFO_READ_AHEAD,
+            ));
+        }
+
+        for address in CAPTURE_BUFFER_ADDRESSES {
+            let start = address & MAIN_RAM_MASK as usize;
+            ranges.push((start, start + CAPTURE_BUFFER_SIZE));
+        }
+
+        SampleBank::from_ranges(ram, ranges)
+    }
+
+    /// Copies the (start, end) byte ranges out of `ram`, merging any that touch
+    pub fn from_ranges(ram: &[u8], mut ranges: Vec<(usize, usize)>) -> SampleBank {
+        // Word align so reads never straddle a region's edge
+        for (start, end) in &mut ranges {
+            *start &= !0x3;
+            *end = (*end + 0x3) & !0x3;
+        }
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= *last_end + MERGE_GAP => {
+                    *last_end = (*last_end).max(end)
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let regions = merged
+            .into_iter()
+            .map(|(start, end)| {
+                let mut data = vec![0; end - start];
+                if start < ram.len() {
+                    let available = end.min(ram.len()) - start;
+                    data[..available].copy_from_slice(&ram[start..start + available]);
+                }
+                BankRegion {
+                    address: start as u32,
+                    data,
+                }
+            })
+            .collect();
+
+        SampleBank { regions }
+    }
+
+    pub fn regions(&self) -> &[BankRegion] {
+        &self.regions
+    }
+
+    /// Bytes of RAM the bank holds
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|region| region.data.len()).sum()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.write_all(&self.serialize_bin()).unwrap();
+        let compressed = encoder.finish().into_result().unwrap();
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + compressed.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&compressed);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<SampleBank> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a sample bank".to_string()));
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(invalid(format!(
+                "unsupported sample bank version {}",
+                bytes[MAGIC.len()]
+            )));
+        }
+
+        let mut serialized = Vec::new();
+        Decoder::new(&bytes[MAGIC.len() + 1..]).read_to_end(&mut serialized)?;
+        let bank = SampleBank::deserialize_bin(&serialized)
+            .map_err(|err| invalid(format!("corrupt sample bank: {:?}", err)))?;
+
+        let sorted = bank
+            .regions
+            .windows(2)
+            .all(|pair| pair[0].end() <= pair[1].address as usize);
+        if !sorted {
+            return Err(invalid("sample bank regions overlap".to_string()));
+        }
+
+        Ok(bank)
+    }
+
+    fn region_at(&self, address: usize) -> Option<(usize, usize)> {
+        let address = address & MAIN_RAM_MASK as usize;
+        let index = self
+            .regions
+            .partition_point(|region| region.end() <= address);
+        let region = self.regions.get(index)?;
+        if address >= region.address as usize {
+            Some((index, address - region.address as usize))
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.region_at(address).is_some()
+    }
+
+    /// Reads outside of the bank's regions are 0
+    pub fn read32(&self, address: usize) -> u32 {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            if let Some((index, offset)) = self.region_at(address + i) {
+                *byte = self.regions[index].data[offset];
+            }
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Writes outside of the bank's regions are dropped
+    pub fn write32(&mut self, address: usize, val: u32) {
+        for (i, byte) in val.to_le_bytes().iter().enumerate() {
+            if let Some((index, offset)) = self.region_at(address + i) {
+                self.regions[index].data[offset] = *byte;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, player::Player};
+
+    #[test]
+    fn test_sample_bank_plays_like_ram() {
+        let ram = fixtures::patterned_ram();
+        // Piano on the first track, and the first rhythm section's kick
+        let mut mio = fixtures::song(0, &[(0, 12)]);
+        fixtures::set_instrument(&mut mio, fixtures::DRUM_TRACK, 0);
+        fixtures::set_drum(&mut mio, 0, 0, 0);
+
+        let mut from_ram = Player::new(&mio, &ram, 1.0).unwrap();
+        let mut from_bank = Player::from_sample_bank(&mio, SampleBank::from_ram(&ram), 1.0);
+        let (mut expected, mut output) = (vec![0.0; 8192], vec![0.0; 8192]);
+        for _ in 0..4 {
+            from_ram.render_native(&mut expected);
+            from_bank.render_native(&mut output);
+            assert_eq!(expected, output);
+        }
+        assert!(expected.iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn test_sample_bank_round_trip() {
+        let ram: Vec<u8> = (0..0x4000).map(|i| (i * 7) as u8).collect();
+        let bank =
+            SampleBank::from_ranges(&ram, vec![(0x100, 0x180), (0x1C0, 0x200), (0x2000, 0x2002)]);
+
+        // The first two are close enough to merge
+        assert_eq!(bank.regions().len(), 2);
+        assert_eq!(
+            bank.read32(0x02000104),
+            u32::from_le_bytes([28, 35, 42, 49])
+        );
+        assert_eq!(bank.read32(0x1000), 0);
+
+        let decoded = SampleBank::decode(&bank.encode()).unwrap();
+        assert_eq!(decoded, bank);
+        for address in (0x2000..0x2004).step_by(4) {
+            assert_eq!(
+                decoded.read32(address),
+                u32::from_le_bytes([
+                    ram[address],
+                    ram[address + 1],
+                    ram[address + 2],
+                    ram[address + 3]
+                ])
+            );
+        }
+    }
+}
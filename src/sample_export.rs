@@ -0,0 +1,274 @@
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    audio::*,
+    bus::BusError,
+    drums::drum_instructions,
+    ins::instrument_instructions,
+    spu::{decode_sample, Nds},
+    wav::{encode_sample_wav, SampleLoop},
+};
+
+pub use crate::spu::SampleFormat;
+
+// `Spu::channel_play_note` keys every sample on as ADPCM
+const SAMPLE_FORMAT: SampleFormat = SampleFormat::Adpcm;
+
+pub struct ExportedSample {
+    pub name: String,
+    pub wav: Vec<u8>,
+}
+
+/// A sample region in RAM along with how it's played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleRegion {
+    pub format: SampleFormat,
+    pub src_address: usize,
+    pub loop_pos: usize,
+    pub length: usize,
+    pub timer_reload: u16,
+    pub is_repeating: bool,
+    pub unity_note: u8,
+}
+
+/// How an instrument plays `sample`, with `unity_note` as the note it plays at its base pitch
+pub fn sample_region(sample: &Sample, unity_note: u8) -> SampleRegion {
+    SampleRegion {
+        format: SAMPLE_FORMAT,
+        src_address: sample.src_address,
+        loop_pos: sample.loop_pos,
+        length: sample.length,
+        timer_reload: sample.base_timer_reload,
+        is_repeating: sample.is_repeating,
+        unity_note,
+    }
+}
+
+pub fn drum_sample_region(sample: &DrumSample) -> SampleRegion {
+    SampleRegion {
+        format: SAMPLE_FORMAT,
+        src_address: sample.src_address,
+        loop_pos: sample.loop_pos,
+        length: sample.length,
+        timer_reload: sample.timer_reload,
+        is_repeating: sample.is_repeating,
+        unity_note: BASE_MIDI_NOTE,
+    }
+}
+
+/// Every RAM sample used by an instrument, the root note of ranged samples is their lowest note
+pub fn instrument_regions(instrument: &Instrument) -> Vec<SampleRegion> {
+    let mut regions: Vec<SampleRegion> = Vec::new();
+    for (adsr, low, _) in instrument.instructions.adsrs() {
+        if let InstrumentSample::PCM16(sample) = &adsr.sample {
+            let region = sample_region(sample, BASE_MIDI_NOTE + low);
+            if !regions.contains(&region) {
+                regions.push(region);
+            }
+        }
+    }
+    regions
+}
+
+pub fn drum_regions(drum: &DrumInstructions) -> Vec<SampleRegion> {
+    let mut regions: Vec<SampleRegion> = Vec::new();
+    for sample in drum.samples() {
+        let region = drum_sample_region(sample);
+        if !regions.contains(&region) {
+            regions.push(region);
+        }
+    }
+    regions
+}
+
+pub fn export_region(nds: Arc<Mutex<Nds>>, region: &SampleRegion) -> Vec<u8> {
+    let decoded = decode_sample(
+        nds,
+        region.format,
+        region.src_address,
+        region.loop_pos,
+        region.length,
+    );
+
+    let sample_loop = if region.is_repeating && !decoded.samples.is_empty() {
+        Some(SampleLoop {
+            start: decoded.loop_start,
+            end: decoded.samples.len() - 1,
+        })
+    } else {
+        None
+    };
+
+    encode_sample_wav(
+        &decoded.samples,
+        timer_reload_sample_rate(region.timer_reload),
+        region.unity_note,
+        sample_loop,
+    )
+}
+
+/// Decodes every instrument and drum sample in the RAM dump to a named WAV
+pub fn export_samples(ram: &[u8]) -> Result<Vec<ExportedSample>, BusError> {
+    let nds = Arc::new(Mutex::new(Nds::new(ram.to_vec())?));
+    let mut exported = Vec::new();
+
+    let mut export_all = |name: String, regions: Vec<SampleRegion>| {
+        for (i, region) in regions.iter().enumerate() {
+            let name = if regions.len() == 1 {
+                name.clone()
+            } else {
+                format!("{}_{}", name, i + 1)
+            };
+            exported.push(ExportedSample {
+                name,
+                wav: export_region(nds.clone(), region),
+            });
+        }
+    };
+
+    for instrument in instrument_instructions() {
+        export_all(file_name(&instrument.name), instrument_regions(&instrument));
+    }
+
+    for section in drum_instructions().iter() {
+        for (id, drum) in section.instructions.iter().enumerate() {
+            export_all(
+                format!("{}_drum_{:02}", file_name(&section.name), id),
+                drum_regions(drum),
+            );
+        }
+    }
+
+    Ok(exported)
+}
+
+pub fn write_sample_files(ram: &[u8], directory: &Path) -> io::Result<()> {
+    for sample in export_samples(ram)? {
+        std::fs::write(directory.join(format!("{}.wav", sample.name)), &sample.wav)?;
+    }
+    Ok(())
+}
+
+pub fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures,
+        spu::encode_adpcm,
+        wav::{decode_wav, DecodedWav},
+    };
+
+    const OFFSET: usize = 0x1000;
+
+    fn ram_with(data: &[u8]) -> Arc<Mutex<Nds>> {
+        let mut ram = fixtures::zeroed_ram();
+        ram[OFFSET..OFFSET + data.len()].copy_from_slice(data);
+        Arc::new(Mutex::new(Nds::new(ram).unwrap()))
+    }
+
+    fn region(format: SampleFormat, loop_pos: usize, length: usize) -> SampleRegion {
+        SampleRegion {
+            format,
+            src_address: 0x02000000 + OFFSET,
+            loop_pos,
+            length,
+            timer_reload: 0xFC00,
+            is_repeating: true,
+            unity_note: 67,
+        }
+    }
+
+    /// The `smpl` chunk's MIDI unity note
+    fn unity_note(wav: &[u8]) -> u32 {
+        let chunk = wav.windows(4).position(|id| id == b"smpl").unwrap();
+        let unity = chunk + 20;
+        u32::from_le_bytes([wav[unity], wav[unity + 1], wav[unity + 2], wav[unity + 3]])
+    }
+
+    fn decode(wav: &[u8]) -> DecodedWav {
+        let decoded = decode_wav(wav).unwrap();
+        assert_eq!(decoded.channels, 1);
+        assert_eq!(decoded.sample_rate, timer_reload_sample_rate(0xFC00));
+        assert_eq!(unity_note(wav), 67);
+        decoded
+    }
+
+    #[test]
+    fn test_export_pcm16() {
+        let pcm: Vec<i16> = (0..64).map(|i| (i * 1000 - 32000) as i16).collect();
+        let bytes: Vec<u8> = pcm.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let nds = ram_with(&bytes);
+
+        // 16 samples before the loop and 48 in it
+        let looping = region(SampleFormat::Pcm16, 32, 96);
+        let wav = decode(&export_region(nds.clone(), &looping));
+        assert_eq!(wav.samples, pcm);
+        let sample_loop = wav.sample_loop.unwrap();
+        assert_eq!((sample_loop.start, sample_loop.end), (16, 63));
+
+        let one_shot = SampleRegion {
+            is_repeating: false,
+            ..looping
+        };
+        let wav = decode(&export_region(nds, &one_shot));
+        assert_eq!(wav.samples, pcm);
+        assert!(wav.sample_loop.is_none());
+    }
+
+    #[test]
+    fn test_export_adpcm() {
+        let pcm: Vec<i16> = (0..500)
+            .map(|i| ((i as f32 * 0.05).sin() * 12000.0) as i16)
+            .collect();
+        let encoded = encode_adpcm(&pcm, Some(203));
+        let nds = ram_with(&encoded.data);
+
+        let adpcm = region(SampleFormat::Adpcm, encoded.loop_pos, encoded.length);
+        let wav = decode(&export_region(nds.clone(), &adpcm));
+        // Exactly what a channel plays
+        let played = decode_sample(
+            nds,
+            SampleFormat::Adpcm,
+            adpcm.src_address,
+            adpcm.loop_pos,
+            adpcm.length,
+        );
+        assert_eq!(wav.samples, played.samples);
+        // Which tracks the sine it was encoded from, once the step size has caught up
+        let tracked = wav.samples[encoded.padding..].iter().zip(&pcm).skip(16);
+        for (exported, original) in tracked {
+            assert!((*exported as i32 - *original as i32).abs() < 256);
+        }
+        let sample_loop = wav.sample_loop.unwrap();
+        assert_eq!(sample_loop.start, encoded.padding + 203);
+        assert_eq!(sample_loop.end, wav.samples.len() - 1);
+    }
+
+    #[test]
+    fn test_write_sample_files() {
+        let ram = fixtures::patterned_ram();
+        let exported = export_samples(&ram).unwrap();
+        assert!(!exported.is_empty());
+        for sample in &exported {
+            assert_eq!(decode_wav(&sample.wav).unwrap().channels, 1);
+        }
+
+        let directory = std::env::temp_dir().join(format!("wahdio-samples-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        write_sample_files(&ram, &directory).unwrap();
+        // Names don't collide, so every sample gets its own file
+        let written = std::fs::read_dir(&directory).unwrap().count();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(written, exported.len());
+    }
+}
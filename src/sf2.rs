@@ -0,0 +1,752 @@
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    audio::*,
+    bus::BusError,
+    drums::drum_instructions,
+    ins::instrument_instructions,
+    sample_export::{drum_sample_region, sample_region, SampleRegion},
+    spu::{decode_sample, noise_sequence, psg_wave, Nds},
+};
+
+/// The MIDI note the first drum of a rhythm section is mapped to, the rest follow it
+pub const BASE_DRUM_MIDI_NOTE: u8 = 36;
+const DRUM_BANK: u16 = 128;
+
+// Generator operators from the SoundFont 2.01 specification
+const GEN_PAN: u16 = 17;
+const GEN_ATTACK_VOL_ENV: u16 = 34;
+const GEN_DECAY_VOL_ENV: u16 = 36;
+const GEN_SUSTAIN_VOL_ENV: u16 = 37;
+const GEN_RELEASE_VOL_ENV: u16 = 38;
+const GEN_INSTRUMENT: u16 = 41;
+const GEN_KEY_RANGE: u16 = 43;
+const GEN_INITIAL_ATTENUATION: u16 = 48;
+const GEN_SAMPLE_ID: u16 = 53;
+const GEN_SAMPLE_MODES: u16 = 54;
+const GEN_OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// A channel volume of this much plays the sample at full scale
+const FULL_VOLUME: f32 = 2048.0;
+/// The attenuation SF2 envelope times are measured over, in centibels
+const ENVELOPE_RANGE: f32 = 1000.0;
+const MAX_ATTENUATION: f32 = 1440.0;
+/// Synthesized PSG and noise samples are stretched until they reach at least this rate
+const MIN_SYNTH_SAMPLE_RATE: u32 = 22050;
+const PSG_PERIODS: usize = 8;
+const NOISE_LENGTH: usize = 0x7FFF;
+/// Zeroes the specification requires after every sample
+const SAMPLE_PADDING: usize = 46;
+/// Samples copied past a loop's end so synthesizers can interpolate across it
+const LOOP_GUARD: usize = 8;
+
+/// Builds a SoundFont with a preset for every instrument in bank 0,
+/// and a drum kit for every rhythm section in bank 128
+pub fn export_sf2(ram: &[u8]) -> Result<Vec<u8>, BusError> {
+    let nds = Arc::new(Mutex::new(Nds::new(ram.to_vec())?));
+    let mut builder = SoundFontBuilder::default();
+
+    for (index, instrument) in instrument_instructions().iter().enumerate() {
+        let zones = playable_adsrs(&instrument.instructions)
+            .into_iter()
+            .map(|(adsr, low, high)| builder.adsr_zone(&nds, adsr, low, high))
+            .collect();
+        builder.add_preset(&instrument.name, index as u16, 0, zones);
+    }
+
+    for (index, section) in drum_instructions().iter().enumerate() {
+        let zones = section
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(id, drum)| builder.drum_zone(&nds, drum, BASE_DRUM_MIDI_NOTE + id as u8))
+            .collect();
+        builder.add_preset(&section.name, index as u16, DRUM_BANK, zones);
+    }
+
+    Ok(builder.build())
+}
+
+pub fn write_sf2_file(ram: &[u8], path: &Path) -> io::Result<()> {
+    std::fs::write(path, export_sf2(ram)?)
+}
+
+/// The ADSRs a single note on the instrument starts with, along with the notes they cover
+fn playable_adsrs(instructions: &InstrumentInstructions) -> Vec<(&Adsr, u8, u8)> {
+    match instructions {
+        // Only the first of these plays when the note starts
+        InstrumentInstructions::TimedMultiple(_) | InstrumentInstructions::Random(_) => {
+            instructions.adsrs().into_iter().take(1).collect()
+        }
+        InstrumentInstructions::Cricket(cricket_adsr) => cricket_adsr
+            .iter()
+            .map(|cricket| (&cricket.adsr, cricket.low, cricket.high))
+            .collect(),
+        _ => instructions.adsrs(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SampleSource {
+    Ram(SampleRegion),
+    Psg { table_index: u8, timer_reload: u16 },
+    Noise { timer_reload: u16 },
+}
+
+/// Volumes are channel volumes, times are envelope ticks
+struct VolumeEnvelope {
+    peak: f32,
+    attack: u32,
+    decay: u32,
+    /// `None` when the sound ends once it's decayed
+    sustain: Option<f32>,
+    /// Volume multiplier applied every tick after the note is released
+    release_ratio: f32,
+}
+
+impl VolumeEnvelope {
+    fn flat(volume: u32) -> VolumeEnvelope {
+        VolumeEnvelope {
+            peak: volume as f32,
+            attack: 0,
+            decay: 0,
+            sustain: Some(volume as f32),
+            release_ratio: release_ratio(None),
+        }
+    }
+
+    fn generators(&self) -> Vec<(u16, i16)> {
+        let mut generators = vec![(
+            GEN_INITIAL_ATTENUATION,
+            attenuation(self.peak, FULL_VOLUME) as i16,
+        )];
+
+        if self.attack > 0 {
+            generators.push((GEN_ATTACK_VOL_ENV, timecents(self.attack as f32)));
+        }
+
+        let (sustain, decay) = match self.sustain {
+            Some(sustain) => {
+                let sustain = attenuation(sustain, self.peak);
+                // SF2 decays at a fixed rate towards the sustain level, pick the rate that
+                // arrives there when the DS envelope does
+                let decay = if sustain > 0.0 {
+                    self.decay as f32 * ENVELOPE_RANGE / sustain
+                } else {
+                    0.0
+                };
+                (sustain, decay)
+            }
+            None => (ENVELOPE_RANGE, self.decay.max(1) as f32),
+        };
+        generators.push((GEN_DECAY_VOL_ENV, timecents(decay)));
+        generators.push((GEN_SUSTAIN_VOL_ENV, sustain as i16));
+
+        let release = (10_f32.powf(ENVELOPE_RANGE / 200.0)).ln() / -self.release_ratio.ln();
+        generators.push((GEN_RELEASE_VOL_ENV, timecents(release)));
+
+        generators
+    }
+}
+
+/// How much the volume of a released (`Channel::Freeing`) sound is multiplied by each tick
+fn release_ratio(release: Option<&ReleaseInstructions>) -> f32 {
+    match release {
+        Some(ReleaseInstructions::Basic)
+        | Some(ReleaseInstructions::Exponential { .. })
+        | Some(ReleaseInstructions::ExponentialUntil { .. }) => (-0.17_f32).exp(),
+        Some(ReleaseInstructions::Geometric { ratio }) => *ratio,
+        Some(ReleaseInstructions::GeometricStopBlowing { .. })
+        | Some(ReleaseInstructions::Static(_)) => 0.0,
+        Some(ReleaseInstructions::KeepBlowing) => (-0.01_f32).exp(),
+        None => 0.5,
+    }
+}
+
+fn instrument_release(release: &InstrumentRelease) -> ReleaseInstructions {
+    match release {
+        InstrumentRelease::Basic => ReleaseInstructions::Basic,
+        InstrumentRelease::ExponentialUntil { duration, until } => {
+            ReleaseInstructions::ExponentialUntil {
+                duration: *duration,
+                until: *until,
+            }
+        }
+        InstrumentRelease::Geometric { ratio } => ReleaseInstructions::Geometric { ratio: *ratio },
+        InstrumentRelease::GeometricStopBlowing { ratio } => {
+            ReleaseInstructions::GeometricStopBlowing { ratio: *ratio }
+        }
+        InstrumentRelease::Static(_) => ReleaseInstructions::Static(0),
+        InstrumentRelease::KeepBlowing => ReleaseInstructions::KeepBlowing,
+    }
+}
+
+/// Centibels `volume` is below `reference`
+fn attenuation(volume: f32, reference: f32) -> f32 {
+    if volume <= 0.0 {
+        return MAX_ATTENUATION;
+    }
+    (200.0 * (reference / volume).log10()).clamp(0.0, MAX_ATTENUATION)
+}
+
+fn timecents(ticks: f32) -> i16 {
+    if ticks <= 0.0 {
+        return -12000;
+    }
+    let seconds = ticks * EVENT_TIMING as f32 / SAMPLE_RATE as f32;
+    (1200.0 * seconds.log2()).clamp(-12000.0, 8000.0) as i16
+}
+
+/// Stretches `samples` so they play at no less than `MIN_SYNTH_SAMPLE_RATE`
+fn hold_samples(samples: &[i16], sample_rate: u32) -> (Vec<i16>, u32) {
+    let hold = ((MIN_SYNTH_SAMPLE_RATE + sample_rate - 1) / sample_rate.max(1)).max(1);
+    let samples = samples
+        .iter()
+        .flat_map(|&sample| std::iter::repeat_n(sample, hold as usize))
+        .collect();
+    (samples, sample_rate * hold)
+}
+
+struct Zone {
+    key_range: (u8, u8),
+    generators: Vec<(u16, i16)>,
+    sample_id: u16,
+}
+
+struct SampleHeader {
+    name: String,
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    root_key: u8,
+}
+
+struct Preset {
+    name: String,
+    preset: u16,
+    bank: u16,
+    zones: Vec<Zone>,
+}
+
+#[derive(Default)]
+struct SoundFontBuilder {
+    sample_data: Vec<i16>,
+    samples: Vec<(SampleSource, SampleHeader)>,
+    presets: Vec<Preset>,
+}
+
+impl SoundFontBuilder {
+    fn add_preset(&mut self, name: &str, preset: u16, bank: u16, zones: Vec<Zone>) {
+        self.presets.push(Preset {
+            name: name.to_string(),
+            preset,
+            bank,
+            zones,
+        });
+    }
+
+    fn adsr_zone(&mut self, nds: &Arc<Mutex<Nds>>, adsr: &Adsr, low: u8, high: u8) -> Zone {
+        // Volumes are interpolated across the range, take them from the middle of it
+        let until_note = high as u32;
+        let note_offset = (high as u32 - low as u32) / 2;
+        let interp = |volume| interp_val_until(note_offset, volume, until_note);
+
+        let initial_volume = interp(adsr.sample.volume());
+        let (peak, attack) = match &adsr.attack {
+            Some(InstrumentAttack::Exact { adjustments })
+            | Some(InstrumentAttack::ExactWithMagicPitch { adjustments }) => (
+                interp(adjustments.last().unwrap().volume),
+                adjustments.iter().map(|adj| adj.time).max().unwrap(),
+            ),
+            None => (initial_volume, 0),
+        };
+        let decay = match &adsr.decay {
+            Some(InstrumentDecay::ExponentialWithVariableSustain {
+                duration,
+                sustain_duration,
+            }) => duration - interp(*sustain_duration),
+            Some(InstrumentDecay::Exponential { duration })
+            | Some(InstrumentDecay::ExponentialWithWobble { duration, .. })
+            | Some(InstrumentDecay::ExponentialRising { duration, .. })
+            | Some(InstrumentDecay::Linear { duration }) => *duration,
+            None => 0,
+        };
+        let has_envelope = adsr.attack.is_some()
+            || adsr.decay.is_some()
+            || adsr.sustain.is_some()
+            || adsr.release.is_some();
+
+        let envelope = if has_envelope {
+            VolumeEnvelope {
+                peak: peak as f32,
+                attack,
+                decay,
+                sustain: adsr.sustain.map(|sustain| interp(sustain.volume) as f32),
+                release_ratio: release_ratio(
+                    adsr.release.as_ref().map(instrument_release).as_ref(),
+                ),
+            }
+        } else {
+            VolumeEnvelope::flat(initial_volume)
+        };
+
+        let root_key = BASE_MIDI_NOTE + low;
+        let (source, is_repeating) = match &adsr.sample {
+            InstrumentSample::PCM16(sample) => (
+                SampleSource::Ram(sample_region(sample, root_key)),
+                sample.is_repeating,
+            ),
+            InstrumentSample::Custom(sample, format) => (
+                SampleSource::Ram(SampleRegion {
+                    format: *format,
+                    ..sample_region(sample, root_key)
+                }),
+                sample.is_repeating,
+            ),
+            InstrumentSample::PSG(sample) => (
+                SampleSource::Psg {
+                    table_index: sample.table_index,
+                    timer_reload: sample.base_timer_reload,
+                },
+                true,
+            ),
+        };
+
+        let mut generators = envelope.generators();
+        generators.push((GEN_SAMPLE_MODES, is_repeating as i16));
+        generators.push((GEN_OVERRIDING_ROOT_KEY, root_key as i16));
+
+        Zone {
+            key_range: (BASE_MIDI_NOTE + low, BASE_MIDI_NOTE + high),
+            generators,
+            sample_id: self.sample_id(nds, source, root_key),
+        }
+    }
+
+    fn drum_zone(&mut self, nds: &Arc<Mutex<Nds>>, drum: &DrumInstructions, key: u8) -> Zone {
+        let (source, is_repeating, base_pan, envelope) = match drum {
+            DrumInstructions::Dsr {
+                sample,
+                decay,
+                sustain,
+                release,
+            }
+            | DrumInstructions::DsrPitchAdjust {
+                sample,
+                decay,
+                sustain,
+                release,
+                ..
+            } => (
+                SampleSource::Ram(drum_sample_region(sample)),
+                sample.is_repeating,
+                sample.base_pan,
+                VolumeEnvelope {
+                    peak: sample.volume as f32,
+                    attack: 0,
+                    decay: decay.duration,
+                    sustain: Some(sustain.volume as f32),
+                    release_ratio: release_ratio(Some(release)),
+                },
+            ),
+            DrumInstructions::Sr {
+                sample,
+                sustain,
+                release,
+            }
+            | DrumInstructions::PitchThenRelease {
+                sample,
+                sustain,
+                release,
+                ..
+            } => (
+                SampleSource::Ram(drum_sample_region(sample)),
+                sample.is_repeating,
+                sample.base_pan,
+                VolumeEnvelope {
+                    peak: sample.volume as f32,
+                    attack: 0,
+                    decay: 0,
+                    sustain: Some(sustain.volume as f32),
+                    release_ratio: release_ratio(Some(release)),
+                },
+            ),
+            DrumInstructions::Decay {
+                sample,
+                decay,
+                final_volume,
+            } => {
+                // Decays at the rate that reaches `final_volume` at the end of the decay
+                let drop = attenuation((*final_volume as f32).max(0.1), sample.volume as f32);
+                let decay = (decay.duration as f32 * ENVELOPE_RANGE / drop.max(1.0)) as u32;
+                (
+                    SampleSource::Ram(drum_sample_region(sample)),
+                    sample.is_repeating,
+                    sample.base_pan,
+                    VolumeEnvelope {
+                        peak: sample.volume as f32,
+                        attack: 0,
+                        decay,
+                        sustain: None,
+                        release_ratio: release_ratio(None),
+                    },
+                )
+            }
+            DrumInstructions::Simple { sample }
+            | DrumInstructions::ExactVolumeAdjust { sample, .. }
+            | DrumInstructions::RepeatOnce { sample, .. } => (
+                SampleSource::Ram(drum_sample_region(sample)),
+                sample.is_repeating,
+                sample.base_pan,
+                VolumeEnvelope::flat(sample.volume),
+            ),
+            DrumInstructions::Multiple { samples } => {
+                let sample = &samples[0].sample;
+                (
+                    SampleSource::Ram(drum_sample_region(sample)),
+                    sample.is_repeating,
+                    sample.base_pan,
+                    VolumeEnvelope::flat(sample.volume),
+                )
+            }
+            DrumInstructions::Noise {
+                sample,
+                attack,
+                decay,
+                sustain,
+                release,
+            } => {
+                let (peak, attack) = match attack {
+                    AttackInstructions::Linear { volume, duration } => (*volume, *duration),
+                    AttackInstructions::Exact { adjustments } => (
+                        adjustments.last().unwrap().volume,
+                        adjustments.iter().map(|adj| adj.time).max().unwrap(),
+                    ),
+                };
+                (
+                    SampleSource::Noise {
+                        timer_reload: sample.timer_reload,
+                    },
+                    true,
+                    sample.base_pan,
+                    VolumeEnvelope {
+                        peak: peak as f32,
+                        attack,
+                        decay: decay.duration,
+                        sustain: Some(sustain.volume as f32),
+                        release_ratio: release_ratio(Some(release)),
+                    },
+                )
+            }
+        };
+
+        let mut generators = envelope.generators();
+        // DS pans go from 0 to 127, SF2 pans from -500 to 500
+        generators.push((
+            GEN_PAN,
+            ((base_pan as i16 - 64) * 500 / 64).clamp(-500, 500),
+        ));
+        generators.push((GEN_SAMPLE_MODES, is_repeating as i16));
+        generators.push((GEN_OVERRIDING_ROOT_KEY, key as i16));
+
+        Zone {
+            key_range: (key, key),
+            generators,
+            sample_id: self.sample_id(nds, source, key),
+        }
+    }
+
+    /// Adds the sample the first time it's used and returns its index
+    fn sample_id(&mut self, nds: &Arc<Mutex<Nds>>, source: SampleSource, root_key: u8) -> u16 {
+        if let Some(id) = self.samples.iter().position(|(s, _)| *s == source) {
+            return id as u16;
+        }
+
+        let (samples, loop_start, is_repeating, sample_rate, name) = match source {
+            SampleSource::Ram(region) => {
+                let decoded = decode_sample(
+                    nds.clone(),
+                    region.format,
+                    region.src_address,
+                    region.loop_pos,
+                    region.length,
+                );
+                (
+                    decoded.samples,
+                    decoded.loop_start,
+                    region.is_repeating,
+                    timer_reload_sample_rate(region.timer_reload),
+                    format!("ram_{:06X}", region.src_address & 0x3FFFFF),
+                )
+            }
+            SampleSource::Psg {
+                table_index,
+                timer_reload,
+            } => {
+                let wave: Vec<i16> = psg_wave(table_index)
+                    .iter()
+                    .cycle()
+                    .take(8 * PSG_PERIODS)
+                    .cloned()
+                    .collect();
+                let (samples, sample_rate) =
+                    hold_samples(&wave, timer_reload_sample_rate(timer_reload));
+                (
+                    samples,
+                    0,
+                    true,
+                    sample_rate,
+                    format!("psg_{}_{}", table_index, timer_reload),
+                )
+            }
+            SampleSource::Noise { timer_reload } => {
+                let (samples, sample_rate) = hold_samples(
+                    &noise_sequence(NOISE_LENGTH),
+                    timer_reload_sample_rate(timer_reload),
+                );
+                (
+                    samples,
+                    0,
+                    true,
+                    sample_rate,
+                    format!("noise_{}", timer_reload),
+                )
+            }
+        };
+
+        let start = self.sample_data.len() as u32;
+        self.sample_data.extend_from_slice(&samples);
+        let end = self.sample_data.len() as u32;
+        let (loop_start, loop_end) = if is_repeating && loop_start < samples.len() {
+            let guard = samples[loop_start..].iter().cycle().take(LOOP_GUARD);
+            self.sample_data.extend(guard);
+            (start + loop_start as u32, end)
+        } else {
+            (start, end)
+        };
+        self.sample_data
+            .extend(std::iter::repeat_n(0, SAMPLE_PADDING));
+
+        self.samples.push((
+            source,
+            SampleHeader {
+                name,
+                start,
+                end,
+                loop_start,
+                loop_end,
+                sample_rate,
+                root_key,
+            },
+        ));
+        (self.samples.len() - 1) as u16
+    }
+
+    fn build(self) -> Vec<u8> {
+        let mut info = Vec::new();
+        write_chunk(&mut info, b"ifil", &[2, 0, 1, 0]);
+        write_chunk(&mut info, b"isng", &zstr("EMU8000", 8));
+        write_chunk(&mut info, b"INAM", &zstr("Sound Collection", 18));
+
+        let mut smpl = Vec::with_capacity(self.sample_data.len() * 2);
+        for sample in &self.sample_data {
+            smpl.extend_from_slice(&sample.to_le_bytes());
+        }
+        let mut sdta = Vec::new();
+        write_chunk(&mut sdta, b"smpl", &smpl);
+
+        // Every preset has a single zone holding an instrument of the same index
+        let (mut phdr, mut pbag, mut pgen) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut inst, mut ibag, mut igen) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut ibag_count, mut igen_count) = (0u16, 0u16);
+
+        for (index, preset) in self.presets.iter().enumerate() {
+            phdr.extend_from_slice(&zstr(&preset.name, 20));
+            phdr.extend_from_slice(&preset.preset.to_le_bytes());
+            phdr.extend_from_slice(&preset.bank.to_le_bytes());
+            phdr.extend_from_slice(&(index as u16).to_le_bytes());
+            phdr.extend_from_slice(&[0; 12]);
+
+            pbag.extend_from_slice(&(index as u16).to_le_bytes());
+            pbag.extend_from_slice(&0u16.to_le_bytes());
+            write_generator(&mut pgen, GEN_INSTRUMENT, index as i16);
+
+            inst.extend_from_slice(&zstr(&preset.name, 20));
+            inst.extend_from_slice(&ibag_count.to_le_bytes());
+            for zone in &preset.zones {
+                ibag.extend_from_slice(&igen_count.to_le_bytes());
+                ibag.extend_from_slice(&0u16.to_le_bytes());
+                ibag_count += 1;
+
+                // The key range has to come first and the sample last
+                let key_range = i16::from_le_bytes([zone.key_range.0, zone.key_range.1]);
+                write_generator(&mut igen, GEN_KEY_RANGE, key_range);
+                for (operator, amount) in &zone.generators {
+                    write_generator(&mut igen, *operator, *amount);
+                }
+                write_generator(&mut igen, GEN_SAMPLE_ID, zone.sample_id as i16);
+                igen_count += zone.generators.len() as u16 + 2;
+            }
+        }
+
+        // Terminal records
+        let preset_count = self.presets.len() as u16;
+        phdr.extend_from_slice(&zstr("EOP", 20));
+        phdr.extend_from_slice(&[0; 4]);
+        phdr.extend_from_slice(&preset_count.to_le_bytes());
+        phdr.extend_from_slice(&[0; 12]);
+        pbag.extend_from_slice(&preset_count.to_le_bytes());
+        pbag.extend_from_slice(&0u16.to_le_bytes());
+        write_generator(&mut pgen, 0, 0);
+        inst.extend_from_slice(&zstr("EOI", 20));
+        inst.extend_from_slice(&ibag_count.to_le_bytes());
+        ibag.extend_from_slice(&igen_count.to_le_bytes());
+        ibag.extend_from_slice(&0u16.to_le_bytes());
+        write_generator(&mut igen, 0, 0);
+
+        let mut shdr = Vec::new();
+        for (_, header) in &self.samples {
+            shdr.extend_from_slice(&zstr(&header.name, 20));
+            shdr.extend_from_slice(&header.start.to_le_bytes());
+            shdr.extend_from_slice(&header.end.to_le_bytes());
+            shdr.extend_from_slice(&header.loop_start.to_le_bytes());
+            shdr.extend_from_slice(&header.loop_end.to_le_bytes());
+            shdr.extend_from_slice(&header.sample_rate.to_le_bytes());
+            shdr.push(header.root_key);
+            // Pitch correction, sample link, mono sample type
+            shdr.push(0);
+            shdr.extend_from_slice(&0u16.to_le_bytes());
+            shdr.extend_from_slice(&1u16.to_le_bytes());
+        }
+        shdr.extend_from_slice(&zstr("EOS", 20));
+        shdr.extend_from_slice(&[0; 26]);
+
+        let mut pdta = Vec::new();
+        write_chunk(&mut pdta, b"phdr", &phdr);
+        write_chunk(&mut pdta, b"pbag", &pbag);
+        write_chunk(&mut pdta, b"pmod", &[0; 10]);
+        write_chunk(&mut pdta, b"pgen", &pgen);
+        write_chunk(&mut pdta, b"inst", &inst);
+        write_chunk(&mut pdta, b"ibag", &ibag);
+        write_chunk(&mut pdta, b"imod", &[0; 10]);
+        write_chunk(&mut pdta, b"igen", &igen);
+        write_chunk(&mut pdta, b"shdr", &shdr);
+
+        let mut lists = Vec::new();
+        write_list(&mut lists, b"INFO", &info);
+        write_list(&mut lists, b"sdta", &sdta);
+        write_list(&mut lists, b"pdta", &pdta);
+
+        let mut bytes = Vec::with_capacity(12 + lists.len());
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(4 + lists.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"sfbk");
+        bytes.extend_from_slice(&lists);
+        bytes
+    }
+}
+
+fn write_generator(bytes: &mut Vec<u8>, operator: u16, amount: i16) {
+    bytes.extend_from_slice(&operator.to_le_bytes());
+    bytes.extend_from_slice(&amount.to_le_bytes());
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        bytes.push(0);
+    }
+}
+
+fn write_list(bytes: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(b"LIST");
+    bytes.extend_from_slice(&(4 + data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(data);
+}
+
+/// `text` as a zero terminated, zero padded ASCII string of `length` bytes
+fn zstr(text: &str, length: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = text
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'_' })
+        .take(length - 1)
+        .collect();
+    bytes.resize(length, 0);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn chunk<'a>(bytes: &'a [u8], id: &[u8; 4]) -> &'a [u8] {
+        let mut pos = 0;
+        while pos + 8 <= bytes.len() {
+            let size = u32::from_le_bytes([
+                bytes[pos + 4],
+                bytes[pos + 5],
+                bytes[pos + 6],
+                bytes[pos + 7],
+            ]) as usize;
+            let data = &bytes[pos + 8..pos + 8 + size];
+            if &bytes[pos..pos + 4] == id {
+                return data;
+            }
+            if &bytes[pos..pos + 4] == b"LIST" {
+                let found = chunk(&data[4..], id);
+                if !found.is_empty() {
+                    return found;
+                }
+            }
+            pos += 8 + size + size % 2;
+        }
+        &[]
+    }
+
+    #[test]
+    fn test_sf2_layout() {
+        let ram = fixtures::zeroed_ram();
+        let sf2 = export_sf2(&ram).unwrap();
+
+        assert_eq!(&sf2[0..4], b"RIFF");
+        assert_eq!(&sf2[8..12], b"sfbk");
+        let body = &sf2[12..];
+
+        let presets = instrument_instructions().len() + RHYTHM_SECTION_COUNT;
+        assert_eq!(chunk(body, b"phdr").len(), (presets + 1) * 38);
+        assert_eq!(chunk(body, b"inst").len(), (presets + 1) * 22);
+        assert_eq!(chunk(body, b"pgen").len(), (presets + 1) * 4);
+
+        let ibag = chunk(body, b"ibag");
+        let igen = chunk(body, b"igen");
+        let last_gen = u16::from_le_bytes([ibag[ibag.len() - 4], ibag[ibag.len() - 3]]);
+        assert_eq!((last_gen as usize + 1) * 4, igen.len());
+
+        // Every zone ends with a sample that exists
+        let samples = chunk(body, b"shdr").len() / 46 - 1;
+        for generator in igen.chunks(4) {
+            let operator = u16::from_le_bytes([generator[0], generator[1]]);
+            if operator == GEN_SAMPLE_ID {
+                assert!((u16::from_le_bytes([generator[2], generator[3]]) as usize) < samples);
+            }
+        }
+
+        // Drum kits follow the instruments in the percussion bank
+        let phdr = chunk(body, b"phdr");
+        let first_kit = &phdr[instrument_instructions().len() * 38..];
+        assert_eq!(
+            u16::from_le_bytes([first_kit[22], first_kit[23]]),
+            DRUM_BANK
+        );
+    }
+}
@@ -21,6 +21,16 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use nanoserde::{DeBin, DeBinErr, SerBin};
+
+use crate::{
+    audio::{Write, SAMPLE_RATE},
+    bus::{BusError, MainRam, MemoryRegion, OPEN_BUS},
+    log::{self, Event},
+    sample_bank::SampleBank,
+    trace::SpuTrace,
+};
+
 // WARNING: SOME OF THESE VALUES ARE INCORRECT FOR CONVENIENCE
 // to add an echo originally added in code
 const PSG_TABLE: [[i16; 8]; 8] = [
@@ -124,76 +134,407 @@ const ADPCM_TABLE: [u16; 89] = [
 
 const ADPCM_INDEX_TABLE: [i8; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeBin, SerBin)]
 pub enum AudioBitDepth {
+    /// The DS's PWM output, biased, 10-bit and through its analog filtering
     _10bit,
+    /// The clean mixer output
     _16bit,
 }
 
+/// Rough corner frequencies of the DS's analog output path: the coupling capacitor's
+/// high-pass and the low-pass smoothing the PWM output. Models differ, these are in between.
+const OUTPUT_HIGHPASS_HZ: f32 = 16.0;
+const OUTPUT_LOWPASS_HZ: f32 = 8000.0;
+
+/// A one-pole high-pass followed by a one-pole low-pass, on one side of the output
+#[derive(Debug, Clone, Copy, Default, DeBin, SerBin)]
+struct OutputFilter {
+    highpass_input: f32,
+    highpass_output: f32,
+    lowpass_output: f32,
+}
+
+impl OutputFilter {
+    fn run(&mut self, sample: i32) -> i32 {
+        let dt = 1.0 / SAMPLE_RATE as f32;
+        let rc = |hz: f32| 1.0 / (2.0 * std::f32::consts::PI * hz);
+        let highpass = rc(OUTPUT_HIGHPASS_HZ) / (rc(OUTPUT_HIGHPASS_HZ) + dt);
+        let lowpass = dt / (rc(OUTPUT_LOWPASS_HZ) + dt);
+
+        let input = sample as f32;
+        self.highpass_output = highpass * (self.highpass_output + input - self.highpass_input);
+        self.highpass_input = input;
+        self.lowpass_output += lowpass * (self.highpass_output - self.lowpass_output);
+
+        (self.lowpass_output.round() as i32).clamp(-0x8000, 0x7FFF)
+    }
+}
+
+/// Smoothing between PCM/ADPCM samples, not a hardware feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeBin, SerBin)]
+pub enum AudioInterpolation {
+    /// Zero-order hold, as on hardware
+    None,
+    Linear,
+    Cosine,
+    Cubic,
+}
+
+impl AudioInterpolation {
+    pub const ALL: [AudioInterpolation; 4] = [
+        AudioInterpolation::None,
+        AudioInterpolation::Linear,
+        AudioInterpolation::Cosine,
+        AudioInterpolation::Cubic,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioInterpolation::None => "none",
+            AudioInterpolation::Linear => "linear",
+            AudioInterpolation::Cosine => "cosine",
+            AudioInterpolation::Cubic => "cubic",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AudioInterpolation> {
+        AudioInterpolation::ALL
+            .iter()
+            .cloned()
+            .find(|interpolation| interpolation.name() == name)
+    }
+}
+
+/// The ARM7's view of memory, reads and writes go to the first mapped region that covers them
 pub struct Nds {
-    ram: Vec<u8>,
+    regions: Vec<Box<dyn MemoryRegion>>,
 }
 
 const MAIN_RAM_MAX_SIZE: usize = 0x1000000;
 
 impl Nds {
-    pub fn new(ram: Vec<u8>) -> Self {
-        Self { ram }
+    /// `ram` is a dump of main RAM from its start, up to all 4 MiB of it
+    pub fn new(ram: Vec<u8>) -> Result<Self, BusError> {
+        let mut nds = Self::empty();
+        nds.map_region(Box::new(MainRam::new(ram)?));
+        Ok(nds)
     }
 
-    fn arm7_read32(&self, mut address: usize) -> u32 {
-        let mut address = address as u32;
-        //address &= !0x3;
-        // TODO: Make safe
+    /// Main RAM is served from the bank instead of a full dump
+    pub fn from_sample_bank(sample_bank: SampleBank) -> Self {
+        let mut nds = Self::empty();
+        nds.map_region(Box::new(sample_bank));
+        nds
+    }
 
-        // Faking this
+    pub fn empty() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
 
-        //let address_region = address & 0xFF800000;
-        //assert!(address_region == 0x02000000 || address_region == 0x02800000);
-        let main_ram_mask = 0x3FFFFF;
+    /// Regions mapped later take priority over the ones already mapped
+    pub fn map_region(&mut self, region: Box<dyn MemoryRegion>) {
+        self.regions.insert(0, region);
+    }
 
-        match address & 0xFF800000 {
-            0x02000000 | 0x02800000 => {
-                unsafe {
-                    // MainRAMMask?
-                    //println!("read address: {}", address & main_ram_mask);
+    pub fn read32(&self, address: u32) -> Result<u32, BusError> {
+        self.regions
+            .iter()
+            .find_map(|region| region.read32(address))
+            .ok_or(BusError::Unmapped { address })
+    }
 
-                    let ptr = std::mem::transmute::<*const u8, *const u32>(
-                        self.ram.as_ptr().add((address & main_ram_mask) as usize),
-                    );
-                    *ptr
-                }
-            }
-            _ => unimplemented!(),
+    pub fn write32(&mut self, address: u32, val: u32) -> Result<(), BusError> {
+        if self
+            .regions
+            .iter_mut()
+            .any(|region| region.write32(address, val))
+        {
+            Ok(())
+        } else {
+            Err(BusError::Unmapped { address })
         }
     }
 
+    fn arm7_read32(&self, address: usize) -> u32 {
+        self.read32(address as u32).unwrap_or(OPEN_BUS)
+    }
+
     fn arm7_write32(&mut self, address: usize, val: u32) {
-        let mut address = address as u32;
-        address &= !0x3;
-
-        let main_ram_mask = 0x3FFFFF;
-
-        match address & 0xFF800000 {
-            0x02000000 | 0x02800000 => {
-                unsafe {
-                    // MainRAMMask?
-                    //println!("write address: {}", address & main_ram_mask);
-
-                    let ptr = std::mem::transmute::<*mut u8, *mut u32>(
-                        self.ram
-                            .as_mut_ptr()
-                            .add((address & main_ram_mask) as usize),
-                    );
-                    *ptr = val;
-                }
-            }
-            _ => unimplemented!(),
-        }
+        let address = address as u32 & !0x3;
+        // Writes to unmapped memory go nowhere
+        let _ = self.write32(address, val);
+    }
+}
+
+/// Stands in for the memory bus in save states, which only hold SPU state.
+/// Loading reattaches the real one.
+struct DetachedNds;
+
+impl From<&Arc<Mutex<Nds>>> for DetachedNds {
+    fn from(_: &Arc<Mutex<Nds>>) -> DetachedNds {
+        DetachedNds
+    }
+}
+
+impl From<&DetachedNds> for Arc<Mutex<Nds>> {
+    fn from(_: &DetachedNds) -> Arc<Mutex<Nds>> {
+        Arc::new(Mutex::new(Nds::empty()))
+    }
+}
+
+impl SerBin for DetachedNds {
+    fn ser_bin(&self, _s: &mut Vec<u8>) {}
+}
+
+impl DeBin for DetachedNds {
+    fn de_bin(_o: &mut usize, _d: &[u8]) -> Result<Self, DeBinErr> {
+        Ok(DetachedNds)
+    }
+}
+
+/// Every way the sequencer drives the SPU, with the values as the SPU received them
+#[derive(Debug, Clone, Copy, PartialEq, DeBin, SerBin)]
+pub enum SpuCall {
+    Write { address: u32, value: Write },
+    SetChannelVolume { channel: u8, volume: u32 },
+    SetChannelTimerReload { channel: u8, timer_reload: u32 },
+    SetChannelLoopPos { channel: u8, loop_pos: u32 },
+    SetChannelLength { channel: u8, length: u32 },
+    SetChannelSrcAddress { channel: u8, src_address: u32 },
+    SetChannelPan { channel: u8, pan: u8 },
+    ChannelPlayNote { channel: u8, is_repeating: bool },
+    ChannelPlayPsg { channel: u8, table_index: u8 },
+    ChannelPlayNoise { channel: u8 },
+    ChannelPlaySample {
+        channel: u8,
+        is_repeating: bool,
+        format: SampleFormat,
+    },
+}
+
+/// A call made before the `sample`th sample since tracing started was mixed
+#[derive(Debug, Clone, Copy, PartialEq, DeBin, SerBin)]
+pub struct TraceEvent {
+    pub sample: u64,
+    pub call: SpuCall,
+}
+
+struct SpuTracer {
+    start: u64,
+    initial_state: Vec<u8>,
+    events: Vec<TraceEvent>,
+}
+
+/// Traces in progress aren't part of save states
+struct DetachedTracer;
+
+impl From<&Option<SpuTracer>> for DetachedTracer {
+    fn from(_: &Option<SpuTracer>) -> DetachedTracer {
+        DetachedTracer
+    }
+}
+
+impl From<&DetachedTracer> for Option<SpuTracer> {
+    fn from(_: &DetachedTracer) -> Option<SpuTracer> {
+        None
+    }
+}
+
+impl SerBin for DetachedTracer {
+    fn ser_bin(&self, _s: &mut Vec<u8>) {}
+}
+
+impl DeBin for DetachedTracer {
+    fn de_bin(_o: &mut usize, _d: &[u8]) -> Result<Self, DeBinErr> {
+        Ok(DetachedTracer)
     }
 }
 
 const SPU_FIFO_SIZE: usize = 8;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeBin, SerBin)]
+pub enum SampleFormat {
+    Pcm8,
+    Pcm16,
+    Adpcm,
+}
+
+impl SampleFormat {
+    /// Bits 29-30 of a channel's control register
+    fn control_bits(self) -> u32 {
+        match self {
+            SampleFormat::Pcm8 => 0,
+            SampleFormat::Pcm16 => 1,
+            SampleFormat::Adpcm => 2,
+        }
+    }
+}
+
+/// A sample region decoded the same way a channel plays it
+pub struct DecodedSample {
+    pub samples: Vec<i16>,
+    pub loop_start: usize,
+}
+
+/// Decodes `loop_pos + length` bytes of sample data at `src_address`
+pub fn decode_sample(
+    nds: Arc<Mutex<Nds>>,
+    format: SampleFormat,
+    src_address: usize,
+    loop_pos: usize,
+    length: usize,
+) -> DecodedSample {
+    let mut channel = SpuChannel::new(0, nds);
+    // One-shot, so the decoder stops at the end instead of looping
+    channel.control = (1 << 31) | (format.control_bits() << 29) | (2 << 27);
+    channel.src_address = src_address;
+    channel.loop_pos = loop_pos;
+    channel.length = length;
+    channel.start();
+
+    let total_len = loop_pos + length;
+    let (sample_count, loop_start, first_pos) = match format {
+        SampleFormat::Pcm8 => (total_len, loop_pos, 0),
+        SampleFormat::Pcm16 => (total_len / 2, loop_pos / 2, 0),
+        // The first 4 bytes are the header, not samples
+        SampleFormat::Adpcm => (
+            (total_len * 2).saturating_sub(8),
+            (loop_pos * 2).saturating_sub(8),
+            8,
+        ),
+    };
+
+    let mut samples = Vec::with_capacity(sample_count);
+    while samples.len() < sample_count {
+        match format {
+            SampleFormat::Pcm8 => channel.next_sample_pcm8(),
+            SampleFormat::Pcm16 => channel.next_sample_pcm16(),
+            SampleFormat::Adpcm => channel.next_sample_adpcm(),
+        }
+        if channel.pos >= first_pos {
+            samples.push(channel.current_sample);
+        }
+    }
+
+    DecodedSample {
+        samples,
+        loop_start,
+    }
+}
+
+/// Bytes of the word in front of ADPCM data holding the first value and step index
+pub const ADPCM_HEADER_SIZE: usize = 4;
+
+/// PCM encoded as DS IMA-ADPCM, with `loop_pos` and `length` in bytes like the channel
+/// registers take them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedAdpcm {
+    pub data: Vec<u8>,
+    pub loop_pos: usize,
+    pub length: usize,
+    /// Samples of silence in front that move the loop onto a word
+    pub padding: usize,
+}
+
+/// Decodes one nibble the way `SpuChannel::next_sample_adpcm` does, returning the new value
+/// and step index
+fn adpcm_step(val: i32, index: i32, nibble: u8) -> (i32, i32) {
+    let step = ADPCM_TABLE[index as usize];
+    let mut diff = step >> 3;
+    if (nibble & 0x1) != 0 {
+        diff += step >> 2;
+    }
+    if (nibble & 0x2) != 0 {
+        diff += step >> 1;
+    }
+    if (nibble & 0x4) != 0 {
+        diff += step;
+    }
+
+    let val = if (nibble & 0x8) != 0 {
+        (val - diff as i32).max(-0x7FFF)
+    } else {
+        (val + diff as i32).min(0x7FFF)
+    };
+    let index = (index + ADPCM_INDEX_TABLE[(nibble & 0x7) as usize] as i32).clamp(0, 88);
+    (val, index)
+}
+
+/// Encodes `samples` as ADPCM a channel can play: a header with the first sample and a step
+/// index of 0, then two samples a byte, low nibble first. Every nibble is the one the
+/// decoder lands closest to the sample with.
+///
+/// The hardware counts loop points in words, eight samples each. A loop from `loop_start`
+/// to the end of `samples` is moved onto a word by padding the start with silence, and
+/// trimmed to whole words. Without a loop, the end is padded with silence instead.
+pub fn encode_adpcm(samples: &[i16], loop_start: Option<usize>) -> EncodedAdpcm {
+    let padding = loop_start.map_or(0, |start| (8 - start % 8) % 8);
+    let mut padded = vec![0; padding];
+    padded.extend_from_slice(samples);
+    match loop_start {
+        Some(start) => {
+            let start = (start + padding).min(padded.len());
+            let loop_length = (padded.len() - start) / 8 * 8;
+            padded.truncate(start + loop_length);
+        }
+        None => padded.resize(padded.len().div_ceil(8) * 8, 0),
+    }
+
+    let first = padded.first().copied().unwrap_or(0);
+    let mut data = Vec::with_capacity(ADPCM_HEADER_SIZE + padded.len() / 2);
+    data.extend_from_slice(&(first as u16 as u32).to_le_bytes());
+
+    let (mut val, mut index) = (first as i32, 0);
+    for pair in padded.chunks(2) {
+        let mut byte = 0;
+        for (i, &sample) in pair.iter().enumerate() {
+            let nibble = (0..16)
+                .min_by_key(|&nibble| (adpcm_step(val, index, nibble).0 - sample as i32).abs())
+                .unwrap();
+            (val, index) = adpcm_step(val, index, nibble);
+            byte |= nibble << (i * 4);
+        }
+        data.push(byte);
+    }
+
+    let loop_pos = ADPCM_HEADER_SIZE + loop_start.map_or(0, |start| (start + padding) / 2);
+    let loop_pos = loop_pos.min(data.len());
+    EncodedAdpcm {
+        length: data.len() - loop_pos,
+        data,
+        loop_pos,
+        padding,
+    }
+}
+
+/// One period of the wave a PSG channel plays with `table_index`
+pub fn psg_wave(table_index: u8) -> [i16; 8] {
+    PSG_TABLE[table_index as usize & 0x7]
+}
+
+/// The first `length` samples a noise channel plays after being keyed on
+pub fn noise_sequence(length: usize) -> Vec<i16> {
+    let mut channel = SpuChannel::new(0, Arc::new(Mutex::new(Nds::empty())));
+    channel.control = 3 << 29;
+    channel.start();
+
+    (0..length)
+        .map(|_| {
+            channel.next_sample_noise();
+            channel.current_sample
+        })
+        .collect()
+}
+
+fn interp_cos(i: i32) -> i32 {
+    let ratio = 1.0 - (i as f32 * std::f32::consts::PI / 255.0).cos();
+    (ratio * 0x2000 as f32) as i32
+}
+
+#[derive(DeBin, SerBin)]
 pub struct SpuChannel {
     num: usize,
     //nds: Nds
@@ -216,6 +557,9 @@ pub struct SpuChannel {
     current_sample: i16,
     noise_val: u16,
 
+    interpolation: AudioInterpolation,
+    prev_sample: [i16; 3],
+
     adpcm_val: i32,
     adpcm_index: i32,
     adpcm_val_loop: i32,
@@ -228,6 +572,7 @@ pub struct SpuChannel {
     fifo_read_offset: usize,
     fifo_level: usize,
 
+    #[nserde(proxy = "DetachedNds")]
     nds: Arc<Mutex<Nds>>,
 }
 
@@ -254,6 +599,9 @@ impl SpuChannel {
             current_sample: 0,
             noise_val: 0,
 
+            interpolation: AudioInterpolation::None,
+            prev_sample: [0; 3],
+
             adpcm_val: 0,
             adpcm_index: 0,
             adpcm_val_loop: 0,
@@ -288,8 +636,6 @@ impl SpuChannel {
         self.fifo_level = 0;
     }
 
-    // DoSaveState
-
     fn fifo_buffer_data(&mut self) {
         let total_len = self.loop_pos + self.length;
 
@@ -324,8 +670,11 @@ impl SpuChannel {
                 self.fifo_write_pos &= 0x7;
             }
         } else {
+            log::log(Event::BiosRead {
+                channel: self.num as u8,
+                address: self.src_address + self.fifo_read_offset,
+            });
             for _ in (0..burst_len).step_by(4) {
-                println!("   WARNING: IN FAKE ZONE");
                 self.fifo_data[self.fifo_write_pos] = 0;
                 self.fifo_read_offset += 4;
                 self.fifo_write_pos += 1;
@@ -359,6 +708,27 @@ impl SpuChannel {
         ret
     }
 
+    /// Volume with the shift applied, 2048 at full volume
+    pub fn effective_volume(&self) -> u32 {
+        (self.volume as u32) << self.volume_shift
+    }
+
+    pub fn pan(&self) -> u8 {
+        self.pan
+    }
+
+    pub fn src_address(&self) -> usize {
+        self.src_address
+    }
+
+    pub fn timer_reload(&self) -> u16 {
+        self.timer_reload
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.control & (1 << 31) != 0
+    }
+
     pub fn adjust_volume(&mut self, multiplier: f32) {
         self.volume = (self.volume as f32 * multiplier) as u8;
     }
@@ -386,10 +756,12 @@ impl SpuChannel {
         if (val & (1 << 31)) != 0 && (old_control & (1 << 31)) == 0 {
             self.key_on = true;
 
-            println!(
-                "{} Set Cnt: Volume: {}, VolumeShift: {}, Pan: {}, KeyOn: {}",
-                self.num, self.volume, self.volume_shift, self.pan, self.key_on,
-            );
+            log::log(Event::KeyOn {
+                channel: self.num as u8,
+                volume: self.volume,
+                volume_shift: self.volume_shift,
+                pan: self.pan,
+            });
         }
     }
 
@@ -420,6 +792,7 @@ impl SpuChannel {
 
         self.noise_val = 0x7FFF;
         self.current_sample = 0;
+        self.prev_sample = [0; 3];
 
         self.fifo_read_pos = 0;
         self.fifo_write_pos = 0;
@@ -528,36 +901,11 @@ impl SpuChannel {
                 self.adpcm_current_byte >>= 4;
             }
 
-            let val = ADPCM_TABLE[self.adpcm_index as usize];
-            let mut diff = val >> 3;
-            if (self.adpcm_current_byte & 0x1) != 0 {
-                diff += val >> 2;
-            }
-            if (self.adpcm_current_byte & 0x2) != 0 {
-                diff += val >> 1;
-            }
-            if (self.adpcm_current_byte & 0x4) != 0 {
-                diff += val;
-            }
-
-            if (self.adpcm_current_byte & 0x8) != 0 {
-                self.adpcm_val -= diff as i32;
-                if self.adpcm_val < -0x7FFF {
-                    self.adpcm_val = -0x7FFF;
-                }
-            } else {
-                self.adpcm_val += diff as i32;
-                if self.adpcm_val > 0x7FFF {
-                    self.adpcm_val = 0x7FFF;
-                }
-            }
-
-            self.adpcm_index += ADPCM_INDEX_TABLE[(self.adpcm_current_byte & 0x7) as usize] as i32;
-            if self.adpcm_index < 0 {
-                self.adpcm_index = 0;
-            } else if self.adpcm_index > 88 {
-                self.adpcm_index = 88;
-            }
+            (self.adpcm_val, self.adpcm_index) = adpcm_step(
+                self.adpcm_val,
+                self.adpcm_index,
+                self.adpcm_current_byte & 0xF,
+            );
 
             if self.pos == (self.loop_pos << 1) as i32 {
                 self.adpcm_val_loop = self.adpcm_val;
@@ -628,6 +976,14 @@ impl SpuChannel {
         while (self.timer >> 16) != 0 {
             self.timer = self.timer_reload as u32 + (self.timer - 0x10000);
 
+            // the interpolated audio will be delayed by a couple samples,
+            // but it's easier to deal with this way
+            if run_type < 3 && self.interpolation != AudioInterpolation::None {
+                self.prev_sample[2] = self.prev_sample[1];
+                self.prev_sample[1] = self.prev_sample[0];
+                self.prev_sample[0] = self.current_sample;
+            }
+
             match run_type {
                 0 => self.next_sample_pcm8(),
                 1 => self.next_sample_pcm16(),
@@ -640,9 +996,44 @@ impl SpuChannel {
 
         //println!("VAL: vol {:?}, {}", self.volume << self.volume_shift, self.current_sample);
 
+        if run_type < 3 && self.interpolation != AudioInterpolation::None {
+            let mut val = self.interpolated_sample();
+            val <<= self.volume_shift;
+            val = val.wrapping_mul(self.volume as i32);
+            return val;
+        }
+
         self.current_adjusted_sample()
     }
 
+    fn interpolated_sample(&self) -> i32 {
+        let val = self.current_sample as i32;
+        let prev = self.prev_sample.map(|sample| sample as i32);
+
+        let elapsed = self.timer as i32 - self.timer_reload as i32;
+        let sample_pos = ((elapsed * 0x100) / (0x10000 - self.timer_reload as i32)).clamp(0, 0xFF);
+
+        match self.interpolation {
+            AudioInterpolation::None => val,
+            AudioInterpolation::Linear => (val * sample_pos + prev[0] * (0xFF - sample_pos)) >> 8,
+            AudioInterpolation::Cosine => {
+                (val * interp_cos(sample_pos) + prev[0] * interp_cos(0xFF - sample_pos)) >> 14
+            }
+            AudioInterpolation::Cubic => {
+                let a0 = val - prev[0] - prev[2] + prev[1];
+                let a1 = prev[2] - prev[1] - a0;
+                let a2 = prev[0] - prev[2];
+                let a3 = prev[1];
+
+                let x1 = sample_pos;
+                let x2 = (sample_pos * sample_pos) >> 8;
+                let x3 = (x2 * sample_pos) >> 8;
+
+                ((a0 * x3) + (a1 * x2) + (a2 * x1) + (a3 << 8)) >> 8
+            }
+        }
+    }
+
     fn current_adjusted_sample(&self) -> i32 {
         self.adjust_sample(self.current_sample)
     }
@@ -680,8 +1071,10 @@ impl SpuChannel {
     }
 }
 
+#[derive(DeBin, SerBin)]
 struct SpuCaptureUnit {
     num: usize,
+    #[nserde(proxy = "DetachedNds")]
     nds: Arc<Mutex<Nds>>,
 
     control: u8,
@@ -736,7 +1129,23 @@ impl SpuCaptureUnit {
         self.fifo_level = 0;
     }
 
-    // DoSavestate
+    fn buffer(&self) -> Vec<u8> {
+        let nds = self.nds.lock().unwrap();
+        (0..self.length)
+            .step_by(4)
+            .flat_map(|offset| nds.arm7_read32(self.dest_address + offset).to_le_bytes())
+            .collect()
+    }
+
+    fn restore_buffer(&mut self, buffer: &[u8]) {
+        let mut nds = self.nds.lock().unwrap();
+        for (offset, word) in buffer.chunks_exact(4).enumerate() {
+            nds.arm7_write32(
+                self.dest_address + offset * 4,
+                u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+            );
+        }
+    }
 
     fn fifo_flush_data(&mut self) {
         for i in 0..4 {
@@ -873,8 +1282,17 @@ impl SpuCaptureUnit {
     }
 }
 
+/// Only capturing the mixer output is supported, not adding channels or capturing them
+fn check_capture_mode(unit: u8, control: u8) {
+    if (control & 0x03) != 0 {
+        log::log(Event::UnsupportedCaptureMode { unit, control });
+    }
+}
+
 const SPU_OUTPUT_BUFFER_SIZE: usize = 2 * 2048;
+#[derive(DeBin, SerBin)]
 pub struct Spu {
+    #[nserde(proxy = "DetachedNds")]
     nds: Arc<Mutex<Nds>>,
     bit_depth: AudioBitDepth,
     output_back_buffer: [i16; 2 * SPU_OUTPUT_BUFFER_SIZE],
@@ -889,9 +1307,24 @@ pub struct Spu {
     master_volume: u8,
     bias: u16,
     apply_bias: bool,
+    output_filters: [OutputFilter; 2],
 
     pub channels: [SpuChannel; 16],
     capture: [SpuCaptureUnit; 2],
+
+    /// Each channel's panned (left, right) contribution to the mixer during the last `mix`
+    pub channel_outputs: [(i32, i32); 16],
+
+    /// What the sequencer last set each channel's volume and pan to, before `channel_mix`
+    requested_volumes: [f32; 16],
+    requested_pans: [u8; 16],
+    /// (gain, pan offset) the player's mixer applies on top, see `set_channel_mix`
+    channel_mix: [(f32, i32); 16],
+
+    /// Samples mixed so far
+    sample_count: u64,
+    #[nserde(proxy = "DetachedTracer")]
+    tracer: Option<SpuTracer>,
 }
 
 impl Spu {
@@ -910,7 +1343,8 @@ impl Spu {
             control: 0,
             master_volume: 0,
             bias: 0,
-            apply_bias: false,
+            apply_bias: depth == AudioBitDepth::_10bit,
+            output_filters: Default::default(),
 
             channels: [
                 SpuChannel::new(0, nds.clone()),
@@ -937,6 +1371,15 @@ impl Spu {
                 //SpuCaptureUnit::new(0, nds),
                 //SpuCaptureUnit::new(1, nds),
             ],
+
+            channel_outputs: [(0, 0); 16],
+
+            requested_volumes: [0.0; 16],
+            requested_pans: [0; 16],
+            channel_mix: [(1.0, 0); 16],
+
+            sample_count: 0,
+            tracer: None,
         }
     }
 
@@ -955,15 +1398,148 @@ impl Spu {
         self.capture[1].reset();
     }
 
+    /// Switches between the clean output and how it sounded coming out of a DS
+    pub fn set_bit_depth(&mut self, depth: AudioBitDepth) {
+        self.bit_depth = depth;
+        self.apply_bias = depth == AudioBitDepth::_10bit;
+        self.output_filters = Default::default();
+    }
+
+    pub fn bit_depth(&self) -> AudioBitDepth {
+        self.bit_depth
+    }
+
+    /// The bus channels read their samples from
+    pub fn nds(&self) -> Arc<Mutex<Nds>> {
+        self.nds.clone()
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: AudioInterpolation) {
+        for channel in &mut self.channels {
+            channel.interpolation = interpolation;
+        }
+    }
+
+    pub fn interpolation(&self) -> AudioInterpolation {
+        self.channels[0].interpolation
+    }
+
     fn stop(&mut self) {
         self.output_back_buffer_write_position = 0;
         self.output_front_buffer_read_position = 0;
         self.output_front_buffer_write_position = 0;
     }
 
-    // DoSaveState
+    /// Appends the SPU's registers and internal state to `s`, along with the contents
+    /// of the capture buffers since they live in RAM
+    pub fn save_state(&self, s: &mut Vec<u8>) {
+        self.ser_bin(s);
+        for capture in &self.capture {
+            capture.buffer().ser_bin(s);
+        }
+    }
+
+    pub fn load_state(&mut self, o: &mut usize, d: &[u8]) -> Result<(), DeBinErr> {
+        let mut state = Spu::de_bin(o, d)?;
+        let buffers: [Vec<u8>; 2] = DeBin::de_bin(o, d)?;
+
+        state.nds = self.nds.clone();
+        state.tracer = self.tracer.take();
+        for channel in &mut state.channels {
+            channel.nds = self.nds.clone();
+        }
+        for (capture, buffer) in state.capture.iter_mut().zip(&buffers) {
+            capture.nds = self.nds.clone();
+            capture.restore_buffer(buffer);
+        }
+
+        *self = state;
+        Ok(())
+    }
+
+    /// Starts recording every register write and channel helper call
+    pub fn start_trace(&mut self) {
+        let mut initial_state = Vec::new();
+        self.save_state(&mut initial_state);
+        self.tracer = Some(SpuTracer {
+            start: self.sample_count,
+            initial_state,
+            events: Vec::new(),
+        });
+    }
+
+    /// Stops recording and returns what happened since `start_trace`
+    pub fn stop_trace(&mut self) -> Option<SpuTrace> {
+        let tracer = self.tracer.take()?;
+        Some(SpuTrace {
+            initial_state: Some(tracer.initial_state),
+            events: tracer.events,
+            length: self.sample_count - tracer.start,
+        })
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    fn trace(&mut self, call: SpuCall) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.events.push(TraceEvent {
+                sample: self.sample_count - tracer.start,
+                call,
+            });
+        }
+    }
+
+    /// Makes a traced call again
+    pub fn apply(&mut self, call: SpuCall) {
+        match call {
+            SpuCall::Write { address, value } => match value {
+                Write::u8(val) => self.write8(address, val),
+                Write::u16(val) => self.write16(address, val),
+                Write::u32(val) => self.write32(address, val),
+            },
+            SpuCall::SetChannelVolume { channel, volume } => {
+                self.set_channel_volume(channel as usize, volume)
+            }
+            SpuCall::SetChannelTimerReload {
+                channel,
+                timer_reload,
+            } => self.set_channel_timer_reload(channel as usize, timer_reload),
+            SpuCall::SetChannelLoopPos { channel, loop_pos } => {
+                self.set_channel_loop_pos(channel as usize, loop_pos as usize)
+            }
+            SpuCall::SetChannelLength { channel, length } => {
+                self.set_channel_length(channel as usize, length as usize)
+            }
+            SpuCall::SetChannelSrcAddress {
+                channel,
+                src_address,
+            } => self.set_channel_src_address(channel as usize, src_address as usize),
+            SpuCall::SetChannelPan { channel, pan } => {
+                self.write_channel_pan(channel as usize, pan)
+            }
+            SpuCall::ChannelPlayNote {
+                channel,
+                is_repeating,
+            } => self.channel_play_note(channel as usize, is_repeating),
+            SpuCall::ChannelPlayPsg {
+                channel,
+                table_index,
+            } => self.channel_play_psg(channel as usize, table_index),
+            SpuCall::ChannelPlayNoise { channel } => self.channel_play_noise(channel as usize),
+            SpuCall::ChannelPlaySample {
+                channel,
+                is_repeating,
+                format,
+            } => self.channel_play_sample(channel as usize, is_repeating, format),
+        }
+    }
 
     pub fn mix(&mut self, dummy: u32) -> (i16, i16) {
+        self.sample_count += 1;
+        self.channel_outputs = [(0, 0); 16];
+
         let mut left = 0;
         let mut right = 0;
         let mut left_output = 0;
@@ -999,14 +1575,15 @@ impl Spu {
                 //assert_eq!(ch1, -7602176);
             }
 
-            self.channels[0].pan_output(ch0, &mut left, &mut right);
-            self.channels[2].pan_output(ch2, &mut left, &mut right);
+            let outputs = &mut self.channel_outputs;
+            self.channels[0].pan_output(ch0, &mut outputs[0].0, &mut outputs[0].1);
+            self.channels[2].pan_output(ch2, &mut outputs[2].0, &mut outputs[2].1);
 
             if (self.control & (1 << 12)) == 0 {
-                self.channels[1].pan_output(ch1, &mut left, &mut right);
+                self.channels[1].pan_output(ch1, &mut outputs[1].0, &mut outputs[1].1);
             }
             if (self.control & (1 << 13)) == 0 {
-                self.channels[3].pan_output(ch3, &mut left, &mut right);
+                self.channels[3].pan_output(ch3, &mut outputs[3].0, &mut outputs[3].1);
             }
 
             for i in 4..16 {
@@ -1029,7 +1606,13 @@ impl Spu {
                         //);
                     }
                 }
-                chan.pan_output(channel, &mut left, &mut right);
+                let (channel_left, channel_right) = &mut self.channel_outputs[i];
+                chan.pan_output(channel, channel_left, channel_right);
+            }
+
+            for (channel_left, channel_right) in &self.channel_outputs {
+                left += channel_left;
+                right += channel_right;
             }
 
             // sound capture
@@ -1113,11 +1696,8 @@ impl Spu {
 
         //println!("lrlrlr {}, {}", left_output >> 1, right_output >> 1);
 
-        left_output = (left_output * self.master_volume as i32) >> 7;
-        right_output = (right_output * self.master_volume as i32) >> 7;
-
-        left_output >>= 8;
-        right_output >>= 8;
+        left_output = self.apply_master_volume(left_output);
+        right_output = self.apply_master_volume(right_output);
 
         //println!("lrlrlr2 {}, {}", left_output >> 1, right_output >> 1);
 
@@ -1143,6 +1723,9 @@ impl Spu {
         if let AudioBitDepth::_10bit = self.bit_depth {
             left_output &= 0xFFFFFFC0u32 as i32;
             right_output &= 0xFFFFFFC0u32 as i32;
+
+            left_output = self.output_filters[0].run(left_output);
+            right_output = self.output_filters[1].run(right_output);
         }
 
         //println!(
@@ -1173,6 +1756,29 @@ impl Spu {
         (left_write, right_write)
     }
 
+    /// Samples between a channel entering the mixer and it reaching the output
+    pub fn output_latency(&self) -> usize {
+        // Output comes straight from the mixer unless channels 1/3 play back a capture buffer
+        if (self.control & 0x0F00) == 0 {
+            return 0;
+        }
+
+        let capture = &self.capture[0];
+        let buffer_samples = if (capture.control & 0x08) != 0 {
+            capture.length
+        } else {
+            capture.length / 2
+        };
+
+        // Each captured sample is played back one full buffer plus 2 samples later
+        buffer_samples + 2
+    }
+
+    /// Scales a mixer level by the master volume down to the 16-bit output range
+    pub fn apply_master_volume(&self, sample: i32) -> i32 {
+        ((sample * self.master_volume as i32) >> 7) >> 8
+    }
+
     pub fn transfer_output(&mut self) {
         for i in (0..self.output_back_buffer_write_position).step_by(2) {
             self.output_front_buffer[self.output_front_buffer_write_position] =
@@ -1249,6 +1855,10 @@ impl Spu {
     }
 
     pub fn write8(&mut self, address: u32, val: u8) {
+        self.trace(SpuCall::Write {
+            address,
+            value: Write::u8(val),
+        });
         if address < 0x04000500 {
             let chan = &mut self.channels[((address >> 4) & 0xF) as usize];
 
@@ -1263,7 +1873,9 @@ impl Spu {
             match address {
                 0x04000500 => {
                     self.control = (self.control & 0xBF00) | (val & 0x7F) as u16;
-                    println!("Controlset: {}", self.control);
+                    log::log(Event::SoundControl {
+                        control: self.control,
+                    });
                     self.master_volume = (self.control & 0x7F) as u8;
                     if self.master_volume == 127 {
                         self.master_volume += 1;
@@ -1274,15 +1886,11 @@ impl Spu {
                 }
                 0x04000508 => {
                     self.capture[0].set_control(val);
-                    if (val & 0x03) != 0 {
-                        println!("Warning !! UNSUPPORTED SPU CAPTURE MODE {}\n", val);
-                    }
+                    check_capture_mode(0, val);
                 }
                 0x04000509 => {
                     self.capture[1].set_control(val);
-                    if (val & 0x03) != 0 {
-                        println!("Warning !! UNSUPPORTED SPU CAPTURE MODE {}\n", val);
-                    }
+                    check_capture_mode(1, val);
                 }
                 _ => {}
             }
@@ -1303,6 +1911,10 @@ impl Spu {
     }
 
     pub fn write16(&mut self, address: u32, val: u16) {
+        self.trace(SpuCall::Write {
+            address,
+            value: Write::u16(val),
+        });
         if address < 0x04000500 {
             let chan = &mut self.channels[((address >> 4) & 0xF) as usize];
 
@@ -1332,7 +1944,9 @@ impl Spu {
             match address {
                 0x04000500 => {
                     self.control = val & 0xBF7F;
-                    println!("Controlset: {}", self.control);
+                    log::log(Event::SoundControl {
+                        control: self.control,
+                    });
                     self.master_volume = (self.control & 0x7F) as u8;
                     if self.master_volume == 127 {
                         self.master_volume += 1;
@@ -1344,9 +1958,8 @@ impl Spu {
                 0x04000508 => {
                     self.capture[0].set_control((val & 0xFF) as u8);
                     self.capture[1].set_control((val >> 8) as u8);
-                    if (val & 0x0303) != 0 {
-                        println!("Warning !! UNSUPPORTED SPU CAPTURE MODE {}\n", val);
-                    }
+                    check_capture_mode(0, (val & 0xFF) as u8);
+                    check_capture_mode(1, ((val >> 8) & 0xFF) as u8);
                 }
                 0x04000514 => {
                     self.capture[0].set_length(val as u32);
@@ -1373,6 +1986,10 @@ impl Spu {
     }
 
     pub fn write32(&mut self, address: u32, mut val: u32) {
+        self.trace(SpuCall::Write {
+            address,
+            value: Write::u32(val),
+        });
         if address < 0x04000500 {
             let chan = &mut self.channels[((address >> 4) & 0xF) as usize];
 
@@ -1401,7 +2018,9 @@ impl Spu {
             match address {
                 0x04000500 => {
                     self.control = (val & 0xBF7F) as u16;
-                    println!("Controlset: {}", self.control);
+                    log::log(Event::SoundControl {
+                        control: self.control,
+                    });
                     self.master_volume = (self.control & 0x7F) as u8;
                     if self.master_volume == 127 {
                         self.master_volume += 1;
@@ -1413,9 +2032,8 @@ impl Spu {
                 0x04000508 => {
                     self.capture[0].set_control((val & 0xFF) as u8);
                     self.capture[1].set_control((val >> 8) as u8);
-                    if (val & 0x0303) != 0 {
-                        println!("Warning !! UNSUPPORTED SPU CAPTURE MODE {}\n", val);
-                    }
+                    check_capture_mode(0, (val & 0xFF) as u8);
+                    check_capture_mode(1, ((val >> 8) & 0xFF) as u8);
                 }
                 0x04000510 => self.capture[0].set_dest_address(val),
                 0x04000514 => {
@@ -1449,16 +2067,40 @@ impl Spu {
         volume: u32,
         volume_multiplier: f32,
     ) {
-        fn adjust_volume(volume: u32, volume_multiplier: f32) -> u32 {
-            (volume as f32 * volume_multiplier) as u32
+        self.requested_volumes[channel] = volume as f32 * volume_multiplier;
+        self.set_channel_volume(channel, self.mixed_volume(channel));
+    }
+
+    /// Scales and offsets everything the sequencer sets `channel` to from here on, and what
+    /// it's already playing. A gain of 1.0 and pan offset of 0 leave it untouched.
+    pub fn set_channel_mix(&mut self, channel: usize, gain: f32, pan_offset: i32) {
+        if self.channel_mix[channel] == (gain, pan_offset) {
+            return;
         }
-        let adjust_vol = |volume: u32| -> u32 { adjust_volume(volume, volume_multiplier) };
-        self.set_channel_volume(channel, adjust_vol(volume));
+        self.channel_mix[channel] = (gain, pan_offset);
+        self.set_channel_volume(channel, self.mixed_volume(channel));
+        self.write_channel_pan(channel, self.mixed_pan(channel));
+    }
 
-        //println!("{} VOL: {}", channel, adjust_vol(volume));
+    fn mixed_volume(&self, channel: usize) -> u32 {
+        let gain = self.channel_mix[channel].0;
+        // The most volume and volume shift can express
+        (self.requested_volumes[channel] * gain).min(2047.0) as u32
+    }
+
+    fn mixed_pan(&self, channel: usize) -> u8 {
+        let pan = self.requested_pans[channel];
+        match self.channel_mix[channel].1 {
+            0 => pan,
+            pan_offset => (pan as i32 + pan_offset).clamp(0, 127) as u8,
+        }
     }
 
     fn set_channel_volume(&mut self, channel: usize, mut volume: u32) {
+        self.trace(SpuCall::SetChannelVolume {
+            channel: channel as u8,
+            volume,
+        });
         let mut volume_shift_index = 3;
         if volume > 127 {
             volume >>= 2;
@@ -1487,73 +2129,131 @@ impl Spu {
     }
 
     pub fn set_channel_timer_reload(&mut self, channel: usize, timer_reload: u32) {
+        self.trace(SpuCall::SetChannelTimerReload {
+            channel: channel as u8,
+            timer_reload,
+        });
         self.channels[channel].set_timer_reload(timer_reload);
         //println!("TM: {}", (timer_reload & 0xFFFF) as u16);
     }
 
     pub fn set_channel_loop_pos(&mut self, channel: usize, loop_pos: usize) {
+        self.trace(SpuCall::SetChannelLoopPos {
+            channel: channel as u8,
+            loop_pos: loop_pos as u32,
+        });
         self.channels[channel].loop_pos = loop_pos;
         //println!("LP: {}", loop_pos);
     }
 
     pub fn set_channel_length(&mut self, channel: usize, length: usize) {
+        self.trace(SpuCall::SetChannelLength {
+            channel: channel as u8,
+            length: length as u32,
+        });
         self.channels[channel].length = length;
         // println!("LEN: {}", length);
     }
 
     pub fn set_channel_src_address(&mut self, channel: usize, src_address: usize) {
+        self.trace(SpuCall::SetChannelSrcAddress {
+            channel: channel as u8,
+            src_address: src_address as u32,
+        });
         self.channels[channel].src_address = src_address;
         //println!("SRC: {}", src_address);
     }
 
     pub fn set_adjusted_channel_pan(&mut self, channel: usize, pan: u8, pan_addition: i32) {
-        let adjust_pan =
-            |pan: u8| -> u8 { (pan as i32 + pan_addition).max(0).min(u8::MAX as i32) as u8 };
-        let control = self.channels[channel].control;
-        let control = (control & 0xFF00FFFF) | ((adjust_pan(pan) as u32) << 16);
-
-        println!("PANO: {}", adjust_pan(pan));
-
-        self.channels[channel].set_control(control)
+        let pan = (pan as i32 + pan_addition).max(0).min(u8::MAX as i32) as u8;
+        log::log(Event::ChannelPan {
+            channel: channel as u8,
+            pan,
+        });
+        self.set_channel_pan(channel, pan);
     }
 
     pub fn set_channel_pan(&mut self, channel: usize, pan: u8) {
+        self.requested_pans[channel] = pan;
+        self.write_channel_pan(channel, self.mixed_pan(channel));
+    }
+
+    fn write_channel_pan(&mut self, channel: usize, pan: u8) {
+        self.trace(SpuCall::SetChannelPan {
+            channel: channel as u8,
+            pan,
+        });
         let control = self.channels[channel].control;
         let control = (control & 0xFF00FFFF) | ((pan as u32) << 16);
 
-        //println!("PAN2");
         self.channels[channel].set_control(control)
     }
 
     pub fn channel_play_note(&mut self, channel: usize, is_repeating: bool) {
+        self.trace(SpuCall::ChannelPlayNote {
+            channel: channel as u8,
+            is_repeating,
+        });
         //self.channels[channel].key_on = true;
 
-        self.channels[channel].set_control(self.channels[channel].control & 0x00FFFFFF);
-
         if is_repeating {
-            self.channels[channel]
-                .set_control((self.channels[channel].control & 0x00FFFFFF) | 200 << 24)
+            self.key_on(channel, 200)
         } else {
-            self.channels[channel]
-                .set_control((self.channels[channel].control & 0x00FFFFFF) | 208 << 24)
+            self.key_on(channel, 208)
         }
-        println!("{} PLAY {}", channel, self.channels[channel].control)
+    }
+
+    /// Keys `channel` on playing its sample as `format`, where `channel_play_note` always
+    /// plays ADPCM like the game does
+    pub fn channel_play_sample(
+        &mut self,
+        channel: usize,
+        is_repeating: bool,
+        format: SampleFormat,
+    ) {
+        self.trace(SpuCall::ChannelPlaySample {
+            channel: channel as u8,
+            is_repeating,
+            format,
+        });
+
+        let repeat = if is_repeating { 1 } else { 2 };
+        self.key_on(channel, 0x80 | format.control_bits() << 5 | repeat << 3);
+    }
+
+    /// Restarts `channel` with `start_bits` as the top byte of its control register
+    fn key_on(&mut self, channel: usize, start_bits: u32) {
+        self.channels[channel].set_control(self.channels[channel].control & 0x00FFFFFF);
+        self.channels[channel]
+            .set_control((self.channels[channel].control & 0x00FFFFFF) | start_bits << 24);
+        log::log(Event::ChannelPlay {
+            channel: channel as u8,
+            control: self.channels[channel].control,
+        });
     }
 
     pub fn channel_play_psg(&mut self, channel: usize, table_index: u8) {
+        self.trace(SpuCall::ChannelPlayPsg {
+            channel: channel as u8,
+            table_index,
+        });
         //self.channels[channel].key_on = true;
 
         self.channels[channel].set_control(self.channels[channel].control & 0x00FFFFFF);
         self.channels[channel].set_control(
             (self.channels[channel].control & 0x00FFFFFF) | (224 + table_index as u32) << 24,
         );
-        println!(
-            "{} PLAYPSG {} (ti: {})",
-            channel, self.channels[channel].control, table_index
-        )
+        log::log(Event::ChannelPlayPsg {
+            channel: channel as u8,
+            control: self.channels[channel].control,
+            table_index,
+        });
     }
 
     pub fn channel_play_noise(&mut self, channel: usize) {
+        self.trace(SpuCall::ChannelPlayNoise {
+            channel: channel as u8,
+        });
         self.channels[channel].set_control(self.channels[channel].control & 0x00FFFFFF);
         self.channels[channel]
             .set_control((self.channels[channel].control & 0x00FFFFFF) | 224 << 24);
@@ -1586,7 +2286,7 @@ mod tests {
         ram.append(&mut dogadpcm);
         ram.resize(4 * 1024 * 1024, 0);
 
-        let nds = Arc::new(Mutex::new(Nds::new(ram)));
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
 
         let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
 
@@ -1634,7 +2334,7 @@ mod tests {
         ram.append(&mut dogadpcm);
         ram.resize(4 * 1024 * 1024, 0);
 
-        let nds = Arc::new(Mutex::new(Nds::new(ram)));
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
 
         let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
 
@@ -1687,7 +2387,7 @@ mod tests {
         ram.append(&mut dogadpcm);
         ram.resize(4 * 1024 * 1024, 0);
 
-        let nds = Arc::new(Mutex::new(Nds::new(ram)));
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
 
         let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
 
@@ -1796,7 +2496,7 @@ mod tests {
         ram.append(&mut dogadpcm);
         ram.resize(4 * 1024 * 1024, 0);
 
-        let nds = Arc::new(Mutex::new(Nds::new(ram)));
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
 
         let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
 
@@ -1887,4 +2587,173 @@ mod tests {
 
         assert_eq!(left, 1);
     }
+
+    /// A rising PCM16 ramp played for 32 samples, `timer_reload` sets how many runs each
+    /// sample lasts
+    fn render_ramp(interpolation: AudioInterpolation, timer_reload: u16) -> Vec<i32> {
+        let mut ram = vec![0; 4 * 1024 * 1024];
+        for i in 0..64 {
+            let val = (i * 256) as i16;
+            ram[i * 2..i * 2 + 2].copy_from_slice(&val.to_le_bytes());
+        }
+
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
+        let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
+        spu.set_interpolation(interpolation);
+
+        // PCM16, one-shot
+        spu.write32(0x04000040, 0x3000007F);
+        spu.write32(0x04000044, 0x02000000);
+        spu.write16(0x04000048, timer_reload);
+        spu.write32(0x0400004C, 32);
+        spu.write8(0x04000043, 0xB0);
+
+        // Each run moves the timer on by 512
+        let runs = 32 * (0x10000 - timer_reload as usize) / 512;
+        (0..runs).map(|_| spu.channels[4].do_run() >> 11).collect()
+    }
+
+    #[test]
+    fn test_interpolation() {
+        // Half the mixer rate
+        let held = render_ramp(AudioInterpolation::None, 0xFC00);
+        assert!(held.iter().all(|val| val % 256 == 0));
+
+        let linear = render_ramp(AudioInterpolation::Linear, 0xFC00);
+        assert!(linear.iter().any(|val| val % 256 != 0));
+        assert!(linear.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_smooth_interpolation() {
+        // An eighth of the mixer rate, so each sample lasts 8 runs
+        let held = render_ramp(AudioInterpolation::None, 0xF000);
+        let steps: Vec<usize> = (1..held.len())
+            .filter(|&i| held[i] != held[i - 1])
+            .collect();
+        assert!(steps.len() > 20);
+
+        // Cosine runs a sample behind and cubic two, once past the silence before the ramp
+        for (interpolation, delay) in [
+            (AudioInterpolation::Cosine, 8),
+            (AudioInterpolation::Cubic, 16),
+        ] {
+            let smooth = render_ramp(interpolation, 0xF000);
+            for &step in &steps[2..] {
+                assert_eq!(smooth[step], held[step - delay], "{:?}", interpolation);
+            }
+            let ramp = &smooth[steps[2]..];
+            assert!(
+                ramp.windows(2)
+                    .all(|pair| (0..128).contains(&(pair[1] - pair[0]))),
+                "{:?}",
+                interpolation
+            );
+        }
+    }
+
+    fn render_dc(depth: AudioBitDepth) -> Vec<i16> {
+        let mut ram = vec![0; 4 * 1024 * 1024];
+        for i in 0..64 {
+            ram[i * 2..i * 2 + 2].copy_from_slice(&0x4000i16.to_le_bytes());
+        }
+
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
+        let mut spu = Spu::new(nds, AudioBitDepth::_16bit);
+        spu.set_bit_depth(depth);
+        spu.write16(0x04000500, 0x807F);
+        spu.write16(0x04000504, 0x200);
+
+        // PCM16, looping, centered
+        spu.write32(0x04000040, 0x2840007F);
+        spu.write32(0x04000044, 0x02000000);
+        spu.write16(0x04000048, 0xFE00);
+        spu.write32(0x0400004C, 32);
+        spu.write8(0x04000043, 0xA8);
+
+        (0..32768).map(|_| spu.mix(1).0).collect()
+    }
+
+    #[test]
+    fn test_ds_output_stage() {
+        let clean = render_dc(AudioBitDepth::_16bit);
+        let level = *clean.last().unwrap();
+        assert!(level > 1000);
+        assert!(clean[100..].iter().all(|&val| val == level));
+
+        // The step is smoothed on the way up, then the DC drains away
+        let ds = render_dc(AudioBitDepth::_10bit);
+        let peak = ds.iter().position(|&val| val == *ds.iter().max().unwrap());
+        assert!(peak.unwrap() > 5);
+        assert!(ds.last().unwrap().abs() < level / 10);
+    }
+
+    #[test]
+    fn test_decode_adpcm() {
+        let mut ram = vec![0; 4 * 1024 * 1024];
+        ram[4..8].copy_from_slice(&[0x77, 0x77, 0x77, 0x77]);
+
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
+        let decoded = decode_sample(nds, SampleFormat::Adpcm, 0x02000000, 4, 4);
+
+        assert_eq!(decoded.samples.len(), 8);
+        assert_eq!(decoded.loop_start, 0);
+        assert_eq!(decoded.samples[..2], [11, 41]);
+    }
+
+    #[test]
+    fn test_adpcm_round_trip() {
+        // A chirp, rising in pitch and loudness
+        let pcm: Vec<i16> = (0..1003)
+            .map(|i| {
+                let t = i as f32 / 1003.0;
+                ((t * t * 200.0).sin() * t * 20000.0) as i16
+            })
+            .collect();
+        let encoded = encode_adpcm(&pcm, Some(501));
+        assert_eq!(encoded.padding, 3);
+        assert_eq!(encoded.loop_pos, ADPCM_HEADER_SIZE + 252);
+        assert_eq!(encoded.loop_pos % 4, 0);
+        assert_eq!(encoded.length, 248);
+
+        let mut ram = vec![0; 4 * 1024 * 1024];
+        ram[..encoded.data.len()].copy_from_slice(&encoded.data);
+        let nds = Arc::new(Mutex::new(Nds::new(ram).unwrap()));
+        let decoded = decode_sample(
+            nds.clone(),
+            SampleFormat::Adpcm,
+            0x02000000,
+            encoded.loop_pos,
+            encoded.length,
+        );
+        assert_eq!(decoded.loop_start, 504);
+        assert_eq!(decoded.samples.len(), 1000);
+        let (error, power) = decoded.samples[3..].iter().zip(&pcm).fold(
+            (0.0, 0.0),
+            |(error, power), (&decoded, &sample)| {
+                let diff = decoded as f64 - sample as f64;
+                (error + diff * diff, power + sample as f64 * sample as f64)
+            },
+        );
+        // Signal to noise in dB
+        assert!(10.0 * (power / error).log10() > 25.0);
+
+        // Looping comes back to the same samples
+        let mut channel = SpuChannel::new(0, nds);
+        channel.control = (1 << 31) | (2 << 29) | (1 << 27);
+        channel.src_address = 0x02000000;
+        channel.loop_pos = encoded.loop_pos;
+        channel.length = encoded.length;
+        channel.start();
+        let mut looped = Vec::new();
+        while looped.len() < 1000 + 496 * 2 {
+            channel.next_sample_adpcm();
+            if channel.pos >= 8 || !looped.is_empty() {
+                looped.push(channel.current_sample);
+            }
+        }
+        assert_eq!(looped[..1000], decoded.samples[..]);
+        assert_eq!(looped[1000..1496], decoded.samples[504..]);
+        assert_eq!(looped[1496..], decoded.samples[504..]);
+    }
 }
@@ -0,0 +1,166 @@
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use nanoserde::{DeBin, SerBin};
+
+use crate::spu::{AudioBitDepth, Nds, Spu, TraceEvent};
+
+const MAGIC: &[u8; 4] = b"WTRC";
+const VERSION: u8 = 2;
+
+/// Everything the SPU was told to do over `length` samples
+#[derive(SerBin, DeBin, Debug, Clone, PartialEq)]
+pub struct SpuTrace {
+    /// `Spu::save_state` from when the trace started. Traces captured from an emulator
+    /// start from a reset SPU instead, and have to include its setup writes.
+    pub initial_state: Option<Vec<u8>>,
+    /// Sorted by sample
+    pub events: Vec<TraceEvent>,
+    pub length: u64,
+}
+
+impl SpuTrace {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        self.ser_bin(&mut bytes);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<SpuTrace> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not an SPU trace".to_string()));
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(invalid(format!(
+                "unsupported SPU trace version {}",
+                bytes[MAGIC.len()]
+            )));
+        }
+
+        let trace = SpuTrace::deserialize_bin(&bytes[MAGIC.len() + 1..])
+            .map_err(|err| invalid(format!("corrupt SPU trace: {:?}", err)))?;
+        if !trace
+            .events
+            .windows(2)
+            .all(|pair| pair[0].sample <= pair[1].sample)
+        {
+            return Err(invalid("SPU trace events are out of order".to_string()));
+        }
+
+        Ok(trace)
+    }
+
+    pub fn write_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.encode())
+    }
+
+    pub fn read_file(path: &Path) -> io::Result<SpuTrace> {
+        SpuTrace::decode(&std::fs::read(path)?)
+    }
+}
+
+/// Drives a bare `Spu` from a trace, with no sequencer involved
+pub struct TraceReplayer {
+    spu: Spu,
+    trace: SpuTrace,
+    next_event: usize,
+    sample: u64,
+}
+
+impl TraceReplayer {
+    /// `nds` has to hold the same samples the trace was recorded against
+    pub fn new(nds: Nds, trace: SpuTrace) -> io::Result<TraceReplayer> {
+        let mut spu = Spu::new(Arc::new(Mutex::new(nds)), AudioBitDepth::_16bit);
+        if let Some(state) = &trace.initial_state {
+            spu.load_state(&mut 0, state).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt SPU trace state: {:?}", err),
+                )
+            })?;
+        }
+
+        Ok(TraceReplayer {
+            spu,
+            trace,
+            next_event: 0,
+            sample: 0,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.sample >= self.trace.length
+    }
+
+    /// Fills interleaved stereo `data` with the next samples at `SAMPLE_RATE`,
+    /// silence once the trace is over
+    pub fn render(&mut self, data: &mut [f32]) {
+        for samples_out in data.chunks_mut(2) {
+            if self.is_finished() {
+                samples_out.iter_mut().for_each(|sample| *sample = 0.0);
+                continue;
+            }
+
+            while let Some(event) = self.trace.events.get(self.next_event) {
+                if event.sample > self.sample {
+                    break;
+                }
+                self.spu.apply(event.call);
+                self.next_event += 1;
+            }
+
+            let (left_output, right_output) = self.spu.mix(1);
+            samples_out[0] = left_output as f32 / i16::MAX as f32;
+            samples_out[1] = right_output as f32 / i16::MAX as f32;
+            self.sample += 1;
+        }
+    }
+}
+
+/// Replays the whole of `trace`, interleaved stereo at `SAMPLE_RATE`
+pub fn replay_trace(nds: Nds, trace: SpuTrace) -> io::Result<Vec<f32>> {
+    let mut output = vec![0.0; trace.length as usize * 2];
+    TraceReplayer::new(nds, trace)?.render(&mut output);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, player::Player};
+
+    #[test]
+    fn test_replay_matches_player() {
+        let ram = fixtures::patterned_ram();
+        let mut mio = fixtures::mio();
+        fixtures::set_instrument(&mut mio, 0, 0);
+        fixtures::set_instrument(&mut mio, 1, 40);
+        fixtures::set_instrument(&mut mio, fixtures::DRUM_TRACK, 0);
+        for time in 0..8 {
+            fixtures::set_note(&mut mio, 0, time * 4, time as u8);
+            fixtures::set_note(&mut mio, 1, time * 4 + 2, 12);
+            fixtures::set_drum(&mut mio, 0, time * 4, 0);
+        }
+
+        let mut player = Player::new(&mio, &ram, 1.0).unwrap();
+        let mut expected = vec![0.0; 20000];
+        player.render_native(&mut expected);
+
+        player.start_trace();
+        player.render_native(&mut expected);
+        let trace = SpuTrace::decode(&player.stop_trace().encode()).unwrap();
+
+        assert_eq!(trace.length, 10000);
+        assert!(!trace.events.is_empty());
+        let output = replay_trace(Nds::new(ram).unwrap(), trace).unwrap();
+        assert!(expected.iter().any(|&sample| sample != 0.0));
+        assert_eq!(expected, output);
+    }
+}
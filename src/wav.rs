@@ -0,0 +1,175 @@
+use std::io;
+
+/// Encodes interleaved samples in the -1.0..=1.0 range as a 16-bit PCM WAV file
+pub fn encode_wav(samples: &[f32], channels: u16, sample_rate: u32) -> Vec<u8> {
+    encode_wav_i16(&to_pcm16(samples), channels, sample_rate)
+}
+
+/// The 16-bit samples `encode_wav` writes
+pub fn to_pcm16(samples: &[f32]) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect()
+}
+
+pub fn encode_wav_i16(samples: &[i16], channels: u16, sample_rate: u32) -> Vec<u8> {
+    let mut chunks = Vec::new();
+    write_fmt_chunk(&mut chunks, channels, sample_rate);
+    write_data_chunk(&mut chunks, samples);
+
+    riff(&chunks)
+}
+
+pub struct DecodedWav {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved
+    pub samples: Vec<i16>,
+    /// The first loop of a `smpl` chunk, in frames
+    pub sample_loop: Option<SampleLoop>,
+}
+
+/// Reads a 16-bit PCM WAV file such as the ones `encode_wav` and `encode_sample_wav` write
+pub fn decode_wav(bytes: &[u8]) -> io::Result<DecodedWav> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    };
+
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut format = None;
+    let mut samples = None;
+    let mut sample_loop = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let size = u32_at(offset + 4) as usize;
+        let body = offset + 8;
+        if body + size > bytes.len() {
+            return Err(invalid("truncated WAV chunk"));
+        }
+
+        match &bytes[offset..offset + 4] {
+            b"fmt " if size >= 16 => {
+                if u16_at(body) != 1 || u16_at(body + 14) != 16 {
+                    return Err(invalid("only 16-bit PCM WAV files are supported"));
+                }
+                format = Some((u16_at(body + 2), u32_at(body + 4)));
+            }
+            b"data" => {
+                samples = Some(
+                    bytes[body..body + size]
+                        .chunks_exact(2)
+                        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+                        .collect(),
+                );
+            }
+            b"smpl" if size >= 36 + 24 && u32_at(body + 28) > 0 => {
+                sample_loop = Some(SampleLoop {
+                    start: u32_at(body + 44) as usize,
+                    end: u32_at(body + 48) as usize,
+                });
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        offset = body + size + (size & 1);
+    }
+
+    match (format, samples) {
+        (Some((channels, sample_rate)), Some(samples)) => Ok(DecodedWav {
+            channels,
+            sample_rate,
+            samples,
+            sample_loop,
+        }),
+        _ => Err(invalid("WAV file is missing its fmt or data chunk")),
+    }
+}
+
+/// Loop points in samples, `end` is the last sample played before jumping back to `start`
+#[derive(Debug, Clone, Copy)]
+pub struct SampleLoop {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Encodes a mono instrument sample with a `smpl` chunk carrying its root note and loop
+pub fn encode_sample_wav(
+    samples: &[i16],
+    sample_rate: u32,
+    unity_note: u8,
+    sample_loop: Option<SampleLoop>,
+) -> Vec<u8> {
+    let mut chunks = Vec::new();
+    write_fmt_chunk(&mut chunks, 1, sample_rate);
+    write_data_chunk(&mut chunks, samples);
+
+    let loops: Vec<SampleLoop> = sample_loop.into_iter().collect();
+    chunks.extend_from_slice(b"smpl");
+    chunks.extend_from_slice(&(36 + 24 * loops.len() as u32).to_le_bytes());
+    // Manufacturer, product
+    chunks.extend_from_slice(&0u32.to_le_bytes());
+    chunks.extend_from_slice(&0u32.to_le_bytes());
+    // Sample period in nanoseconds
+    chunks.extend_from_slice(&(1_000_000_000 / sample_rate).to_le_bytes());
+    chunks.extend_from_slice(&(unity_note as u32).to_le_bytes());
+    // Pitch fraction, SMPTE format, SMPTE offset
+    chunks.extend_from_slice(&0u32.to_le_bytes());
+    chunks.extend_from_slice(&0u32.to_le_bytes());
+    chunks.extend_from_slice(&0u32.to_le_bytes());
+    chunks.extend_from_slice(&(loops.len() as u32).to_le_bytes());
+    // Sampler data
+    chunks.extend_from_slice(&0u32.to_le_bytes());
+    for (id, sample_loop) in loops.iter().enumerate() {
+        chunks.extend_from_slice(&(id as u32).to_le_bytes());
+        // Forward loop
+        chunks.extend_from_slice(&0u32.to_le_bytes());
+        chunks.extend_from_slice(&(sample_loop.start as u32).to_le_bytes());
+        chunks.extend_from_slice(&(sample_loop.end as u32).to_le_bytes());
+        // Fraction, play count (infinite)
+        chunks.extend_from_slice(&0u32.to_le_bytes());
+        chunks.extend_from_slice(&0u32.to_le_bytes());
+    }
+
+    riff(&chunks)
+}
+
+fn write_fmt_chunk(bytes: &mut Vec<u8>, channels: u16, sample_rate: u32) {
+    let block_align = channels * 2;
+
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    bytes.extend_from_slice(&block_align.to_le_bytes());
+    bytes.extend_from_slice(&16u16.to_le_bytes());
+}
+
+fn write_data_chunk(bytes: &mut Vec<u8>, samples: &[i16]) {
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&((samples.len() * 2) as u32).to_le_bytes());
+    for sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+}
+
+fn riff(chunks: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(12 + chunks.len());
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+    bytes.extend_from_slice(chunks);
+    bytes
+}
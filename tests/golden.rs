@@ -0,0 +1,77 @@
+//! Renders every song in a golden corpus and compares it against its expected outputs.
+//!
+//! `tests/golden/zeroed_ram` and `tests/golden/patterned_ram` hold hand-built songs played
+//! from RAM generated here, the same as the crate's test fixtures, so they always run. Real
+//! RAM dumps can't be checked in, so `tests/golden/songs` only runs with `WAHDIO_RAM`
+//! pointing at one. Set `WAHDIO_BLESS=1` to write new golden outputs after an intended change.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::path::Path;
+
+use wahdio::{bus::MAIN_RAM_SIZE, golden::GoldenCorpus};
+
+fn check_corpus(corpus: &GoldenCorpus, ram: &[u8]) {
+    if std::env::var_os("WAHDIO_BLESS").is_some() {
+        corpus.bless(ram).expect("Failed to write golden outputs");
+        return;
+    }
+
+    let diffs = corpus.check(ram).expect("Failed to render the corpus");
+    let mut report = String::new();
+    for diff in &diffs {
+        report.push_str(&diff.to_string());
+    }
+    println!("{}", report);
+    assert!(diffs.iter().all(|diff| diff.passed()), "{}", report);
+}
+
+/// A corpus of hand-built songs in `tests/golden/<ram>`
+fn synthetic_corpus(ram: &str) -> GoldenCorpus {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(ram);
+    let corpus = GoldenCorpus {
+        songs: root.join("songs"),
+        goldens: root.join("expected"),
+        // A quarter of a second, the songs are 4 steps at 240 BPM
+        frame_count: 8192,
+        tolerance: 2,
+    };
+    assert!(!corpus.song_names().unwrap().is_empty());
+    corpus
+}
+
+#[test]
+fn golden_renders_zeroed_ram() {
+    // Enough for PSG instruments, which don't read samples
+    check_corpus(&synthetic_corpus("zeroed_ram"), &vec![0; MAIN_RAM_SIZE]);
+}
+
+#[test]
+fn golden_renders_patterned_ram() {
+    // A pattern for sample instruments to play, without silent stretches
+    let ram: Vec<u8> = (0..MAIN_RAM_SIZE).map(|i| (i * 31 % 251) as u8).collect();
+    check_corpus(&synthetic_corpus("patterned_ram"), &ram);
+}
+
+#[test]
+fn golden_renders() {
+    let ram = match std::env::var("WAHDIO_RAM") {
+        Ok(path) => std::fs::read(path).expect("Failed to read WAHDIO_RAM"),
+        Err(_) => {
+            eprintln!("WAHDIO_RAM isn't set, skipping golden renders");
+            return;
+        }
+    };
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let corpus = GoldenCorpus {
+        songs: root.join("songs"),
+        goldens: root.join("expected"),
+        // 10 seconds
+        frame_count: 32824 * 10,
+        tolerance: 2,
+    };
+    check_corpus(&corpus, &ram);
+}